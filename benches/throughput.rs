@@ -0,0 +1,94 @@
+//! Measures chat throughput through `GaiaNodeClient` at increasing concurrency
+//! limits against a local mock upstream with fixed latency.
+//!
+//! Run with `cargo bench --bench throughput`.
+
+use actix_web::{web, App, HttpResponse, HttpServer};
+use gaia_ai_agent_template::actix_server::{gaia_client::GaiaNodeClient, types::ChatOptions};
+use openai_dive::v1::resources::chat::{ChatMessage, ChatMessageContent};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const UPSTREAM_LATENCY: Duration = Duration::from_millis(50);
+const REQUESTS: usize = 256;
+const CONCURRENCY_LEVELS: [usize; 5] = [1, 4, 16, 64, 256];
+
+async fn mock_chat_completion() -> HttpResponse {
+    tokio::time::sleep(UPSTREAM_LATENCY).await;
+    HttpResponse::Ok().json(serde_json::json!({
+        "id": "chatcmpl-bench",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "llama",
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": "pong" },
+            "finish_reason": "stop"
+        }],
+        "usage": { "prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2 }
+    }))
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let server = HttpServer::new(|| {
+        App::new().route("/v1/chat/completions", web::post().to(mock_chat_completion))
+    })
+    .workers(4)
+    .bind(("127.0.0.1", 0))?;
+    let address = server.addrs()[0];
+    let handle = server.run();
+    let server_handle = handle.handle();
+    tokio::spawn(handle);
+
+    let base_url = format!("http://{}/v1", address);
+    println!(
+        "{} requests per level, {:?} upstream latency",
+        REQUESTS, UPSTREAM_LATENCY
+    );
+
+    for max_concurrency in CONCURRENCY_LEVELS {
+        let client = Arc::new(
+            GaiaNodeClient::new(
+                base_url.clone(),
+                String::new(),
+                "llama".to_string(),
+                max_concurrency,
+            )
+            .expect("failed to build client"),
+        );
+
+        let started = Instant::now();
+        let tasks: Vec<_> = (0..REQUESTS)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let messages = vec![ChatMessage::User {
+                        content: ChatMessageContent::Text("ping".to_string()),
+                        name: None,
+                    }];
+                    client.chat(messages, &ChatOptions::default()).await
+                })
+            })
+            .collect();
+
+        let mut failures = 0;
+        for task in tasks {
+            if !matches!(task.await, Ok(Ok(_))) {
+                failures += 1;
+            }
+        }
+        let elapsed = started.elapsed();
+
+        println!(
+            "max_concurrency={:>3}  {:>8.1} req/s  elapsed={:>6.2}s  failures={}",
+            max_concurrency,
+            REQUESTS as f64 / elapsed.as_secs_f64(),
+            elapsed.as_secs_f64(),
+            failures
+        );
+    }
+
+    server_handle.stop(false).await;
+    Ok(())
+}
@@ -0,0 +1,929 @@
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use async_trait::async_trait;
+use gadget_sdk::info;
+use openai_dive::v1::resources::chat::{
+    ChatCompletionFunction, ChatCompletionResponse, ChatCompletionTool, ChatCompletionToolType,
+    ChatMessage, ChatMessageContent,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{
+    auth::{ApiKey, Scope},
+    gaia_client::{APIError, GaiaNodeClient},
+    knowledge::{DocumentFormat, IngestRequest, KnowledgeBase, SearchHit},
+    personas::{self, Persona},
+    rate_limit::json_with_usage,
+    server::AppState,
+    tools::{ToolError, ToolHandler, ToolRegistry},
+    types::ChatOptions,
+};
+
+/// Steps allowed when the request does not set `max_steps`.
+pub const DEFAULT_MAX_STEPS: u32 = 8;
+/// Hard upper bound on steps, regardless of what the request asks for.
+pub const MAX_STEPS_LIMIT: u32 = 32;
+/// Qdrant collection holding the agent's long-term memory when not configured.
+pub const DEFAULT_MEMORY_COLLECTION: &str = "agent-memory";
+
+/// Memories recalled into the prompt at the start of a run.
+const RECALLED_MEMORIES: usize = 3;
+/// Knowledge-base chunks returned by one search.
+const SEARCH_RESULTS: usize = 4;
+/// Longest tool output fed back to the model, in characters.
+const MAX_OBSERVATION_CHARS: usize = 4000;
+/// Largest response body read by the HTTP fetch tool.
+const MAX_FETCH_BYTES: usize = 256 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_FETCH_REDIRECTS: usize = 5;
+/// Deepest nesting of parentheses, functions, signs and powers the
+/// calculator evaluates.
+const MAX_EXPRESSION_DEPTH: usize = 64;
+/// Name of [`KnowledgeSearchTool`], which needs the knowledge scope.
+const KNOWLEDGE_SEARCH_TOOL: &str = "knowledge_search";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentConfig {
+    /// Hosts the `http_fetch` tool may reach; `*.example.com` also matches
+    /// subdomains. The tool is disabled when empty.
+    pub fetch_allowlist: Vec<String>,
+    pub memory_collection: String,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            fetch_allowlist: Vec::new(),
+            memory_collection: DEFAULT_MEMORY_COLLECTION.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentRunRequest {
+    pub task: String,
+    /// Persona whose system prompt, defaults and tools apply to the run.
+    pub agent: Option<String>,
+    pub max_steps: Option<u32>,
+    /// Recall related past runs and remember this one; on by default.
+    pub use_memory: Option<bool>,
+    #[serde(flatten)]
+    pub options: ChatOptions,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentStepKind {
+    /// The plan written before acting.
+    Plan,
+    /// A tool call and what it returned.
+    Action,
+    /// The final answer.
+    Answer,
+    /// A reply that was neither a tool call nor an answer.
+    Invalid,
+}
+
+/// One entry of the trace returned with every run.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentStep {
+    pub index: u32,
+    pub kind: AgentStepKind,
+    pub thought: Option<String>,
+    pub tool: Option<String>,
+    pub input: Option<Value>,
+    pub observation: Option<String>,
+    pub is_error: bool,
+    pub tokens: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentFinishReason {
+    Answer,
+    /// The step limit was reached before the model answered.
+    MaxSteps,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentRunResponse {
+    pub answer: Option<String>,
+    pub finish_reason: AgentFinishReason,
+    pub plan: Option<String>,
+    /// Past runs recalled from long-term memory.
+    pub memories: Vec<SearchHit>,
+    pub steps: Vec<AgentStep>,
+    /// Tokens used across all model calls.
+    pub total_tokens: u32,
+}
+
+/// Long-term memory of past runs, kept in its own Qdrant collection.
+///
+/// Every memory is tagged with the API key that produced it, and only
+/// recalled for that key.
+pub struct AgentMemory {
+    store: KnowledgeBase,
+}
+
+impl AgentMemory {
+    pub fn new(store: KnowledgeBase) -> Self {
+        Self { store }
+    }
+
+    pub async fn recall(
+        &self,
+        client: &GaiaNodeClient,
+        task: &str,
+        owner: Option<&str>,
+    ) -> Result<Vec<SearchHit>, APIError> {
+        let filter = owner
+            .map(|owner| json!({"must": [{"key": "metadata.owner", "match": {"value": owner}}]}));
+        self.store
+            .search(client, task, RECALLED_MEMORIES, filter, None)
+            .await
+    }
+
+    pub async fn remember(
+        &self,
+        client: &GaiaNodeClient,
+        task: &str,
+        answer: &str,
+        owner: Option<&str>,
+    ) -> Result<(), APIError> {
+        self.store
+            .ingest(
+                client,
+                IngestRequest {
+                    document_id: None,
+                    title: Some(truncate(task, 200)),
+                    content: format!("Task: {}\nAnswer: {}", task, answer),
+                    format: DocumentFormat::Text,
+                    chunk_size: None,
+                    chunk_overlap: None,
+                    model: None,
+                    metadata: Some(json!({ "owner": owner })),
+                },
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// The reply the model is asked to give at every step.
+#[derive(Deserialize)]
+struct AgentReply {
+    thought: Option<String>,
+    action: Option<String>,
+    #[serde(default)]
+    input: Value,
+    final_answer: Option<String>,
+}
+
+/// Runs a plan–act–observe loop for `request.task`.
+///
+/// The model first writes a plan, then at every step replies with a JSON
+/// object naming either a tool from `tools` to call or its final answer.
+/// Tool outputs are fed back as observations, which together with the
+/// model's replies form the scratchpad the next step sees. With `memory`,
+/// related past runs are recalled into the prompt and the answer is stored
+/// for later runs; memory failures are logged and never fail the run.
+///
+/// With a `persona`, its system prompt leads every call and its defaults
+/// fill the options the request leaves unset.
+///
+/// Reaching the step limit is not an error: the trace is returned with no
+/// answer and [`AgentFinishReason::MaxSteps`].
+pub async fn run_agent(
+    client: &GaiaNodeClient,
+    tools: &ToolRegistry,
+    memory: Option<&AgentMemory>,
+    owner: Option<&str>,
+    persona: Option<&Persona>,
+    request: AgentRunRequest,
+) -> Result<AgentRunResponse, APIError> {
+    if request.task.trim().is_empty() {
+        return Err(APIError::InvalidParameters(
+            "task must not be empty".to_string(),
+        ));
+    }
+    let with_persona = |messages: Vec<ChatMessage>, options: ChatOptions| match persona {
+        Some(persona) => persona.apply(messages, options),
+        None => (messages, options),
+    };
+    let (_, options) = with_persona(Vec::new(), request.options);
+    options.validate()?;
+    let max_steps = request
+        .max_steps
+        .unwrap_or(DEFAULT_MAX_STEPS)
+        .clamp(1, MAX_STEPS_LIMIT);
+    // The loop drives tools itself, so none are passed to the model natively.
+    let options = ChatOptions {
+        tools: None,
+        tool_choice: None,
+        ..options
+    };
+
+    let memories = match memory {
+        Some(memory) => match memory.recall(client, &request.task, owner).await {
+            Ok(memories) => memories,
+            Err(e) => {
+                info!("Failed to recall agent memories: {}", e);
+                Vec::new()
+            }
+        },
+        None => Vec::new(),
+    };
+
+    let mut steps = Vec::new();
+    let mut total_tokens = 0;
+
+    let mut context = format!("Task: {}", request.task);
+    if !memories.is_empty() {
+        context.push_str("\n\nNotes from earlier, related tasks:");
+        for hit in &memories {
+            context.push_str(&format!("\n- {}", hit.text));
+        }
+    }
+
+    let plan_messages = vec![
+        system_message(
+            "You plan how to complete tasks. Write a short numbered plan for the task \
+             below, using the tools listed if they help. Do not carry out the plan yet."
+                .to_string(),
+        ),
+        user_message(format!("{}\n\n{}", tool_descriptions(tools), context)),
+    ];
+    let (plan_messages, _) = with_persona(plan_messages, ChatOptions::default());
+    let completion = client.chat(plan_messages, &options).await?;
+    let tokens = usage(&completion);
+    total_tokens += tokens;
+    let plan = reply_text(&completion);
+    steps.push(AgentStep {
+        index: 0,
+        kind: AgentStepKind::Plan,
+        thought: plan.clone(),
+        tool: None,
+        input: None,
+        observation: None,
+        is_error: false,
+        tokens,
+    });
+
+    let (mut messages, _) = with_persona(
+        vec![
+            system_message(act_prompt(tools)),
+            user_message(match &plan {
+                Some(plan) => format!("{}\n\nPlan:\n{}", context, plan),
+                None => context,
+            }),
+        ],
+        ChatOptions::default(),
+    );
+
+    for index in 1..=max_steps {
+        let completion = client.chat(messages.clone(), &options).await?;
+        let tokens = usage(&completion);
+        total_tokens += tokens;
+        let text = reply_text(&completion).unwrap_or_default();
+        if let Some(choice) = completion.choices.first() {
+            messages.push(choice.message.clone());
+        }
+
+        let reply = match parse_reply(&text) {
+            Some(reply) => reply,
+            None => {
+                let observation = "Your reply was not a JSON object. Reply with a tool call \
+                                   or a final answer in the required format."
+                    .to_string();
+                messages.push(user_message(format!("Observation: {}", observation)));
+                steps.push(AgentStep {
+                    index,
+                    kind: AgentStepKind::Invalid,
+                    thought: Some(text),
+                    tool: None,
+                    input: None,
+                    observation: Some(observation),
+                    is_error: true,
+                    tokens,
+                });
+                continue;
+            }
+        };
+
+        if let Some(answer) = reply.final_answer {
+            steps.push(AgentStep {
+                index,
+                kind: AgentStepKind::Answer,
+                thought: reply.thought,
+                tool: None,
+                input: None,
+                observation: None,
+                is_error: false,
+                tokens,
+            });
+            if let Some(memory) = memory {
+                if let Err(e) = memory.remember(client, &request.task, &answer, owner).await {
+                    info!("Failed to store agent memory: {}", e);
+                }
+            }
+            return Ok(AgentRunResponse {
+                answer: Some(answer),
+                finish_reason: AgentFinishReason::Answer,
+                plan,
+                memories,
+                steps,
+                total_tokens,
+            });
+        }
+
+        let (kind, tool, observation, is_error) = match reply.action {
+            Some(name) => {
+                let (observation, is_error) = match tools.get(&name) {
+                    Some(handler) => match handler.call(reply.input.clone()).await {
+                        Ok(output) => (truncate(&output, MAX_OBSERVATION_CHARS), false),
+                        Err(e) => (format!("Error: {}", e), true),
+                    },
+                    None => (format!("Error: there is no tool named {}", name), true),
+                };
+                (AgentStepKind::Action, Some(name), observation, is_error)
+            }
+            None => (
+                AgentStepKind::Invalid,
+                None,
+                "Your reply named neither an action nor a final_answer.".to_string(),
+                true,
+            ),
+        };
+        messages.push(user_message(format!("Observation: {}", observation)));
+        steps.push(AgentStep {
+            index,
+            kind,
+            thought: reply.thought,
+            tool,
+            input: Some(reply.input).filter(|input| !input.is_null()),
+            observation: Some(observation),
+            is_error,
+            tokens,
+        });
+    }
+
+    Ok(AgentRunResponse {
+        answer: None,
+        finish_reason: AgentFinishReason::MaxSteps,
+        plan,
+        memories,
+        steps,
+        total_tokens,
+    })
+}
+
+fn system_message(text: String) -> ChatMessage {
+    ChatMessage::System {
+        content: ChatMessageContent::Text(text),
+        name: None,
+    }
+}
+
+fn user_message(text: String) -> ChatMessage {
+    ChatMessage::User {
+        content: ChatMessageContent::Text(text),
+        name: None,
+    }
+}
+
+fn tool_descriptions(tools: &ToolRegistry) -> String {
+    let mut definitions = tools.definitions();
+    if definitions.is_empty() {
+        return "No tools are available.".to_string();
+    }
+    definitions.sort_by(|a, b| a.function.name.cmp(&b.function.name));
+    let mut text = "Tools:".to_string();
+    for definition in definitions {
+        text.push_str(&format!(
+            "\n- {}: {} Arguments (JSON schema): {}",
+            definition.function.name,
+            definition.function.description.unwrap_or_default(),
+            definition.function.parameters
+        ));
+    }
+    text
+}
+
+fn act_prompt(tools: &ToolRegistry) -> String {
+    format!(
+        "You complete tasks step by step, following the plan and using tools when needed.\n\n\
+         {}\n\n\
+         Reply with a single JSON object and nothing else. To call a tool, reply \
+         {{\"thought\": \"...\", \"action\": \"<tool name>\", \"input\": {{...}}}} and wait \
+         for the observation. When you know the answer, reply \
+         {{\"thought\": \"...\", \"final_answer\": \"...\"}}.",
+        tool_descriptions(tools)
+    )
+}
+
+/// Parses the first JSON object in `text`, tolerating surrounding prose and
+/// code fences.
+fn parse_reply(text: &str) -> Option<AgentReply> {
+    let start = text.find('{')?;
+    let end = text.rfind('}')?;
+    if end < start {
+        return None;
+    }
+    serde_json::from_str(&text[start..=end]).ok()
+}
+
+fn reply_text(completion: &ChatCompletionResponse) -> Option<String> {
+    let value = serde_json::to_value(completion).ok()?;
+    value["choices"][0]["message"]["content"]
+        .as_str()
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+fn usage(completion: &ChatCompletionResponse) -> u32 {
+    completion
+        .usage
+        .as_ref()
+        .map_or(0, |usage| usage.total_tokens)
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+fn function_tool(name: &str, description: &str, parameters: Value) -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: ChatCompletionFunction {
+            name: name.to_string(),
+            description: Some(description.to_string()),
+            parameters,
+        },
+    }
+}
+
+/// Fetches a web page or API response from an allowlisted host.
+pub struct HttpFetchTool {
+    http_client: reqwest::Client,
+    allowlist: Arc<Vec<String>>,
+}
+
+impl HttpFetchTool {
+    pub fn new(allowlist: Vec<String>) -> Result<Self, APIError> {
+        let allowlist = Arc::new(allowlist);
+        let redirect_allowlist = allowlist.clone();
+        // Redirects must stay on allowlisted hosts too.
+        let policy = reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_FETCH_REDIRECTS
+                || !is_allowed(&redirect_allowlist, attempt.url())
+            {
+                attempt.stop()
+            } else {
+                attempt.follow()
+            }
+        });
+        Ok(Self {
+            http_client: reqwest::Client::builder()
+                .redirect(policy)
+                .timeout(FETCH_TIMEOUT)
+                .build()?,
+            allowlist,
+        })
+    }
+}
+
+fn is_allowed(allowlist: &[String], url: &url::Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    allowlist
+        .iter()
+        .any(|entry| match entry.strip_prefix("*.") {
+            Some(domain) => host == domain || host.ends_with(&format!(".{}", domain)),
+            None => host == entry,
+        })
+}
+
+#[async_trait]
+impl ToolHandler for HttpFetchTool {
+    fn definition(&self) -> ChatCompletionTool {
+        function_tool(
+            "http_fetch",
+            &format!(
+                "Fetches a URL with GET and returns the response body. Allowed hosts: {}.",
+                self.allowlist.join(", ")
+            ),
+            json!({
+                "type": "object",
+                "properties": {"url": {"type": "string"}},
+                "required": ["url"],
+            }),
+        )
+    }
+
+    async fn call(&self, arguments: Value) -> Result<String, ToolError> {
+        let url = arguments["url"]
+            .as_str()
+            .ok_or_else(|| ToolError::InvalidArguments("url is required".to_string()))?;
+        let url = url::Url::parse(url).map_err(|e| ToolError::InvalidArguments(e.to_string()))?;
+        if !is_allowed(&self.allowlist, &url) {
+            return Err(ToolError::InvalidArguments(format!(
+                "{} is not on the allowlist",
+                url.host_str().unwrap_or_default()
+            )));
+        }
+
+        let mut response = self
+            .http_client
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| ToolError::Execution(e.to_string()))?;
+        let status = response.status();
+        let mut body = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| ToolError::Execution(e.to_string()))?
+        {
+            body.extend_from_slice(&chunk);
+            if body.len() >= MAX_FETCH_BYTES {
+                body.truncate(MAX_FETCH_BYTES);
+                break;
+            }
+        }
+        let body = String::from_utf8_lossy(&body);
+        if !status.is_success() {
+            return Err(ToolError::Execution(format!(
+                "{}: {}",
+                status,
+                truncate(&body, 200)
+            )));
+        }
+        Ok(body.into_owned())
+    }
+}
+
+/// Evaluates arithmetic expressions.
+pub struct CalculatorTool;
+
+#[async_trait]
+impl ToolHandler for CalculatorTool {
+    fn definition(&self) -> ChatCompletionTool {
+        function_tool(
+            "calculator",
+            "Evaluates an arithmetic expression with + - * / % ^, parentheses, \
+             sqrt, abs, ln, log, sin, cos, tan, pi and e.",
+            json!({
+                "type": "object",
+                "properties": {"expression": {"type": "string"}},
+                "required": ["expression"],
+            }),
+        )
+    }
+
+    async fn call(&self, arguments: Value) -> Result<String, ToolError> {
+        let expression = arguments["expression"]
+            .as_str()
+            .ok_or_else(|| ToolError::InvalidArguments("expression is required".to_string()))?;
+        evaluate(expression)
+            .map(|value| value.to_string())
+            .map_err(ToolError::InvalidArguments)
+    }
+}
+
+/// Searches the node's knowledge base.
+pub struct KnowledgeSearchTool {
+    client: Arc<GaiaNodeClient>,
+    knowledge: Arc<KnowledgeBase>,
+}
+
+impl KnowledgeSearchTool {
+    pub fn new(client: Arc<GaiaNodeClient>, knowledge: Arc<KnowledgeBase>) -> Self {
+        Self { client, knowledge }
+    }
+}
+
+#[async_trait]
+impl ToolHandler for KnowledgeSearchTool {
+    fn definition(&self) -> ChatCompletionTool {
+        function_tool(
+            KNOWLEDGE_SEARCH_TOOL,
+            "Searches the knowledge base and returns the most relevant passages.",
+            json!({
+                "type": "object",
+                "properties": {"query": {"type": "string"}},
+                "required": ["query"],
+            }),
+        )
+    }
+
+    async fn call(&self, arguments: Value) -> Result<String, ToolError> {
+        let query = arguments["query"]
+            .as_str()
+            .ok_or_else(|| ToolError::InvalidArguments("query is required".to_string()))?;
+        let hits = self
+            .knowledge
+            .search(&self.client, query, SEARCH_RESULTS, None, None)
+            .await
+            .map_err(|e| ToolError::Execution(e.to_string()))?;
+        if hits.is_empty() {
+            return Ok("No results.".to_string());
+        }
+        Ok(hits
+            .iter()
+            .map(|hit| match &hit.title {
+                Some(title) => format!("[{}] {}", title, hit.text),
+                None => hit.text.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n"))
+    }
+}
+
+/// Evaluates an arithmetic expression.
+fn evaluate(expression: &str) -> Result<f64, String> {
+    let mut parser = ExpressionParser {
+        chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+        position: 0,
+        depth: 0,
+    };
+    let value = parser.expression()?;
+    if parser.position < parser.chars.len() {
+        return Err(format!(
+            "Unexpected '{}' at position {}",
+            parser.chars[parser.position], parser.position
+        ));
+    }
+    if !value.is_finite() {
+        return Err("The result is not a finite number".to_string());
+    }
+    Ok(value)
+}
+
+/// Recursive-descent parser over an expression with whitespace removed.
+struct ExpressionParser {
+    chars: Vec<char>,
+    position: usize,
+    /// Recursion depth, bounded by [`MAX_EXPRESSION_DEPTH`] so that hostile
+    /// input cannot overflow the stack.
+    depth: usize,
+}
+
+impl ExpressionParser {
+    /// Runs `parse` one level deeper, failing past [`MAX_EXPRESSION_DEPTH`].
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<f64, String>,
+    ) -> Result<f64, String> {
+        if self.depth >= MAX_EXPRESSION_DEPTH {
+            return Err(format!(
+                "The expression is nested more than {} levels deep",
+                MAX_EXPRESSION_DEPTH
+            ));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expression(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat('+') {
+                value += self.term()?;
+            } else if self.eat('-') {
+                value -= self.term()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value *= self.unary()?;
+            } else if self.eat('/') {
+                value /= self.unary()?;
+            } else if self.eat('%') {
+                value %= self.unary()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Unary signs apply to a whole power, so `-2 ^ 2` is `-4`.
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.eat('-') {
+            Ok(-self.nested(Self::unary)?)
+        } else if self.eat('+') {
+            self.nested(Self::unary)
+        } else {
+            self.power()
+        }
+    }
+
+    /// `^` binds tighter than unary minus on its left and is right-associative.
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.primary()?;
+        if self.eat('^') {
+            Ok(base.powf(self.nested(Self::unary)?))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn primary(&mut self) -> Result<f64, String> {
+        if self.eat('(') {
+            let value = self.nested(Self::expression)?;
+            if !self.eat(')') {
+                return Err("Missing closing parenthesis".to_string());
+            }
+            return Ok(value);
+        }
+
+        let start = self.position;
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                while matches!(self.peek(), Some(c) if c.is_ascii_digit() || c == '.') {
+                    self.position += 1;
+                }
+                let number: String = self.chars[start..self.position].iter().collect();
+                number
+                    .parse()
+                    .map_err(|_| format!("Invalid number {}", number))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric()) {
+                    self.position += 1;
+                }
+                let name: String = self.chars[start..self.position].iter().collect();
+                match name.as_str() {
+                    "pi" => return Ok(std::f64::consts::PI),
+                    "e" => return Ok(std::f64::consts::E),
+                    _ => {}
+                }
+                let function: fn(f64) -> f64 = match name.as_str() {
+                    "sqrt" => f64::sqrt,
+                    "abs" => f64::abs,
+                    "ln" => f64::ln,
+                    "log" => f64::log10,
+                    "sin" => f64::sin,
+                    "cos" => f64::cos,
+                    "tan" => f64::tan,
+                    _ => return Err(format!("Unknown function {}", name)),
+                };
+                if !self.eat('(') {
+                    return Err(format!("Expected '(' after {}", name));
+                }
+                let argument = self.nested(Self::expression)?;
+                if !self.eat(')') {
+                    return Err("Missing closing parenthesis".to_string());
+                }
+                Ok(function(argument))
+            }
+            Some(c) => Err(format!("Unexpected '{}' at position {}", c, start)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+/// Tools available to agent runs: the server's registered tools plus the
+/// calculator, knowledge-base search and, with an allowlist, HTTP fetch.
+///
+/// Callers without the knowledge scope have knowledge-base search taken away
+/// again in [`run`].
+pub fn agent_tools(
+    registry: &ToolRegistry,
+    client: Arc<GaiaNodeClient>,
+    knowledge: Arc<KnowledgeBase>,
+    config: &AgentConfig,
+) -> Result<ToolRegistry, APIError> {
+    let mut tools = registry.clone();
+    tools.register(CalculatorTool);
+    tools.register(KnowledgeSearchTool::new(client, knowledge));
+    if !config.fetch_allowlist.is_empty() {
+        tools.register(HttpFetchTool::new(config.fetch_allowlist.clone())?);
+    }
+    Ok(tools)
+}
+
+/// Registers `POST /agent/run`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/agent/run", web::post().to(run));
+}
+
+async fn run(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+    request: web::Json<AgentRunRequest>,
+) -> HttpResponse {
+    let request = request.into_inner();
+    let persona = match request
+        .agent
+        .as_deref()
+        .map(|agent| personas::resolve(&app_state, agent))
+    {
+        Some(Ok(persona)) => Some(persona),
+        Some(Err(e)) => return e.error_response(),
+        None => None,
+    };
+    let mut tools = match &persona {
+        Some(persona) => Arc::new(persona.tools(&app_state.agent_tools)),
+        None => app_state.agent_tools.clone(),
+    };
+
+    let key = req.extensions().get::<ApiKey>().cloned();
+    if key
+        .as_ref()
+        .is_some_and(|key| !key.allows(Scope::Knowledge))
+    {
+        tools = Arc::new(tools.without(KNOWLEDGE_SEARCH_TOOL));
+    }
+    let owner = key.map(|key| key.id);
+    let memory = request
+        .use_memory
+        .unwrap_or(true)
+        .then_some(app_state.agent_memory.as_ref());
+    match run_agent(
+        &app_state.gaia_client,
+        &tools,
+        memory,
+        owner.as_deref(),
+        persona.as_ref(),
+        request,
+    )
+    .await
+    {
+        Ok(response) => json_with_usage(&response),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calculator_follows_precedence() {
+        assert_eq!(evaluate("1 + 2 * 3").unwrap(), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3").unwrap(), 9.0);
+        assert_eq!(evaluate("2 ^ 3 ^ 2").unwrap(), 512.0);
+        assert_eq!(evaluate("-2 ^ 2").unwrap(), -4.0);
+        assert_eq!(evaluate("2 ^ -1").unwrap(), 0.5);
+        assert_eq!(evaluate("10 % 4 - 1").unwrap(), 1.0);
+        assert_eq!(evaluate("sqrt(16) + abs(-2)").unwrap(), 6.0);
+    }
+
+    #[test]
+    fn calculator_rejects_invalid_expressions() {
+        for expression in ["1 +", "(1 + 2", "2)", "foo(1)", "1 / 0", ""] {
+            assert!(evaluate(expression).is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn calculator_limits_nesting() {
+        let within = format!(
+            "{}1{}",
+            "(".repeat(MAX_EXPRESSION_DEPTH),
+            ")".repeat(MAX_EXPRESSION_DEPTH)
+        );
+        assert_eq!(evaluate(&within).unwrap(), 1.0);
+
+        for expression in [
+            format!("{}1", "(".repeat(100_000)),
+            format!("{}1", "-".repeat(100_000)),
+            format!("{}1", "2^".repeat(100_000)),
+            format!("{}1", "sqrt(".repeat(100_000)),
+        ] {
+            let error = evaluate(&expression).unwrap_err();
+            assert!(error.contains("nested"), "{}", error);
+        }
+    }
+}
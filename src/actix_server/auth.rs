@@ -0,0 +1,489 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, StatusCode},
+    middleware::Next,
+    web, Error, HttpMessage, HttpResponse,
+};
+use color_eyre::{eyre::eyre, Result};
+use gadget_sdk::info;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{error::error_response, server::AppState};
+
+/// Prefix of every generated API key, to make leaked keys easy to recognise.
+const KEY_PREFIX: &str = "gaia-";
+
+/// What an API key is allowed to do.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Chat, image analysis, embeddings and model listing.
+    Chat,
+    /// Image generation and editing.
+    Images,
+    /// Uploading, listing and deleting knowledge-base documents.
+    Knowledge,
+    /// Key management; implies every other scope.
+    Admin,
+}
+
+/// A stored API key. Only the SHA-256 hash of the secret is kept.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub key_hash: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: u64,
+    pub revoked: bool,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+}
+
+/// Key metadata returned by the admin endpoints, without the hash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: u64,
+    pub revoked: bool,
+}
+
+impl From<&ApiKey> for ApiKeyInfo {
+    fn from(key: &ApiKey) -> Self {
+        Self {
+            id: key.id.clone(),
+            name: key.name.clone(),
+            scopes: key.scopes.clone(),
+            created_at: key.created_at,
+            revoked: key.revoked,
+        }
+    }
+}
+
+/// File-backed store of API keys.
+///
+/// The whole store is rewritten on every change, which is fine for the
+/// handful of keys an operator manages by hand.
+pub struct ApiKeyStore {
+    path: PathBuf,
+    keys: RwLock<Vec<ApiKey>>,
+}
+
+impl ApiKeyStore {
+    /// Opens the store at `path`, creating it if it does not exist.
+    ///
+    /// A new store is seeded with a single admin key so the operator can
+    /// create further keys. Its secret is written to `<store>.admin-key`,
+    /// readable by the owner only; just the key id and that path are logged.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let keys = if path.exists() {
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| eyre!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents)
+                .map_err(|e| eyre!("Invalid API key store {}: {}", path.display(), e))?
+        } else {
+            Vec::new()
+        };
+
+        let store = Self {
+            path,
+            keys: RwLock::new(keys),
+        };
+
+        if store.keys.read().is_empty() {
+            let (key, secret) = store.create("bootstrap-admin".to_string(), vec![Scope::Admin])?;
+            let secret_path = bootstrap_secret_path(&store.path);
+            if let Err(e) = write_secret(&secret_path, &secret) {
+                // A key nobody has the secret of is useless; drop it so the next start retries.
+                let mut keys = store.keys.write();
+                keys.clear();
+                store.persist(&keys)?;
+                return Err(e);
+            }
+            info!(
+                "Created bootstrap admin API key {}; its secret is in {} (move it somewhere safe and delete the file)",
+                key.id,
+                secret_path.display()
+            );
+        }
+
+        Ok(store)
+    }
+
+    /// Creates a key and returns its metadata along with the plaintext secret.
+    pub fn create(&self, name: String, scopes: Vec<Scope>) -> Result<(ApiKeyInfo, String)> {
+        let secret = format!(
+            "{}{}{}",
+            KEY_PREFIX,
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+        let key = ApiKey {
+            id: uuid::Uuid::new_v4().to_string(),
+            name,
+            key_hash: hash_secret(&secret),
+            scopes,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            revoked: false,
+        };
+        let info = ApiKeyInfo::from(&key);
+
+        let mut keys = self.keys.write();
+        keys.push(key);
+        self.persist(&keys)?;
+
+        Ok((info, secret))
+    }
+
+    /// Revokes the key with the given id. Returns `false` if it does not exist.
+    pub fn revoke(&self, id: &str) -> Result<bool> {
+        let mut keys = self.keys.write();
+        let Some(key) = keys.iter_mut().find(|key| key.id == id) else {
+            return Ok(false);
+        };
+        key.revoked = true;
+        self.persist(&keys)?;
+        Ok(true)
+    }
+
+    pub fn list(&self) -> Vec<ApiKeyInfo> {
+        self.keys.read().iter().map(ApiKeyInfo::from).collect()
+    }
+
+    /// Looks up the non-revoked key matching a bearer token.
+    pub fn authenticate(&self, secret: &str) -> Option<ApiKey> {
+        let key_hash = hash_secret(secret);
+        self.keys
+            .read()
+            .iter()
+            .find(|key| !key.revoked && key.key_hash == key_hash)
+            .cloned()
+    }
+
+    fn persist(&self, keys: &[ApiKey]) -> Result<()> {
+        let contents = serde_json::to_string_pretty(keys)?;
+        // Write to a sibling file first so a crash never leaves a truncated store.
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents)
+            .map_err(|e| eyre!("Failed to write {}: {}", tmp_path.display(), e))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .map_err(|e| eyre!("Failed to replace {}: {}", self.path.display(), e))?;
+        Ok(())
+    }
+}
+
+/// Where the secret of the bootstrap admin key of the store at `path` is written.
+fn bootstrap_secret_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".admin-key");
+    path.with_file_name(file_name)
+}
+
+/// Writes `secret` to `path`, readable and writable by the owner only.
+fn write_secret(path: &Path, secret: &str) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| eyre!("Failed to create {}: {}", path.display(), e))?;
+    // `mode` only applies to new files; tighten a leftover one as well.
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    writeln!(file, "{}", secret).map_err(|e| eyre!("Failed to write {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+fn hash_secret(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The scope a request needs.
+///
+/// Decided on the route pattern the request matches rather than its raw
+/// path, which the router percent-decodes first. Requests that match no
+/// route, including encoded paths such as `/%61dmin/keys`, need the admin
+/// scope.
+pub(super) fn required_scope(req: &ServiceRequest) -> Scope {
+    req.match_pattern()
+        .map(|pattern| scope_for_pattern(&pattern))
+        .unwrap_or(Scope::Admin)
+}
+
+/// The scope a route pattern needs; admin unless listed.
+fn scope_for_pattern(pattern: &str) -> Scope {
+    match pattern {
+        "/chat"
+        | "/agent/run"
+        | "/analyze_image"
+        | "/embeddings"
+        | "/v1/chat/completions"
+        | "/v1/embeddings"
+        | "/v1/models" => Scope::Chat,
+        "/create_image" | "/edit_image" | "/v1/images/generations" | "/v1/images/edits" => {
+            Scope::Images
+        }
+        pattern if pattern.starts_with("/sessions") => Scope::Chat,
+        // Image jobs additionally need the images scope, checked when queued.
+        pattern if pattern.starts_with("/jobs") => Scope::Chat,
+        pattern if pattern.starts_with("/knowledge") => Scope::Knowledge,
+        _ => Scope::Admin,
+    }
+}
+
+/// Middleware enforcing bearer-token authentication and per-key scopes.
+///
+/// Does nothing when the server runs without an API key store. On success the
+/// matching [`ApiKey`] is inserted into the request extensions.
+pub async fn require_api_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let store = req
+        .app_data::<web::Data<AppState>>()
+        .and_then(|state| state.api_keys.clone());
+    let Some(store) = store else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let scope = required_scope(&req);
+
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    let key = match token {
+        Some(token) => store.authenticate(token),
+        None => {
+            let response = error_response(
+                StatusCode::UNAUTHORIZED,
+                "invalid_request_error",
+                "missing_api_key",
+                "Missing bearer token in the Authorization header".to_string(),
+            );
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    };
+
+    let Some(key) = key else {
+        let response = error_response(
+            StatusCode::UNAUTHORIZED,
+            "invalid_request_error",
+            "invalid_api_key",
+            "Invalid or revoked API key".to_string(),
+        );
+        return Ok(req.into_response(response).map_into_right_body());
+    };
+
+    if !key.allows(scope) {
+        let response = error_response(
+            StatusCode::FORBIDDEN,
+            "permission_error",
+            "insufficient_scope",
+            format!("API key {} is missing the {:?} scope", key.id, scope),
+        );
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    req.extensions_mut().insert(key);
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+#[derive(Deserialize)]
+struct CreateKeyRequest {
+    name: String,
+    scopes: Vec<Scope>,
+}
+
+#[derive(Serialize)]
+struct CreateKeyResponse {
+    #[serde(flatten)]
+    key: ApiKeyInfo,
+    /// The plaintext key; only returned once.
+    secret: String,
+}
+
+/// Registers the key management endpoints under `/admin/keys`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin/keys")
+            .route("", web::get().to(list_keys))
+            .route("", web::post().to(create_key))
+            .route("/{id}", web::delete().to(revoke_key)),
+    );
+}
+
+fn store_unavailable() -> HttpResponse {
+    error_response(
+        StatusCode::NOT_FOUND,
+        "invalid_request_error",
+        "auth_disabled",
+        "API key authentication is not enabled on this server".to_string(),
+    )
+}
+
+async fn list_keys(app_state: web::Data<AppState>) -> HttpResponse {
+    match &app_state.api_keys {
+        Some(store) => HttpResponse::Ok().json(store.list()),
+        None => store_unavailable(),
+    }
+}
+
+async fn create_key(
+    app_state: web::Data<AppState>,
+    request: web::Json<CreateKeyRequest>,
+) -> HttpResponse {
+    let Some(store) = &app_state.api_keys else {
+        return store_unavailable();
+    };
+    let request = request.into_inner();
+    if request.scopes.is_empty() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "invalid_parameters",
+            "At least one scope is required".to_string(),
+        );
+    }
+
+    match store.create(request.name, request.scopes) {
+        Ok((key, secret)) => HttpResponse::Created().json(CreateKeyResponse { key, secret }),
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "api_error",
+            "internal_error",
+            e.to_string(),
+        ),
+    }
+}
+
+async fn revoke_key(app_state: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+    let Some(store) = &app_state.api_keys else {
+        return store_unavailable();
+    };
+
+    match store.revoke(&id) {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => error_response(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            "key_not_found",
+            format!("No API key with id {}", id),
+        ),
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "api_error",
+            "internal_error",
+            e.to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    /// Answers every request with the scope it would need.
+    async fn scope_of(method: &str, uri: &str) -> String {
+        let app = test::init_service(
+            App::new()
+                .wrap_fn(|req, _| {
+                    let scope = format!("{:?}", required_scope(&req));
+                    let response = req.into_response(HttpResponse::Ok().body(scope));
+                    futures::future::ready(Ok(response))
+                })
+                .route("/chat", web::post().to(HttpResponse::Ok))
+                .route("/v1/images/edits", web::post().to(HttpResponse::Ok))
+                .service(
+                    web::scope("/sessions").route("/{id}/chat", web::post().to(HttpResponse::Ok)),
+                )
+                .service(
+                    web::scope("/knowledge")
+                        .route("/documents/{id}", web::delete().to(HttpResponse::Ok)),
+                )
+                .configure(configure),
+        )
+        .await;
+        let request = test::TestRequest::default()
+            .method(method.parse().unwrap())
+            .uri(uri)
+            .to_request();
+        let body = test::call_and_read_body(&app, request).await;
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn scopes_follow_the_matched_route() {
+        assert_eq!(scope_of("POST", "/chat").await, "Chat");
+        assert_eq!(scope_of("POST", "/v1/images/edits").await, "Images");
+        assert_eq!(scope_of("POST", "/sessions/abc/chat").await, "Chat");
+        assert_eq!(
+            scope_of("DELETE", "/knowledge/documents/1").await,
+            "Knowledge"
+        );
+        assert_eq!(scope_of("POST", "/admin/keys").await, "Admin");
+        assert_eq!(scope_of("DELETE", "/admin/keys/1").await, "Admin");
+    }
+
+    #[actix_web::test]
+    async fn unmatched_and_encoded_paths_need_admin() {
+        assert_eq!(scope_of("POST", "/%61dmin/keys").await, "Admin");
+        assert_eq!(scope_of("POST", "/%63hat").await, "Admin");
+        assert_eq!(scope_of("GET", "/unknown").await, "Admin");
+    }
+
+    #[test]
+    fn unlisted_patterns_need_admin() {
+        assert_eq!(scope_for_pattern("/admin/upstreams"), Scope::Admin);
+        assert_eq!(scope_for_pattern("/admin/agents/{name}"), Scope::Admin);
+        assert_eq!(scope_for_pattern("/jobs/{id}"), Scope::Chat);
+        assert_eq!(scope_for_pattern("/metrics"), Scope::Admin);
+    }
+
+    #[test]
+    fn bootstrap_secret_is_written_to_a_private_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("gaia-keys-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keys.json");
+        let store = ApiKeyStore::open(&path).unwrap();
+
+        let secret_path = bootstrap_secret_path(&path);
+        assert_eq!(secret_path, dir.join("keys.json.admin-key"));
+        let mode = std::fs::metadata(&secret_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let secret = std::fs::read_to_string(&secret_path).unwrap();
+        let key = store.authenticate(secret.trim()).unwrap();
+        assert!(key.allows(Scope::Admin));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
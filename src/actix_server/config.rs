@@ -0,0 +1,517 @@
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+
+use super::{
+    agent::{AgentConfig, DEFAULT_MEMORY_COLLECTION},
+    context::{ContextSettings, ContextStrategy, DEFAULT_CONTEXT_SIZE, DEFAULT_KEEP_LAST},
+    gaia_client::DEFAULT_REQUEST_TIMEOUT,
+    knowledge::{
+        KnowledgeConfig, DEFAULT_CHUNK_OVERLAP, DEFAULT_CHUNK_SIZE, DEFAULT_COLLECTION,
+        DEFAULT_QDRANT_URL,
+    },
+    queue::{DEFAULT_JOB_WORKERS, DEFAULT_MAX_QUEUED_JOBS},
+    rate_limit::RateLimitConfig,
+    upstream::{BalancingStrategy, ModelRoute, PoolSettings, UpstreamSpec},
+};
+
+/// A Gaia node started by job 1 listens on port 8080 of the same machine.
+pub const DEFAULT_BASE_URL: &str = "http://127.0.0.1:8080/v1";
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+/// Kept off port 8080 so the server does not collide with a local Gaia node.
+pub const DEFAULT_PORT: u16 = 3000;
+pub const DEFAULT_MODEL: &str = "llama";
+/// Upstream requests allowed in flight at once when not configured.
+pub const DEFAULT_MAX_CONCURRENCY: usize = 64;
+/// Seconds in-flight requests are given to finish during a graceful shutdown.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+/// Seconds between active health checks of the upstream nodes.
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 10;
+
+/// HTTP server options as given on the command line, in the environment or in
+/// a JSON config file.
+///
+/// Every field is optional so the sources can be layered: CLI flags and
+/// environment variables take precedence over the config file, which takes
+/// precedence over the built-in defaults.
+#[derive(StructOpt, Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "snake_case")]
+pub struct ServerArgs {
+    /// Path to a JSON file with the same keys as these flags (snake_case).
+    #[structopt(long = "server-config", env = "GAIA_SERVER_CONFIG", parse(from_os_str))]
+    #[serde(skip)]
+    pub config_file: Option<PathBuf>,
+
+    /// Base URL of the upstream Gaia node's OpenAI-compatible API.
+    #[structopt(long = "gaia-base-url", env = "GAIA_BASE_URL")]
+    pub gaia_base_url: Option<String>,
+
+    /// Comma-separated Gaia nodes to balance across, each `URL` or
+    /// `URL;weight=N`. The first is the node managed by this operator.
+    /// Replaces `--gaia-base-url`.
+    #[structopt(long = "gaia-upstreams", env = "GAIA_UPSTREAMS", use_delimiter = true)]
+    pub gaia_upstreams: Option<Vec<String>>,
+
+    /// How requests are spread across nodes: `weighted_round_robin` or `least_in_flight`.
+    #[structopt(long = "load-balancing", env = "GAIA_LOAD_BALANCING")]
+    pub load_balancing: Option<BalancingStrategy>,
+
+    /// Consecutive failures after which a node is taken out of rotation.
+    #[structopt(long = "max-upstream-failures", env = "GAIA_MAX_UPSTREAM_FAILURES")]
+    pub max_upstream_failures: Option<u32>,
+
+    /// Seconds a failing node stays out of rotation.
+    #[structopt(long = "ejection-duration", env = "GAIA_EJECTION_DURATION")]
+    pub ejection_duration: Option<u64>,
+
+    /// Seconds between active health checks of every node.
+    #[structopt(long = "health-check-interval", env = "GAIA_HEALTH_CHECK_INTERVAL")]
+    pub health_check_interval: Option<u64>,
+
+    /// API key sent to the upstream Gaia node.
+    #[structopt(long = "gaia-api-key", env = "GAIA_API_KEY", hide_env_values = true)]
+    pub gaia_api_key: Option<String>,
+
+    /// Host or IP address the HTTP server listens on.
+    #[structopt(long = "host", env = "GAIA_HOST")]
+    pub host: Option<String>,
+
+    /// Port the HTTP server listens on.
+    #[structopt(long = "port", env = "GAIA_PORT")]
+    pub port: Option<u16>,
+
+    /// Number of HTTP worker threads (defaults to the number of CPU cores).
+    #[structopt(long = "workers", env = "GAIA_WORKERS")]
+    pub workers: Option<usize>,
+
+    /// PEM certificate chain; enables TLS together with `--tls-key`.
+    #[structopt(long = "tls-cert", env = "GAIA_TLS_CERT", parse(from_os_str))]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key; enables TLS together with `--tls-cert`.
+    #[structopt(long = "tls-key", env = "GAIA_TLS_KEY", parse(from_os_str))]
+    pub tls_key: Option<PathBuf>,
+
+    /// Maximum number of requests sent to the Gaia node concurrently.
+    #[structopt(long = "max-concurrency", env = "GAIA_MAX_CONCURRENCY")]
+    pub max_concurrency: Option<usize>,
+
+    /// Seconds a single upstream request may take before failing with 504.
+    #[structopt(long = "request-timeout", env = "GAIA_REQUEST_TIMEOUT")]
+    pub request_timeout: Option<u64>,
+
+    /// JSON file holding API keys; enables bearer-token authentication.
+    #[structopt(long = "api-keys-file", env = "GAIA_API_KEYS_FILE", parse(from_os_str))]
+    pub api_keys_file: Option<PathBuf>,
+
+    /// Requests per minute allowed for each API key and client IP.
+    #[structopt(long = "rate-limit-rpm", env = "GAIA_RATE_LIMIT_RPM")]
+    pub rate_limit_rpm: Option<u32>,
+
+    /// Tokens per minute allowed for each API key and client IP.
+    #[structopt(long = "rate-limit-tpm", env = "GAIA_RATE_LIMIT_TPM")]
+    pub rate_limit_tpm: Option<u32>,
+
+    /// Tokens per UTC day allowed for each API key and client IP.
+    #[structopt(long = "daily-token-quota", env = "GAIA_DAILY_TOKEN_QUOTA")]
+    pub daily_token_quota: Option<u64>,
+
+    /// Tokens per UTC month allowed for each API key and client IP.
+    #[structopt(long = "monthly-token-quota", env = "GAIA_MONTHLY_TOKEN_QUOTA")]
+    pub monthly_token_quota: Option<u64>,
+
+    /// JSON file where quota counters are persisted across restarts.
+    #[structopt(
+        long = "rate-limit-state-file",
+        env = "GAIA_RATE_LIMIT_STATE_FILE",
+        parse(from_os_str)
+    )]
+    pub rate_limit_state_file: Option<PathBuf>,
+
+    /// Seconds to let in-flight requests finish when shutting down.
+    #[structopt(long = "shutdown-timeout", env = "GAIA_SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout: Option<u64>,
+
+    /// Model used when a request does not name one.
+    #[structopt(long = "default-model", env = "GAIA_DEFAULT_MODEL")]
+    pub default_model: Option<String>,
+
+    /// JSON file holding agent personas; enables `/admin/agents` and `agent`.
+    #[structopt(long = "agents-file", env = "GAIA_AGENTS_FILE", parse(from_os_str))]
+    pub agents_file: Option<PathBuf>,
+
+    /// Directory of the database holding chat sessions; enables `/sessions`.
+    #[structopt(long = "sessions-db", env = "GAIA_SESSIONS_DB", parse(from_os_str))]
+    pub sessions_db: Option<PathBuf>,
+
+    /// Directory of the database holding queued jobs; enables `/jobs`.
+    #[structopt(long = "jobs-db", env = "GAIA_JOBS_DB", parse(from_os_str))]
+    pub jobs_db: Option<PathBuf>,
+
+    /// Jobs run concurrently by the queue's worker pool.
+    #[structopt(long = "job-workers", env = "GAIA_JOB_WORKERS")]
+    pub job_workers: Option<usize>,
+
+    /// Jobs a single API key or client IP may have waiting in the queue.
+    #[structopt(long = "max-queued-jobs", env = "GAIA_MAX_QUEUED_JOBS")]
+    pub max_queued_jobs: Option<usize>,
+
+    /// Secret used to sign job webhook payloads; webhooks are refused without it.
+    #[structopt(long = "webhook-secret", env = "GAIA_WEBHOOK_SECRET")]
+    pub webhook_secret: Option<String>,
+
+    /// Context window, in tokens, of the node's chat model.
+    #[structopt(long = "chat-ctx-size", env = "GAIA_CHAT_CTX_SIZE")]
+    pub chat_ctx_size: Option<u32>,
+
+    /// How chats exceeding the context window are shortened: `drop_oldest`,
+    /// `keep_last` or `summarize`.
+    #[structopt(long = "context-strategy", env = "GAIA_CONTEXT_STRATEGY")]
+    pub context_strategy: Option<ContextStrategy>,
+
+    /// Recent messages kept by `keep_last` and left unsummarized by `summarize`.
+    #[structopt(long = "context-keep-last", env = "GAIA_CONTEXT_KEEP_LAST")]
+    pub context_keep_last: Option<usize>,
+
+    /// URL of the Qdrant instance holding the node's knowledge base.
+    #[structopt(long = "qdrant-url", env = "GAIA_QDRANT_URL")]
+    pub qdrant_url: Option<String>,
+
+    /// API key sent to Qdrant, if it requires one.
+    #[structopt(
+        long = "qdrant-api-key",
+        env = "GAIA_QDRANT_API_KEY",
+        hide_env_values = true
+    )]
+    pub qdrant_api_key: Option<String>,
+
+    /// Qdrant collection documents are ingested into.
+    #[structopt(long = "qdrant-collection", env = "GAIA_QDRANT_COLLECTION")]
+    pub qdrant_collection: Option<String>,
+
+    /// Characters per knowledge-base chunk.
+    #[structopt(long = "chunk-size", env = "GAIA_CHUNK_SIZE")]
+    pub chunk_size: Option<usize>,
+
+    /// Characters shared by consecutive knowledge-base chunks.
+    #[structopt(long = "chunk-overlap", env = "GAIA_CHUNK_OVERLAP")]
+    pub chunk_overlap: Option<usize>,
+
+    /// Hosts the agent's `http_fetch` tool may reach, comma-separated;
+    /// `*.example.com` also matches subdomains.
+    #[structopt(
+        long = "agent-fetch-allowlist",
+        env = "GAIA_AGENT_FETCH_ALLOWLIST",
+        use_delimiter = true
+    )]
+    pub agent_fetch_allowlist: Option<Vec<String>>,
+
+    /// Qdrant collection holding the agent's long-term memory.
+    #[structopt(long = "agent-memory-collection", env = "GAIA_AGENT_MEMORY_COLLECTION")]
+    pub agent_memory_collection: Option<String>,
+
+    /// Models served by their own nodes; only read from the config file.
+    #[structopt(skip)]
+    pub models: Option<Vec<ModelRoute>>,
+}
+
+impl ServerArgs {
+    /// Fills every unset field from `other`.
+    fn or(self, other: ServerArgs) -> ServerArgs {
+        ServerArgs {
+            config_file: self.config_file.or(other.config_file),
+            gaia_base_url: self.gaia_base_url.or(other.gaia_base_url),
+            gaia_upstreams: self.gaia_upstreams.or(other.gaia_upstreams),
+            load_balancing: self.load_balancing.or(other.load_balancing),
+            max_upstream_failures: self.max_upstream_failures.or(other.max_upstream_failures),
+            ejection_duration: self.ejection_duration.or(other.ejection_duration),
+            health_check_interval: self.health_check_interval.or(other.health_check_interval),
+            gaia_api_key: self.gaia_api_key.or(other.gaia_api_key),
+            host: self.host.or(other.host),
+            port: self.port.or(other.port),
+            workers: self.workers.or(other.workers),
+            tls_cert: self.tls_cert.or(other.tls_cert),
+            tls_key: self.tls_key.or(other.tls_key),
+            max_concurrency: self.max_concurrency.or(other.max_concurrency),
+            request_timeout: self.request_timeout.or(other.request_timeout),
+            api_keys_file: self.api_keys_file.or(other.api_keys_file),
+            rate_limit_rpm: self.rate_limit_rpm.or(other.rate_limit_rpm),
+            rate_limit_tpm: self.rate_limit_tpm.or(other.rate_limit_tpm),
+            daily_token_quota: self.daily_token_quota.or(other.daily_token_quota),
+            monthly_token_quota: self.monthly_token_quota.or(other.monthly_token_quota),
+            rate_limit_state_file: self.rate_limit_state_file.or(other.rate_limit_state_file),
+            shutdown_timeout: self.shutdown_timeout.or(other.shutdown_timeout),
+            default_model: self.default_model.or(other.default_model),
+            agents_file: self.agents_file.or(other.agents_file),
+            sessions_db: self.sessions_db.or(other.sessions_db),
+            jobs_db: self.jobs_db.or(other.jobs_db),
+            job_workers: self.job_workers.or(other.job_workers),
+            max_queued_jobs: self.max_queued_jobs.or(other.max_queued_jobs),
+            webhook_secret: self.webhook_secret.or(other.webhook_secret),
+            chat_ctx_size: self.chat_ctx_size.or(other.chat_ctx_size),
+            context_strategy: self.context_strategy.or(other.context_strategy),
+            context_keep_last: self.context_keep_last.or(other.context_keep_last),
+            qdrant_url: self.qdrant_url.or(other.qdrant_url),
+            qdrant_api_key: self.qdrant_api_key.or(other.qdrant_api_key),
+            qdrant_collection: self.qdrant_collection.or(other.qdrant_collection),
+            chunk_size: self.chunk_size.or(other.chunk_size),
+            chunk_overlap: self.chunk_overlap.or(other.chunk_overlap),
+            agent_fetch_allowlist: self.agent_fetch_allowlist.or(other.agent_fetch_allowlist),
+            agent_memory_collection: self
+                .agent_memory_collection
+                .or(other.agent_memory_collection),
+            models: self.models.or(other.models),
+        }
+    }
+}
+
+/// Fully resolved HTTP server configuration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerConfig {
+    /// Upstream nodes; the first is the node managed by this operator.
+    pub upstreams: Vec<UpstreamSpec>,
+    pub pool: PoolSettings,
+    pub health_check_interval: u64,
+    pub gaia_api_key: String,
+    pub host: String,
+    pub port: u16,
+    pub workers: Option<usize>,
+    pub tls: Option<TlsConfig>,
+    pub max_concurrency: usize,
+    pub request_timeout: u64,
+    pub api_keys_file: Option<PathBuf>,
+    pub rate_limits: RateLimitConfig,
+    pub shutdown_timeout: u64,
+    pub default_model: String,
+    pub models: Vec<ModelRoute>,
+    pub knowledge: KnowledgeConfig,
+    pub agent: AgentConfig,
+    pub agents_file: Option<PathBuf>,
+    pub sessions_db: Option<PathBuf>,
+    pub jobs_db: Option<PathBuf>,
+    pub job_workers: usize,
+    pub max_queued_jobs: usize,
+    pub webhook_secret: Option<String>,
+    pub context: ContextSettings,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl ServerConfig {
+    /// Resolves the configuration from CLI/environment arguments, the optional
+    /// config file they point to, and the defaults.
+    pub fn load(args: ServerArgs) -> Result<Self> {
+        let args = match &args.config_file {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| eyre!("Failed to read {}: {}", path.display(), e))?;
+                let file: ServerArgs = serde_json::from_str(&contents)
+                    .map_err(|e| eyre!("Invalid server config {}: {}", path.display(), e))?;
+                args.or(file)
+            }
+            None => args,
+        };
+
+        let upstreams = match (args.gaia_upstreams, args.gaia_base_url) {
+            (Some(_), Some(_)) => {
+                return Err(eyre!(
+                    "gaia_upstreams and gaia_base_url cannot be set together"
+                ))
+            }
+            (Some(upstreams), None) => upstreams
+                .iter()
+                .map(|upstream| upstream.parse::<UpstreamSpec>().map_err(|e| eyre!(e)))
+                .collect::<Result<Vec<_>>>()?,
+            (None, base_url) => {
+                let gaia_base_url = base_url.unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+                url::Url::parse(&gaia_base_url)
+                    .map_err(|e| eyre!("Invalid gaia_base_url {}: {}", gaia_base_url, e))?;
+                vec![UpstreamSpec {
+                    url: gaia_base_url,
+                    weight: 1,
+                }]
+            }
+        };
+        if upstreams.is_empty() {
+            return Err(eyre!("gaia_upstreams must list at least one node"));
+        }
+        let models = args.models.unwrap_or_default();
+        for (i, route) in models.iter().enumerate() {
+            if models[..i].iter().any(|other| other.model == route.model) {
+                return Err(eyre!("Model {} is routed more than once", route.model));
+            }
+            if route.upstreams.is_empty() {
+                return Err(eyre!("Model {} has no upstreams", route.model));
+            }
+            for upstream in &route.upstreams {
+                if upstream.weight == 0 {
+                    return Err(eyre!(
+                        "Upstream weight must be greater than 0: {}",
+                        upstream.url
+                    ));
+                }
+                url::Url::parse(&upstream.url)
+                    .map_err(|e| eyre!("Invalid upstream URL {}: {}", upstream.url, e))?;
+            }
+        }
+
+        let knowledge = KnowledgeConfig {
+            qdrant_url: args
+                .qdrant_url
+                .unwrap_or_else(|| DEFAULT_QDRANT_URL.to_string()),
+            qdrant_api_key: args.qdrant_api_key,
+            collection: args
+                .qdrant_collection
+                .unwrap_or_else(|| DEFAULT_COLLECTION.to_string()),
+            chunk_size: args.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+            chunk_overlap: args.chunk_overlap.unwrap_or(DEFAULT_CHUNK_OVERLAP),
+        };
+        url::Url::parse(&knowledge.qdrant_url)
+            .map_err(|e| eyre!("Invalid qdrant_url {}: {}", knowledge.qdrant_url, e))?;
+        if knowledge.chunk_size == 0 {
+            return Err(eyre!("chunk_size must be greater than 0"));
+        }
+        if knowledge.chunk_overlap >= knowledge.chunk_size {
+            return Err(eyre!("chunk_overlap must be smaller than chunk_size"));
+        }
+
+        let agent = AgentConfig {
+            fetch_allowlist: args.agent_fetch_allowlist.unwrap_or_default(),
+            memory_collection: args
+                .agent_memory_collection
+                .unwrap_or_else(|| DEFAULT_MEMORY_COLLECTION.to_string()),
+        };
+        if agent.memory_collection == knowledge.collection {
+            return Err(eyre!(
+                "agent_memory_collection must differ from qdrant_collection"
+            ));
+        }
+
+        if args.chat_ctx_size == Some(0) {
+            return Err(eyre!("chat_ctx_size must be greater than 0"));
+        }
+        if args.job_workers == Some(0) {
+            return Err(eyre!("job_workers must be greater than 0"));
+        }
+        if args.max_queued_jobs == Some(0) {
+            return Err(eyre!("max_queued_jobs must be greater than 0"));
+        }
+        if args.context_keep_last == Some(0) {
+            return Err(eyre!("context_keep_last must be greater than 0"));
+        }
+        if args.max_upstream_failures == Some(0) {
+            return Err(eyre!("max_upstream_failures must be greater than 0"));
+        }
+        if args.health_check_interval == Some(0) {
+            return Err(eyre!("health_check_interval must be greater than 0"));
+        }
+
+        if args.workers == Some(0) {
+            return Err(eyre!("workers must be greater than 0"));
+        }
+        if args.max_concurrency == Some(0) {
+            return Err(eyre!("max_concurrency must be greater than 0"));
+        }
+
+        let tls = match (args.tls_cert, args.tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                cert_path,
+                key_path,
+            }),
+            (None, None) => None,
+            _ => return Err(eyre!("tls_cert and tls_key must be set together")),
+        };
+
+        let defaults = PoolSettings::default();
+        Ok(Self {
+            upstreams,
+            pool: PoolSettings {
+                strategy: args.load_balancing.unwrap_or(defaults.strategy),
+                max_failures: args.max_upstream_failures.unwrap_or(defaults.max_failures),
+                ejection_duration: args
+                    .ejection_duration
+                    .map(Duration::from_secs)
+                    .unwrap_or(defaults.ejection_duration),
+            },
+            health_check_interval: args
+                .health_check_interval
+                .unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL),
+            gaia_api_key: args.gaia_api_key.unwrap_or_default(),
+            host: args.host.unwrap_or_else(|| DEFAULT_HOST.to_string()),
+            port: args.port.unwrap_or(DEFAULT_PORT),
+            workers: args.workers,
+            tls,
+            max_concurrency: args.max_concurrency.unwrap_or(DEFAULT_MAX_CONCURRENCY),
+            request_timeout: args
+                .request_timeout
+                .unwrap_or(DEFAULT_REQUEST_TIMEOUT.as_secs()),
+            api_keys_file: args.api_keys_file,
+            rate_limits: RateLimitConfig {
+                requests_per_minute: args.rate_limit_rpm,
+                tokens_per_minute: args.rate_limit_tpm,
+                daily_token_quota: args.daily_token_quota,
+                monthly_token_quota: args.monthly_token_quota,
+                state_file: args.rate_limit_state_file,
+            },
+            shutdown_timeout: args.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            default_model: args
+                .default_model
+                .unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+            models,
+            knowledge,
+            agent,
+            agents_file: args.agents_file,
+            sessions_db: args.sessions_db,
+            jobs_db: args.jobs_db,
+            job_workers: args.job_workers.unwrap_or(DEFAULT_JOB_WORKERS),
+            max_queued_jobs: args.max_queued_jobs.unwrap_or(DEFAULT_MAX_QUEUED_JOBS),
+            webhook_secret: args.webhook_secret,
+            context: ContextSettings {
+                size: args.chat_ctx_size.unwrap_or(DEFAULT_CONTEXT_SIZE),
+                strategy: args.context_strategy.unwrap_or_default(),
+                keep_last: args.context_keep_last.unwrap_or(DEFAULT_KEEP_LAST),
+            },
+        })
+    }
+}
+
+impl TlsConfig {
+    /// Reads the certificate chain and private key into a rustls server config.
+    pub fn load(&self) -> Result<rustls::ServerConfig> {
+        let cert_file = std::fs::File::open(&self.cert_path)
+            .map_err(|e| eyre!("Failed to open {}: {}", self.cert_path.display(), e))?;
+        let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(cert_file))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| eyre!("Invalid certificate {}: {}", self.cert_path.display(), e))?;
+
+        let key_file = std::fs::File::open(&self.key_path)
+            .map_err(|e| eyre!("Failed to open {}: {}", self.key_path.display(), e))?;
+        let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(key_file))
+            .map_err(|e| eyre!("Invalid private key {}: {}", self.key_path.display(), e))?
+            .ok_or_else(|| eyre!("No private key found in {}", self.key_path.display()))?;
+
+        let provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+
+        Ok(config)
+    }
+}
+
+/// Turns a node's public URL into the base URL of its OpenAI-compatible API.
+pub fn api_base_url(public_url: &str) -> String {
+    let public_url = public_url.trim_end_matches('/');
+    if public_url.ends_with("/v1") {
+        public_url.to_string()
+    } else {
+        format!("{}/v1", public_url)
+    }
+}
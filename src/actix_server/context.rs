@@ -0,0 +1,421 @@
+use openai_dive::v1::resources::chat::{ChatMessage, ChatMessageContent};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::str::FromStr;
+
+/// Context window of the node's chat model, matching gaianet's default.
+pub const DEFAULT_CONTEXT_SIZE: u32 = 4096;
+/// Non-system messages kept by [`ContextStrategy::KeepLast`] and left
+/// unsummarized by [`ContextStrategy::Summarize`] when not configured.
+pub const DEFAULT_KEEP_LAST: usize = 6;
+/// Tokens kept free for the reply when the request does not set `max_tokens`.
+pub const DEFAULT_REPLY_RESERVE: u32 = 512;
+
+/// Tokens added per message for the role and chat-template markers.
+const MESSAGE_OVERHEAD: u32 = 4;
+
+/// What to do when a conversation does not fit in the context window.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextStrategy {
+    /// Drop the oldest non-system messages until the rest fits.
+    #[default]
+    DropOldest,
+    /// Keep system messages and the last N messages only.
+    KeepLast,
+    /// Replace older turns with a summary written by the model itself.
+    Summarize,
+}
+
+impl FromStr for ContextStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_oldest" => Ok(Self::DropOldest),
+            "keep_last" => Ok(Self::KeepLast),
+            "summarize" => Ok(Self::Summarize),
+            _ => Err(format!(
+                "Invalid context strategy: {}. Must be 'drop_oldest', 'keep_last' or 'summarize'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContextSettings {
+    /// Context window of the chat model, in tokens.
+    pub size: u32,
+    pub strategy: ContextStrategy,
+    pub keep_last: usize,
+}
+
+impl Default for ContextSettings {
+    fn default() -> Self {
+        Self {
+            size: DEFAULT_CONTEXT_SIZE,
+            strategy: ContextStrategy::default(),
+            keep_last: DEFAULT_KEEP_LAST,
+        }
+    }
+}
+
+/// What was left out of a conversation to make it fit, returned alongside
+/// the completion.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContextReport {
+    pub strategy: ContextStrategy,
+    pub context_size: u32,
+    pub estimated_tokens_before: u32,
+    pub estimated_tokens_after: u32,
+    /// Messages removed without a trace.
+    pub dropped_messages: usize,
+    /// Messages replaced by a summary.
+    pub summarized_messages: usize,
+    /// Tokens spent writing a new summary. Non-streamed completions count
+    /// them in their usage; streams are charged for them separately when
+    /// they start, since the usage they report covers the reply only.
+    #[serde(default)]
+    pub summary_tokens: u32,
+    /// The summary sent with the request, for callers that keep it between
+    /// turns.
+    #[serde(skip)]
+    pub summary: Option<ContextSummary>,
+}
+
+/// A summary of the first `messages` non-system messages of a conversation,
+/// which later turns can reuse instead of summarizing them again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ContextSummary {
+    pub messages: usize,
+    pub text: String,
+}
+
+/// Rough token count of a message list, at about four characters per token.
+///
+/// Only the text of the messages is counted, plus a fixed overhead per
+/// message; real tokenizers differ, so budgets should leave some headroom.
+pub fn estimate_tokens(messages: &[ChatMessage]) -> u32 {
+    messages.iter().map(estimate_message_tokens).sum()
+}
+
+fn estimate_message_tokens(message: &ChatMessage) -> u32 {
+    let characters = serde_json::to_value(message)
+        .map(|value| text_length(&value))
+        .unwrap_or_default();
+    (characters / 4) as u32 + MESSAGE_OVERHEAD
+}
+
+/// Total length of every string in a JSON value, ignoring keys.
+fn text_length(value: &Value) -> usize {
+    match value {
+        Value::String(text) => text.len(),
+        Value::Array(values) => values.iter().map(text_length).sum(),
+        Value::Object(fields) => fields.values().map(text_length).sum(),
+        _ => 0,
+    }
+}
+
+fn is_system(message: &ChatMessage) -> bool {
+    matches!(message, ChatMessage::System { .. })
+}
+
+/// Drops the oldest non-system messages until the total fits in `budget`.
+///
+/// The latest message is always kept, and tool results whose call was
+/// dropped go with it. Returns how many messages were removed.
+pub fn drop_oldest(messages: &mut Vec<ChatMessage>, budget: u32) -> usize {
+    let before = messages.len();
+    let mut total = estimate_tokens(messages);
+    let mut i = 0;
+    while total > budget && i + 1 < messages.len() {
+        if is_system(&messages[i]) {
+            i += 1;
+            continue;
+        }
+        total -= estimate_message_tokens(&messages[i]);
+        messages.remove(i);
+    }
+    drop_orphaned_tool_results(messages);
+    before - messages.len()
+}
+
+/// Keeps system messages and the last `keep` other messages.
+pub fn keep_last(messages: &mut Vec<ChatMessage>, keep: usize) -> usize {
+    let before = messages.len();
+    let others = messages.iter().filter(|m| !is_system(m)).count();
+    let mut to_drop = others.saturating_sub(keep);
+    messages.retain(|message| {
+        if to_drop > 0 && !is_system(message) {
+            to_drop -= 1;
+            return false;
+        }
+        true
+    });
+    drop_orphaned_tool_results(messages);
+    before - messages.len()
+}
+
+/// Splits off the non-system messages older than the last `keep`, which are
+/// the ones to summarize.
+pub fn split_for_summary(
+    messages: Vec<ChatMessage>,
+    keep: usize,
+) -> (Vec<ChatMessage>, Vec<ChatMessage>) {
+    let others = messages.iter().filter(|m| !is_system(m)).count();
+    let (mut kept, mut older) = split_oldest(messages, others.saturating_sub(keep));
+    // A tool result cannot be kept once the call that produced it is summarized.
+    while let Some(position) = kept
+        .iter()
+        .position(|m| !is_system(m))
+        .filter(|&i| matches!(kept[i], ChatMessage::Tool { .. }))
+    {
+        older.push(kept.remove(position));
+    }
+    (kept, older)
+}
+
+/// Replaces the messages covered by `summary` with the summary itself.
+pub fn apply_summary(messages: Vec<ChatMessage>, summary: &ContextSummary) -> Vec<ChatMessage> {
+    let (mut kept, _) = split_oldest(messages, summary.messages);
+    insert_summary(&mut kept, &summary.text);
+    kept
+}
+
+/// Splits off the first `count` non-system messages.
+fn split_oldest(messages: Vec<ChatMessage>, count: usize) -> (Vec<ChatMessage>, Vec<ChatMessage>) {
+    let mut remaining = count;
+    let mut older = Vec::new();
+    let mut kept = Vec::new();
+    for message in messages {
+        if remaining > 0 && !is_system(&message) {
+            remaining -= 1;
+            older.push(message);
+        } else {
+            kept.push(message);
+        }
+    }
+    (kept, older)
+}
+
+/// Builds the request asking the model to summarize `older`, keeping only
+/// the most recent part of the transcript when it exceeds `budget` tokens.
+///
+/// A `previous` summary of the messages before `older` is always kept, so
+/// summaries can be extended rather than rewritten from scratch.
+pub fn summary_prompt(
+    older: &[ChatMessage],
+    previous: Option<&str>,
+    budget: u32,
+) -> Vec<ChatMessage> {
+    let mut transcript = older
+        .iter()
+        .filter_map(|message| {
+            let value = serde_json::to_value(message).ok()?;
+            let role = value["role"].as_str().unwrap_or("unknown").to_string();
+            let mut text = String::new();
+            collect_text(&value["content"], &mut text);
+            Some(format!("{}: {}", role, text.trim()))
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let previous = previous.map(|text| format!("Summary of the conversation so far: {}\n", text));
+    let max_length = (budget as usize * 4).saturating_sub(previous.as_ref().map_or(0, String::len));
+    if transcript.len() > max_length {
+        let mut start = transcript.len() - max_length;
+        while !transcript.is_char_boundary(start) {
+            start += 1;
+        }
+        transcript.drain(..start);
+    }
+    if let Some(previous) = previous {
+        transcript.insert_str(0, &previous);
+    }
+
+    vec![
+        ChatMessage::System {
+            content: ChatMessageContent::Text(
+                "Summarize the conversation below in a few sentences. Keep names, numbers, \
+                 decisions and open questions. Reply with the summary only."
+                    .to_string(),
+            ),
+            name: None,
+        },
+        ChatMessage::User {
+            content: ChatMessageContent::Text(transcript),
+            name: None,
+        },
+    ]
+}
+
+/// Inserts `summary` after the leading system messages.
+fn insert_summary(messages: &mut Vec<ChatMessage>, summary: &str) {
+    let position = messages.iter().take_while(|m| is_system(m)).count();
+    messages.insert(
+        position,
+        ChatMessage::System {
+            content: ChatMessageContent::Text(format!(
+                "Summary of the earlier conversation: {}",
+                summary
+            )),
+            name: None,
+        },
+    );
+}
+
+fn collect_text(value: &Value, text: &mut String) {
+    match value {
+        Value::String(s) => {
+            text.push_str(s);
+            text.push(' ');
+        }
+        Value::Array(values) => values.iter().for_each(|v| collect_text(v, text)),
+        Value::Object(fields) => {
+            if let Some(value) = fields.get("text") {
+                collect_text(value, text);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Removes tool results left at the start of the conversation without the
+/// assistant message that requested them.
+fn drop_orphaned_tool_results(messages: &mut Vec<ChatMessage>) {
+    while let Some(position) = messages
+        .iter()
+        .position(|m| !is_system(m))
+        .filter(|&i| matches!(messages[i], ChatMessage::Tool { .. }))
+    {
+        // Never remove the message being answered.
+        if position + 1 == messages.len() {
+            break;
+        }
+        messages.remove(position);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system(text: &str) -> ChatMessage {
+        ChatMessage::System {
+            content: ChatMessageContent::Text(text.to_string()),
+            name: None,
+        }
+    }
+
+    fn user(text: &str) -> ChatMessage {
+        ChatMessage::User {
+            content: ChatMessageContent::Text(text.to_string()),
+            name: None,
+        }
+    }
+
+    fn tool(text: &str) -> ChatMessage {
+        ChatMessage::Tool {
+            content: text.to_string(),
+            tool_call_id: "call_1".to_string(),
+        }
+    }
+
+    fn texts(messages: &[ChatMessage]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|message| {
+                let mut text = String::new();
+                collect_text(
+                    &serde_json::to_value(message).unwrap()["content"],
+                    &mut text,
+                );
+                text.trim().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tokens_are_estimated_from_text_length() {
+        assert_eq!(estimate_tokens(&[]), 0);
+        assert_eq!(
+            estimate_tokens(&[user(&"a".repeat(400))]) - estimate_tokens(&[user("")]),
+            100
+        );
+        assert_eq!(
+            estimate_tokens(&[system("rules"), user("hi")]),
+            estimate_tokens(&[system("rules")]) + estimate_tokens(&[user("hi")])
+        );
+    }
+
+    #[test]
+    fn drop_oldest_keeps_system_messages_and_the_latest_one() {
+        let long = "a".repeat(400);
+        let mut messages = vec![system("rules"), user(&long), user(&long), user("last")];
+        let budget = estimate_tokens(&[system("rules"), user(&long), user("last")]);
+
+        assert_eq!(drop_oldest(&mut messages, budget), 1);
+        assert_eq!(texts(&messages), ["rules", long.as_str(), "last"]);
+
+        assert_eq!(drop_oldest(&mut messages, 0), 1);
+        assert_eq!(texts(&messages), ["rules", "last"]);
+    }
+
+    #[test]
+    fn drop_oldest_removes_orphaned_tool_results() {
+        let mut messages = vec![user(&"a".repeat(400)), tool("42"), user("last")];
+
+        assert_eq!(
+            drop_oldest(&mut messages, estimate_tokens(&[user("last")])),
+            2
+        );
+        assert_eq!(texts(&messages), ["last"]);
+    }
+
+    #[test]
+    fn keep_last_keeps_system_messages() {
+        let mut messages = vec![system("rules"), user("1"), user("2"), user("3")];
+
+        assert_eq!(keep_last(&mut messages, 2), 1);
+        assert_eq!(texts(&messages), ["rules", "2", "3"]);
+        assert_eq!(keep_last(&mut messages, 5), 0);
+    }
+
+    #[test]
+    fn tool_results_are_summarized_with_their_call() {
+        let messages = vec![system("rules"), user("1"), tool("42"), user("2")];
+
+        let (kept, older) = split_for_summary(messages, 2);
+        assert_eq!(texts(&kept), ["rules", "2"]);
+        assert_eq!(texts(&older), ["1", "42"]);
+    }
+
+    #[test]
+    fn summaries_replace_the_messages_they_cover() {
+        let summary = ContextSummary {
+            messages: 2,
+            text: "Counted to two.".to_string(),
+        };
+        let messages = vec![system("rules"), user("1"), user("2"), user("3")];
+
+        assert_eq!(
+            texts(&apply_summary(messages, &summary)),
+            [
+                "rules",
+                "Summary of the earlier conversation: Counted to two.",
+                "3"
+            ]
+        );
+    }
+
+    #[test]
+    fn summary_prompts_keep_the_previous_summary() {
+        let older = [user(&"a".repeat(400)), user("recent")];
+
+        let prompt = summary_prompt(&older, Some("Said hello."), 20);
+        let transcript = &texts(&prompt)[1];
+        assert!(transcript.starts_with("Summary of the conversation so far: Said hello."));
+        assert!(transcript.ends_with("user: recent"));
+        assert!(transcript.len() <= 20 * 4);
+    }
+}
@@ -0,0 +1,206 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    error::JsonPayloadError,
+    http::{
+        header::{HeaderName, HeaderValue},
+        StatusCode,
+    },
+    middleware::Next,
+    Error, HttpRequest, HttpResponse, ResponseError,
+};
+use serde::Serialize;
+
+use super::gaia_client::APIError;
+
+/// Header carrying the request id, accepted from clients and echoed back.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest client-supplied request id that is accepted as-is.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Error body shared by every route, compatible with the OpenAI error format.
+///
+/// ```json
+/// {"error": {"type": "upstream_error", "code": "upstream_timeout", "message": "...", "param": null, "request_id": "..."}}
+/// ```
+#[derive(Serialize)]
+pub struct ErrorBody {
+    pub error: ErrorDetail,
+}
+
+#[derive(Serialize)]
+pub struct ErrorDetail {
+    pub r#type: &'static str,
+    pub code: &'static str,
+    pub message: String,
+    pub param: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl ErrorBody {
+    pub fn new(r#type: &'static str, code: &'static str, message: String) -> Self {
+        Self {
+            error: ErrorDetail {
+                r#type,
+                code,
+                message,
+                param: None,
+                request_id: current_request_id(),
+            },
+        }
+    }
+}
+
+/// Builds a JSON error response tagged with the current request id.
+pub fn error_response(
+    status: StatusCode,
+    r#type: &'static str,
+    code: &'static str,
+    message: String,
+) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorBody::new(r#type, code, message))
+}
+
+/// The id of the request being handled, if called from within one.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+impl APIError {
+    /// Machine-readable `(type, code)` pair describing the error.
+    ///
+    /// Clients can retry `upstream_unavailable`, `upstream_timeout` and
+    /// `upstream_rate_limited`; the other codes will fail the same way again.
+    pub fn kind(&self) -> (&'static str, &'static str) {
+        use openai_dive::v1::error::APIError as Upstream;
+
+        match self {
+            APIError::InvalidParameters(_) => ("invalid_request_error", "invalid_parameters"),
+            APIError::InvalidRequest(_) => ("invalid_request_error", "invalid_request"),
+            APIError::PayloadTooLarge(_) => ("invalid_request_error", "payload_too_large"),
+            APIError::ChatCompletionError(_)
+            | APIError::ImageCreationError(_)
+            | APIError::ImageEditError(_) => ("invalid_request_error", "invalid_parameters"),
+            APIError::MaxIterationsExceeded(_) => ("invalid_request_error", "max_iterations"),
+            APIError::Timeout(_) => ("upstream_error", "upstream_timeout"),
+            APIError::NoUpstreamAvailable => ("upstream_error", "upstream_unavailable"),
+            APIError::ModelNotFound(_) => ("invalid_request_error", "model_not_found"),
+            APIError::AgentNotFound(_) => ("invalid_request_error", "agent_not_found"),
+            APIError::UnexpectedResponse(_) => ("upstream_error", "upstream_invalid_response"),
+            APIError::VectorStoreError(_) => ("upstream_error", "vector_store_error"),
+            APIError::ReqwestError(e) if e.is_timeout() => ("upstream_error", "upstream_timeout"),
+            APIError::ReqwestError(e) if e.is_connect() => {
+                ("upstream_error", "upstream_unavailable")
+            }
+            APIError::OpenAiDiveError(e) => match e {
+                Upstream::AuthenticationError(_) => ("upstream_error", "upstream_unauthorized"),
+                Upstream::PermissionError(_) => ("upstream_error", "upstream_forbidden"),
+                Upstream::BadRequestError(_) | Upstream::UnprocessableEntityError(_) => {
+                    ("upstream_error", "upstream_bad_request")
+                }
+                Upstream::NotFoundError(_) => ("upstream_error", "upstream_not_found"),
+                Upstream::RateLimitError(_) => ("upstream_error", "upstream_rate_limited"),
+                Upstream::UnknownError(status, _) if (400..500).contains(status) => {
+                    ("upstream_error", "upstream_rejected")
+                }
+                _ => ("upstream_error", "upstream_unavailable"),
+            },
+            APIError::ReqwestError(_) => ("upstream_error", "upstream_unavailable"),
+            APIError::SerializationError(_) | APIError::IOError(_) => {
+                ("api_error", "internal_error")
+            }
+        }
+    }
+
+    /// Whether the node itself failed, as opposed to rejecting the request.
+    pub fn is_upstream_failure(&self) -> bool {
+        matches!(self.kind().1, "upstream_unavailable" | "upstream_timeout")
+    }
+}
+
+impl ResponseError for APIError {
+    fn status_code(&self) -> StatusCode {
+        use openai_dive::v1::error::APIError as Upstream;
+
+        match self {
+            // Upstream 4xx responses keep their status so clients see the real cause.
+            APIError::OpenAiDiveError(e) => match e {
+                Upstream::AuthenticationError(_) => StatusCode::UNAUTHORIZED,
+                Upstream::PermissionError(_) => StatusCode::FORBIDDEN,
+                Upstream::BadRequestError(_) => StatusCode::BAD_REQUEST,
+                Upstream::NotFoundError(_) => StatusCode::NOT_FOUND,
+                Upstream::UnprocessableEntityError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                Upstream::RateLimitError(_) => StatusCode::TOO_MANY_REQUESTS,
+                Upstream::UnknownError(status, _) if (400..500).contains(status) => {
+                    StatusCode::from_u16(*status).unwrap_or(StatusCode::BAD_GATEWAY)
+                }
+                _ => StatusCode::BAD_GATEWAY,
+            },
+            _ => match self.kind().1 {
+                "upstream_timeout" => StatusCode::GATEWAY_TIMEOUT,
+                "upstream_unavailable" | "upstream_invalid_response" | "vector_store_error" => {
+                    StatusCode::BAD_GATEWAY
+                }
+                "max_iterations" => StatusCode::UNPROCESSABLE_ENTITY,
+                "payload_too_large" => StatusCode::PAYLOAD_TOO_LARGE,
+                "model_not_found" | "agent_not_found" => StatusCode::NOT_FOUND,
+                "internal_error" => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::BAD_REQUEST,
+            },
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let (r#type, code) = self.kind();
+        error_response(self.status_code(), r#type, code, self.to_string())
+    }
+}
+
+/// Turns malformed JSON bodies into structured 400 errors.
+pub fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> Error {
+    let status = match &err {
+        JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => {
+            StatusCode::PAYLOAD_TOO_LARGE
+        }
+        JsonPayloadError::ContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    let response = error_response(
+        status,
+        "invalid_request_error",
+        "invalid_json",
+        err.to_string(),
+    );
+    actix_web::error::InternalError::from_response(err, response).into()
+}
+
+/// Middleware assigning every request an id, taken from `X-Request-Id` when
+/// the client sends a usable one.
+///
+/// The id is available to handlers through [`current_request_id`] and is
+/// echoed in the response headers.
+pub async fn assign_request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let mut response = REQUEST_ID.scope(request_id.clone(), next.call(req)).await?;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(response)
+}
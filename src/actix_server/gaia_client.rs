@@ -1,21 +1,54 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::{Stream, StreamExt};
 use openai_dive::v1::{
     api::Client,
     resources::{
         chat::{
-            ChatCompletionParametersBuilder, ChatCompletionParametersBuilderError,
-            ChatCompletionResponse, ChatCompletionResponseFormat, ChatMessage, ChatMessageContent,
-            ImageUrl, ImageUrlType,
+            ChatCompletionChunkResponse, ChatCompletionParameters, ChatCompletionParametersBuilder,
+            ChatCompletionParametersBuilderError, ChatCompletionResponse,
+            ChatCompletionResponseFormat, ChatMessage, ChatMessageContent, ImageUrl, ImageUrlType,
+        },
+        embedding::{
+            EmbeddingEncodingFormat, EmbeddingInput, EmbeddingOutput, EmbeddingParameters,
+            EmbeddingResponse,
         },
         image::{
-            CreateImageParametersBuilder, CreateImageParametersBuilderError,
-            EditImageParametersBuilder, EditImageParametersBuilderError, ImageQuality,
-            ImageResponse, ImageSize, ImageStyle, ResponseFormat,
+            CreateImageParameters, CreateImageParametersBuilder, CreateImageParametersBuilderError,
+            EditImageParameters, EditImageParametersBuilder, EditImageParametersBuilderError,
+            ImageQuality, ImageResponse, ImageSize, ImageStyle, ResponseFormat,
         },
-        shared::FileUpload,
+        model::ListModelResponse,
+        shared::{FileUpload, StopToken},
     },
 };
 
+use gadget_sdk::info;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::{
+    context::{
+        self, ContextReport, ContextSettings, ContextStrategy, ContextSummary,
+        DEFAULT_REPLY_RESERVE,
+    },
+    types::{
+        ChatOptions, ChatResponseFormat, EmbeddingEncoding, EmbeddingOptions, EmbeddingValue,
+        EmbeddingVector, EmbeddingsResponse, MAX_EMBEDDING_INPUTS,
+    },
+    upstream::{InFlightGuard, ModelKind, ModelRoute, PoolSettings, UpstreamPool, UpstreamSpec},
+};
+
+/// Stream of chat completion deltas as returned by the upstream Gaia node.
+pub type ChatCompletionStream = Pin<
+    Box<
+        dyn Stream<Item = Result<ChatCompletionChunkResponse, openai_dive::v1::error::APIError>>
+            + Send,
+    >,
+>;
 
 #[derive(Error, Debug)]
 pub enum APIError {
@@ -43,51 +76,486 @@ pub enum APIError {
     #[error("Invalid request: {0}")]
     InvalidRequest(String),
 
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+
     #[error("IO error: {0}")]
     IOError(String),
+
+    #[error("Tool loop did not finish within {0} iterations")]
+    MaxIterationsExceeded(u32),
+
+    #[error("Upstream request timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("No upstream Gaia node available")]
+    NoUpstreamAvailable,
+
+    #[error("The model `{0}` does not exist")]
+    ModelNotFound(String),
+
+    #[error("The agent `{0}` does not exist")]
+    AgentNotFound(String),
+
+    #[error("Unexpected upstream response: {0}")]
+    UnexpectedResponse(String),
+
+    #[error("Vector store error: {0}")]
+    VectorStoreError(String),
 }
 
+/// Upper bound for a single upstream request unless overridden.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Most nodes a retryable request is tried on before giving up.
+const MAX_UPSTREAM_ATTEMPTS: usize = 3;
+
+/// Reply length allowed for the summary of older turns.
+const SUMMARY_MAX_TOKENS: u32 = 256;
+
 // GaiaNodeClient implementation using openai_dive-like structure
+//
+// A single instance is shared by all request handlers. Requests are balanced
+// across a pool of Gaia nodes sharing one HTTP connection pool, and a
+// semaphore caps how many upstream requests are in flight at the same time.
+// Models with a route are sent to their own pool instead of the default one.
 pub struct GaiaNodeClient {
-    pub base_url: String,
-    pub api_key: String,
+    pool: Arc<UpstreamPool>,
+    routes: Vec<RoutedModel>,
+    permits: Arc<Semaphore>,
+    request_timeout: Duration,
+    context: ContextSettings,
     pub current_model: String,
 }
 
 impl GaiaNodeClient {
-    pub fn new(base_url: String, api_key: String, model: String) -> Self {
-        Self {
-            base_url,
+    pub fn new(
+        base_url: String,
+        api_key: String,
+        model: String,
+        max_concurrency: usize,
+    ) -> Result<Self, APIError> {
+        Self::with_upstreams(
+            &[UpstreamSpec {
+                url: base_url,
+                weight: 1,
+            }],
             api_key,
+            model,
+            max_concurrency,
+            PoolSettings::default(),
+        )
+    }
+
+    /// Builds a client balancing requests across `upstreams`, the first of
+    /// which is the node managed by this operator.
+    pub fn with_upstreams(
+        upstreams: &[UpstreamSpec],
+        api_key: String,
+        model: String,
+        max_concurrency: usize,
+        settings: PoolSettings,
+    ) -> Result<Self, APIError> {
+        let http_client = reqwest::Client::builder()
+            .pool_max_idle_per_host(max_concurrency)
+            .build()?;
+
+        Ok(Self {
+            pool: Arc::new(UpstreamPool::new(upstreams, api_key, http_client, settings)),
+            routes: Vec::new(),
+            permits: Arc::new(Semaphore::new(max_concurrency)),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            context: ContextSettings::default(),
             current_model: model,
+        })
+    }
+
+    /// Sets how long a single upstream request may take before failing with
+    /// [`APIError::Timeout`]. For streams this covers opening the stream.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Sets the context window chat requests are fitted into and how.
+    pub fn with_context(mut self, context: ContextSettings) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// Sends requests naming a routed model to that route's nodes.
+    ///
+    /// Once any route is set, requests naming a model that is neither routed
+    /// nor the default model fail with [`APIError::ModelNotFound`].
+    pub fn with_model_routes(mut self, routes: &[ModelRoute]) -> Self {
+        self.routes = routes
+            .iter()
+            .map(|route| RoutedModel {
+                model: route.model.clone(),
+                kind: route.kind,
+                pool: Arc::new(self.pool.sibling(&route.upstreams)),
+            })
+            .collect();
+        self
+    }
+
+    /// Base URL of the operator's own node.
+    pub fn base_url(&self) -> String {
+        self.pool.primary_url().unwrap_or_default()
+    }
+
+    /// Points the operator's own node at a different URL, keeping the connection pool.
+    pub fn set_base_url(&self, base_url: String) {
+        self.pool.replace_primary(base_url);
+    }
+
+    /// The default pool, used for the default model and unrouted requests.
+    pub fn pool(&self) -> Arc<UpstreamPool> {
+        self.pool.clone()
+    }
+
+    /// Every routed model with its kind and pool, in configuration order.
+    pub fn routed_pools(&self) -> Vec<(String, ModelKind, Arc<UpstreamPool>)> {
+        self.routes
+            .iter()
+            .map(|route| (route.model.clone(), route.kind, route.pool.clone()))
+            .collect()
+    }
+
+    /// Model used for `kind` when a request does not name one: the first
+    /// route of that kind, or the client's current model.
+    fn default_model(&self, kind: ModelKind) -> String {
+        self.routes
+            .iter()
+            .find(|route| route.kind == kind)
+            .map(|route| route.model.clone())
+            .unwrap_or_else(|| self.current_model.clone())
+    }
+
+    /// Pool serving `model`.
+    fn pool_for(&self, model: &str) -> Result<Arc<UpstreamPool>, APIError> {
+        if let Some(route) = self.routes.iter().find(|route| route.model == model) {
+            return Ok(route.pool.clone());
+        }
+        if self.routes.is_empty() || model == self.current_model {
+            Ok(self.pool.clone())
+        } else {
+            Err(APIError::ModelNotFound(model.to_string()))
+        }
+    }
+
+    /// Waits for a free upstream slot.
+    async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the upstream semaphore is never closed")
+    }
+
+    /// Sends a request to a node chosen by the pool, giving each attempt up
+    /// to the configured timeout.
+    ///
+    /// Node failures (connection errors, timeouts and 5xx responses) count
+    /// towards ejecting the node. When `retry` is set such failures are
+    /// retried on a different node; only pass it for idempotent requests.
+    async fn dispatch<T, F, Fut>(
+        &self,
+        pool: &UpstreamPool,
+        retry: bool,
+        mut request: F,
+    ) -> Result<(T, InFlightGuard), APIError>
+    where
+        F: FnMut(Client) -> Fut,
+        Fut: Future<Output = Result<T, openai_dive::v1::error::APIError>>,
+    {
+        let attempts = if retry {
+            pool.len().clamp(1, MAX_UPSTREAM_ATTEMPTS)
+        } else {
+            1
+        };
+        let mut tried = Vec::new();
+        let mut last_error = None;
+
+        for _ in 0..attempts {
+            let Some(upstream) = pool.select(&tried) else {
+                break;
+            };
+            let guard = upstream.start();
+            let result = match tokio::time::timeout(
+                self.request_timeout,
+                request(upstream.client()),
+            )
+            .await
+            {
+                Ok(result) => result.map_err(APIError::from),
+                Err(_) => Err(APIError::Timeout(self.request_timeout)),
+            };
+
+            match result {
+                Ok(response) => {
+                    upstream.record_success();
+                    return Ok((response, guard));
+                }
+                Err(e) if e.is_upstream_failure() => {
+                    info!("Upstream {} failed: {}", upstream.url, e);
+                    pool.record_failure(&upstream);
+                    tried.push(upstream.url.clone());
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
         }
+
+        Err(last_error.unwrap_or(APIError::NoUpstreamAvailable))
+    }
+
+    /// [`GaiaNodeClient::dispatch`] for requests that are finished once they return.
+    async fn upstream<T, F, Fut>(
+        &self,
+        pool: &UpstreamPool,
+        retry: bool,
+        request: F,
+    ) -> Result<T, APIError>
+    where
+        F: FnMut(Client) -> Fut,
+        Fut: Future<Output = Result<T, openai_dive::v1::error::APIError>>,
+    {
+        let (response, _guard) = self.dispatch(pool, retry, request).await?;
+        Ok(response)
     }
 
     pub async fn chat(
         &self,
         messages: Vec<ChatMessage>,
+        options: &ChatOptions,
     ) -> Result<ChatCompletionResponse, APIError> {
-        let client = Client::new_with_base(&self.base_url, self.api_key.clone());
+        let (completion, _) = self.chat_with_context(messages, options).await?;
+        Ok(completion)
+    }
 
-        let parameters = ChatCompletionParametersBuilder::default()
-            .model(self.current_model.clone())
+    /// [`GaiaNodeClient::chat`], also returning what was left out of
+    /// `messages` to fit the context window.
+    pub async fn chat_with_context(
+        &self,
+        messages: Vec<ChatMessage>,
+        options: &ChatOptions,
+    ) -> Result<(ChatCompletionResponse, Option<ContextReport>), APIError> {
+        self.chat_with_summary(messages, options, None).await
+    }
+
+    /// [`GaiaNodeClient::chat_with_context`], reusing `summary` of the
+    /// earliest messages instead of writing a new one when it still helps.
+    ///
+    /// Tokens spent on a new summary are added to the completion's usage.
+    pub async fn chat_with_summary(
+        &self,
+        messages: Vec<ChatMessage>,
+        options: &ChatOptions,
+        summary: Option<&ContextSummary>,
+    ) -> Result<(ChatCompletionResponse, Option<ContextReport>), APIError> {
+        options.validate()?;
+        let (messages, report) = self.fit_context(messages, options, summary).await?;
+        let parameters = self.chat_parameters(messages, options)?;
+
+        let mut completion = self.chat_completion(parameters).await?;
+        if let (Some(usage), Some(report)) = (completion.usage.as_mut(), &report) {
+            usage.total_tokens += report.summary_tokens;
+        }
+        Ok((completion, report))
+    }
+
+    /// Starts a streaming chat completion against the upstream node.
+    ///
+    /// The returned stream owns its upstream connection, so dropping it (for
+    /// example when the HTTP client disconnects) cancels the request. Tokens
+    /// spent on a summary are only in the report; the caller charges them.
+    pub async fn chat_stream(
+        &self,
+        messages: Vec<ChatMessage>,
+        options: &ChatOptions,
+    ) -> Result<(ChatCompletionStream, Option<ContextReport>), APIError> {
+        options.validate()?;
+        let (messages, report) = self.fit_context(messages, options, None).await?;
+        let parameters = self.chat_parameters(messages, options)?;
+
+        Ok((self.chat_completion_stream(parameters).await?, report))
+    }
+
+    /// Rough token count of `messages`; see [`context::estimate_tokens`].
+    pub fn estimate_tokens(&self, messages: &[ChatMessage]) -> u32 {
+        context::estimate_tokens(messages)
+    }
+
+    /// Shortens `messages` to fit the context window, leaving room for the
+    /// reply, with the request's strategy or the configured one.
+    ///
+    /// Returns the messages unchanged and no report when they already fit.
+    /// The summarize strategy reuses `cached` while it covers only messages
+    /// that would be summarized anyway, and extends it when the rest no
+    /// longer fits. If a summary cannot be written the oldest messages are
+    /// dropped instead, and whatever strategy ran, the oldest messages are
+    /// dropped as a last resort until the rest fits.
+    pub async fn fit_context(
+        &self,
+        mut messages: Vec<ChatMessage>,
+        options: &ChatOptions,
+        cached: Option<&ContextSummary>,
+    ) -> Result<(Vec<ChatMessage>, Option<ContextReport>), APIError> {
+        let reserve = options.max_tokens.unwrap_or(DEFAULT_REPLY_RESERVE);
+        let budget = self.context.size.saturating_sub(reserve);
+        let before = context::estimate_tokens(&messages);
+        if before <= budget {
+            return Ok((messages, None));
+        }
+
+        let strategy = options.context_strategy.unwrap_or(self.context.strategy);
+        let mut dropped_messages = 0;
+        let mut summarized_messages = 0;
+        let mut summary_tokens = 0;
+        let mut summary = None;
+        match strategy {
+            ContextStrategy::DropOldest => {}
+            ContextStrategy::KeepLast => {
+                dropped_messages += context::keep_last(&mut messages, self.context.keep_last);
+            }
+            ContextStrategy::Summarize => {
+                let (_, older) =
+                    context::split_for_summary(messages.clone(), self.context.keep_last);
+                summary = cached
+                    .filter(|cached| cached.messages <= older.len())
+                    .cloned();
+                let fits = summary.as_ref().is_some_and(|summary| {
+                    let messages = context::apply_summary(messages.clone(), summary);
+                    context::estimate_tokens(&messages) <= budget
+                });
+                let covered = summary.as_ref().map_or(0, |summary| summary.messages);
+                if !fits && covered < older.len() {
+                    let previous = summary.as_ref().map(|summary| summary.text.as_str());
+                    match self.summarize(&older[covered..], previous, options).await {
+                        Ok((text, tokens)) => {
+                            summary = Some(ContextSummary {
+                                messages: older.len(),
+                                text,
+                            });
+                            summary_tokens = tokens;
+                        }
+                        Err(e) => info!("Failed to summarize older messages: {}", e),
+                    }
+                }
+                if let Some(summary) = &summary {
+                    messages = context::apply_summary(messages, summary);
+                    summarized_messages = summary.messages;
+                }
+            }
+        }
+        dropped_messages += context::drop_oldest(&mut messages, budget);
+
+        let report = ContextReport {
+            strategy,
+            context_size: self.context.size,
+            estimated_tokens_before: before,
+            estimated_tokens_after: context::estimate_tokens(&messages),
+            dropped_messages,
+            summarized_messages,
+            summary_tokens,
+            summary,
+        };
+        Ok((messages, Some(report)))
+    }
+
+    /// Asks the chat model for a short summary of `older`, extending
+    /// `previous` if given, and returns it with the tokens it took.
+    async fn summarize(
+        &self,
+        older: &[ChatMessage],
+        previous: Option<&str>,
+        options: &ChatOptions,
+    ) -> Result<(String, u32), APIError> {
+        let budget = self.context.size.saturating_sub(2 * SUMMARY_MAX_TOKENS);
+        let prompt = context::summary_prompt(older, previous, budget);
+        let prompt_tokens = context::estimate_tokens(&prompt);
+        let summary_options = ChatOptions {
+            model: options.model.clone(),
+            max_tokens: Some(SUMMARY_MAX_TOKENS),
+            ..Default::default()
+        };
+        let parameters = self.chat_parameters(prompt, &summary_options)?;
+        let response = self.chat_completion(parameters).await?;
+
+        let value = serde_json::to_value(&response)?;
+        let text = value["choices"][0]["message"]["content"]
+            .as_str()
+            .map(|text| text.trim().to_string())
+            .filter(|text| !text.is_empty())
+            .ok_or_else(|| APIError::UnexpectedResponse("empty summary".to_string()))?;
+        // Nodes that do not report usage are charged an estimate.
+        let tokens = value["usage"]["total_tokens"]
+            .as_u64()
+            .map_or(prompt_tokens + (text.len() / 4) as u32, |tokens| {
+                tokens as u32
+            });
+        Ok((text, tokens))
+    }
+
+    /// Builds the upstream request from validated `options`, falling back to
+    /// the client's current model when no override is given.
+    fn chat_parameters(
+        &self,
+        messages: Vec<ChatMessage>,
+        options: &ChatOptions,
+    ) -> Result<ChatCompletionParameters, APIError> {
+        let mut builder = ChatCompletionParametersBuilder::default();
+        builder
+            .model(
+                options
+                    .model
+                    .clone()
+                    .unwrap_or_else(|| self.current_model.clone()),
+            )
             .messages(messages)
-            .response_format(ChatCompletionResponseFormat::Text)
-            .build()?;
+            .response_format(match options.response_format {
+                ChatResponseFormat::Text => ChatCompletionResponseFormat::Text,
+                ChatResponseFormat::JsonObject => ChatCompletionResponseFormat::JsonObject,
+            });
 
-        let result = client.chat().create(parameters).await?;
+        if let Some(temperature) = options.temperature {
+            builder.temperature(temperature);
+        }
+        if let Some(top_p) = options.top_p {
+            builder.top_p(top_p);
+        }
+        if let Some(max_tokens) = options.max_tokens {
+            builder.max_tokens(max_tokens);
+        }
+        if let Some(stop) = &options.stop {
+            builder.stop(StopToken::Array(stop.clone()));
+        }
+        if let Some(seed) = options.seed {
+            builder.seed(seed);
+        }
+        if let Some(presence_penalty) = options.presence_penalty {
+            builder.presence_penalty(presence_penalty);
+        }
+        if let Some(frequency_penalty) = options.frequency_penalty {
+            builder.frequency_penalty(frequency_penalty);
+        }
+        if let Some(tools) = &options.tools {
+            builder.tools(tools.clone());
+        }
+        if let Some(tool_choice) = &options.tool_choice {
+            builder.tool_choice(tool_choice.clone());
+        }
 
-        Ok(result)
+        Ok(builder.build()?)
     }
 
     pub async fn analyze_image(
         &self,
         image_url: String,
     ) -> Result<ChatCompletionResponse, APIError> {
-        let client = Client::new_with_base(&self.base_url, self.api_key.clone());
-
         let parameters = ChatCompletionParametersBuilder::default()
-            .model(self.current_model.clone())
+            .model(self.default_model(ModelKind::Vision))
             .messages(vec![
                 ChatMessage::User {
                     content: ChatMessageContent::Text("What is in this image?".to_string()),
@@ -107,9 +575,7 @@ impl GaiaNodeClient {
             ])
             .build()?;
 
-        let result = client.chat().create(parameters).await?;
-
-        Ok(result)
+        self.chat_completion(parameters).await
     }
 
     pub async fn create_image(
@@ -120,11 +586,9 @@ impl GaiaNodeClient {
         size: ImageSize,
         style: ImageStyle,
     ) -> Result<ImageResponse, APIError> {
-        let client = Client::new_with_base(&self.base_url, self.api_key.clone());
-
         let parameters = CreateImageParametersBuilder::default()
             .prompt(prompt)
-            .model(self.current_model.clone())
+            .model(self.default_model(ModelKind::Image))
             .n(n)
             .quality(quality)
             .response_format(ResponseFormat::Url)
@@ -132,9 +596,7 @@ impl GaiaNodeClient {
             .style(style)
             .build()?;
 
-        let result = client.images().create(parameters).await?;
-
-        Ok(result)
+        self.generate_images(parameters).await
     }
 
     pub async fn edit_image(
@@ -145,8 +607,6 @@ impl GaiaNodeClient {
         n: u32,
         size: ImageSize,
     ) -> Result<ImageResponse, APIError> {
-        let client = Client::new_with_base(&self.base_url, self.api_key.clone());
-
         let image_path = download_or_verify_file(&image_path, "image").await?;
         let mask_path = if let Some(mask) = mask_path {
             Some(download_or_verify_file(&mask, "mask").await?)
@@ -167,10 +627,273 @@ impl GaiaNodeClient {
 
         let parameters = parameters_builder.build()?;
 
-        let result = client.images().edit(parameters).await?;
+        self.edit_images(parameters).await
+    }
+
+    /// Embeds every input with the node's embedding model.
+    ///
+    /// Vectors are always requested as floats and encoded here, so base64
+    /// output works even with nodes that only produce floats.
+    pub async fn embeddings(
+        &self,
+        input: Vec<String>,
+        options: &EmbeddingOptions,
+    ) -> Result<EmbeddingsResponse, APIError> {
+        options.validate()?;
+        if input.is_empty() {
+            return Err(APIError::InvalidParameters(
+                "input must not be empty".to_string(),
+            ));
+        }
+        if input.len() > MAX_EMBEDDING_INPUTS {
+            return Err(APIError::InvalidParameters(format!(
+                "At most {} inputs are allowed, got {}",
+                MAX_EMBEDDING_INPUTS,
+                input.len()
+            )));
+        }
+        if input.iter().any(|text| text.is_empty()) {
+            return Err(APIError::InvalidParameters(
+                "input must not contain empty strings".to_string(),
+            ));
+        }
+
+        let inputs = input.len();
+        let parameters = EmbeddingParameters {
+            input: EmbeddingInput::StringArray(input),
+            model: options
+                .model
+                .clone()
+                .unwrap_or_else(|| self.default_model(ModelKind::Embedding)),
+            encoding_format: Some(EmbeddingEncodingFormat::Float),
+            dimensions: options.dimensions,
+            user: None,
+        };
+        let response = self.create_embeddings(parameters).await?;
+
+        let mut vectors = response
+            .data
+            .into_iter()
+            .map(|embedding| {
+                let values = match embedding.embedding {
+                    EmbeddingOutput::Float(values) => {
+                        values.into_iter().map(|value| value as f32).collect()
+                    }
+                    EmbeddingOutput::Base64(encoded) => decode_embedding(&encoded)?,
+                };
+                Ok((embedding.index, values))
+            })
+            .collect::<Result<Vec<(u32, Vec<f32>)>, APIError>>()?;
+        vectors.sort_by_key(|(index, _)| *index);
+
+        if vectors.len() != inputs {
+            return Err(APIError::UnexpectedResponse(format!(
+                "expected {} embeddings, got {}",
+                inputs,
+                vectors.len()
+            )));
+        }
+        let dimensions = vectors.first().map_or(0, |(_, values)| values.len());
+        if vectors.iter().any(|(_, values)| values.len() != dimensions) {
+            return Err(APIError::UnexpectedResponse(
+                "embeddings have different dimensions".to_string(),
+            ));
+        }
+
+        Ok(EmbeddingsResponse {
+            model: response.model,
+            dimensions,
+            data: vectors
+                .into_iter()
+                .map(|(index, values)| EmbeddingVector {
+                    index,
+                    embedding: match options.encoding_format {
+                        EmbeddingEncoding::Float => EmbeddingValue::Float(values),
+                        EmbeddingEncoding::Base64 => {
+                            EmbeddingValue::Base64(encode_embedding(&values))
+                        }
+                    },
+                })
+                .collect(),
+            usage: response.usage,
+        })
+    }
+
+    /// Sends fully specified chat completion parameters to the upstream node.
+    pub async fn chat_completion(
+        &self,
+        parameters: ChatCompletionParameters,
+    ) -> Result<ChatCompletionResponse, APIError> {
+        let pool = self.pool_for(&parameters.model)?;
+        let _permit = self.acquire().await;
+        // Not retried: a node that timed out may still be generating the reply.
+        let result = self
+            .upstream(&pool, false, |client| {
+                let parameters = parameters.clone();
+                async move { client.chat().create(parameters).await }
+            })
+            .await?;
+
+        Ok(result)
+    }
+
+    /// Streaming counterpart of [`GaiaNodeClient::chat_completion`].
+    ///
+    /// Like chat completions, opening the stream is not retried on another
+    /// node, and once deltas have been sent a failure ends the stream.
+    pub async fn chat_completion_stream(
+        &self,
+        parameters: ChatCompletionParameters,
+    ) -> Result<ChatCompletionStream, APIError> {
+        let pool = self.pool_for(&parameters.model)?;
+        let permit = self.acquire().await;
+        let (stream, guard) = self
+            .dispatch(&pool, false, |client| {
+                let parameters = parameters.clone();
+                async move { client.chat().create_stream(parameters).await }
+            })
+            .await?;
+
+        // The slot and the node's in-flight count stay taken until the caller
+        // drops the stream.
+        Ok(Box::pin(stream.inspect(move |_| {
+            let _ = (&permit, &guard);
+        })))
+    }
+
+    /// Sends fully specified image generation parameters to the upstream node.
+    pub async fn generate_images(
+        &self,
+        mut parameters: CreateImageParameters,
+    ) -> Result<ImageResponse, APIError> {
+        let model = parameters
+            .model
+            .get_or_insert_with(|| self.default_model(ModelKind::Image));
+        let pool = self.pool_for(model)?;
+        let _permit = self.acquire().await;
+        let result = self
+            .upstream(&pool, false, |client| {
+                let parameters = parameters.clone();
+                async move { client.images().create(parameters).await }
+            })
+            .await?;
+
+        Ok(result)
+    }
+
+    /// Sends fully specified image edit parameters to the upstream node.
+    pub async fn edit_images(
+        &self,
+        mut parameters: EditImageParameters,
+    ) -> Result<ImageResponse, APIError> {
+        let model = parameters
+            .model
+            .get_or_insert_with(|| self.default_model(ModelKind::Image));
+        let pool = self.pool_for(model)?;
+        let _permit = self.acquire().await;
+        let result = self
+            .upstream(&pool, false, |client| {
+                let parameters = parameters.clone();
+                async move { client.images().edit(parameters).await }
+            })
+            .await?;
+
+        Ok(result)
+    }
+
+    /// Requests embeddings from the node's embedding model.
+    pub async fn create_embeddings(
+        &self,
+        parameters: EmbeddingParameters,
+    ) -> Result<EmbeddingResponse, APIError> {
+        let pool = self.pool_for(&parameters.model)?;
+        let _permit = self.acquire().await;
+        let result = self
+            .upstream(&pool, true, |client| {
+                let parameters = parameters.clone();
+                async move { client.embeddings().create(parameters).await }
+            })
+            .await?;
 
         Ok(result)
     }
+
+    /// Lists the models served across every pool.
+    ///
+    /// Pools that cannot be reached are left out; the request only fails when
+    /// none of them answers. Models that would be rejected by routing are
+    /// not listed.
+    pub async fn list_models(&self) -> Result<ListModelResponse, APIError> {
+        let _permit = self.acquire().await;
+        let pools = std::iter::once(self.pool.clone())
+            .chain(self.routes.iter().map(|route| route.pool.clone()))
+            .collect::<Vec<_>>();
+        let responses = futures::future::join_all(pools.iter().map(|pool| {
+            self.upstream(
+                pool,
+                true,
+                |client| async move { client.models().list().await },
+            )
+        }))
+        .await;
+
+        let mut merged: Option<ListModelResponse> = None;
+        let mut last_error = None;
+        for response in responses {
+            match response {
+                Ok(response) => match &mut merged {
+                    Some(merged) => {
+                        for model in response.data {
+                            if !merged.data.iter().any(|known| known.id == model.id) {
+                                merged.data.push(model);
+                            }
+                        }
+                    }
+                    None => merged = Some(response),
+                },
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        let mut merged = match (merged, last_error) {
+            (Some(merged), _) => merged,
+            (None, Some(e)) => return Err(e),
+            (None, None) => return Err(APIError::NoUpstreamAvailable),
+        };
+        merged.data.retain(|model| self.pool_for(&model.id).is_ok());
+        Ok(merged)
+    }
+}
+
+/// A model with its own pool of nodes.
+struct RoutedModel {
+    model: String,
+    kind: ModelKind,
+    pool: Arc<UpstreamPool>,
+}
+
+/// Encodes a vector as base64 little-endian `f32`s, the OpenAI wire format.
+fn encode_embedding(values: &[f32]) -> String {
+    let bytes: Vec<u8> = values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect();
+    BASE64.encode(bytes)
+}
+
+fn decode_embedding(encoded: &str) -> Result<Vec<f32>, APIError> {
+    let bytes = BASE64
+        .decode(encoded)
+        .map_err(|e| APIError::UnexpectedResponse(format!("invalid base64 embedding: {}", e)))?;
+    if bytes.len() % 4 != 0 {
+        return Err(APIError::UnexpectedResponse(
+            "base64 embedding is not a sequence of f32 values".to_string(),
+        ));
+    }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect())
 }
 
 pub async fn download_or_verify_file(path: &str, prefix: &str) -> Result<String, APIError> {
@@ -204,3 +927,36 @@ pub async fn download_or_verify_file(path: &str, prefix: &str) -> Result<String,
         Ok(path.to_string_lossy().into_owned())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embeddings_are_little_endian_f32_in_base64() {
+        let values = [1.0, -2.5, 0.0];
+        let encoded = encode_embedding(&values);
+        assert_eq!(encoded, "AACAPwAAIMAAAAAA");
+        assert_eq!(decode_embedding(&encoded).unwrap(), values);
+
+        let values = [f32::MIN_POSITIVE, f32::MAX, -0.1, 1e-7];
+        assert_eq!(
+            decode_embedding(&encode_embedding(&values)).unwrap(),
+            values
+        );
+        assert!(decode_embedding(&encode_embedding(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_or_invalid_embeddings_are_rejected() {
+        // Three bytes cannot hold an f32.
+        assert!(matches!(
+            decode_embedding("AACA"),
+            Err(APIError::UnexpectedResponse(_))
+        ));
+        assert!(matches!(
+            decode_embedding("not base64!"),
+            Err(APIError::UnexpectedResponse(_))
+        ));
+    }
+}
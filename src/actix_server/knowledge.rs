@@ -0,0 +1,782 @@
+use actix_web::{http::StatusCode, web, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+
+use super::{
+    error::{self, error_response},
+    gaia_client::{APIError, GaiaNodeClient},
+    server::AppState,
+    types::{EmbeddingOptions, EmbeddingValue},
+};
+
+/// A Gaia node's Qdrant instance listens on port 6333 of the same machine.
+pub const DEFAULT_QDRANT_URL: &str = "http://127.0.0.1:6333";
+/// Collection the node's RAG server searches.
+pub const DEFAULT_COLLECTION: &str = "default";
+/// Characters per chunk when not configured.
+pub const DEFAULT_CHUNK_SIZE: usize = 1000;
+/// Characters shared by consecutive chunks when not configured.
+pub const DEFAULT_CHUNK_OVERLAP: usize = 200;
+
+/// Chunks embedded and upserted per round-trip.
+const INGEST_BATCH_SIZE: usize = 32;
+/// Largest document body accepted by the upload endpoint.
+const MAX_DOCUMENT_BYTES: usize = 10 * 1024 * 1024;
+/// Most documents returned by one list call.
+const MAX_LIST_LIMIT: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KnowledgeConfig {
+    pub qdrant_url: String,
+    pub qdrant_api_key: Option<String>,
+    pub collection: String,
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+}
+
+/// How an uploaded document's text is laid out.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentFormat {
+    #[default]
+    Text,
+    /// Chunks never span two sections.
+    Markdown,
+    /// Text extracted from a PDF; hard line wraps and hyphenation are undone
+    /// before chunking.
+    PdfText,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IngestRequest {
+    /// Replaces the document with this id if it already exists.
+    pub document_id: Option<String>,
+    pub title: Option<String>,
+    pub content: String,
+    #[serde(default)]
+    pub format: DocumentFormat,
+    pub chunk_size: Option<usize>,
+    pub chunk_overlap: Option<usize>,
+    /// Embedding model; defaults to the routed embedding model.
+    pub model: Option<String>,
+    /// Free-form metadata stored alongside every chunk.
+    pub metadata: Option<Value>,
+}
+
+/// A document in the knowledge base, as stored on its first chunk.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DocumentInfo {
+    pub document_id: String,
+    pub title: Option<String>,
+    pub format: DocumentFormat,
+    pub chunk_count: usize,
+    pub created_at: u64,
+    pub metadata: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DocumentList {
+    pub documents: Vec<DocumentInfo>,
+    /// Pass as `offset` to fetch the next page; `None` on the last page.
+    pub next_offset: Option<Value>,
+}
+
+/// A chunk returned by a similarity search.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchHit {
+    pub document_id: String,
+    pub title: Option<String>,
+    pub text: String,
+    pub score: f32,
+    pub metadata: Option<Value>,
+}
+
+/// Chunk payload in the layout the Gaia RAG server reads: the text is kept
+/// under `source`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ChunkPayload {
+    source: String,
+    document_id: String,
+    chunk_index: usize,
+    title: Option<String>,
+    format: DocumentFormat,
+    chunk_count: usize,
+    created_at: u64,
+    metadata: Option<Value>,
+    /// Tells the chunks of one ingest apart from those of an earlier version
+    /// of the same document; absent on chunks written before it existed.
+    #[serde(default)]
+    ingest_id: Option<String>,
+}
+
+/// Thin client for the Qdrant REST API, scoped to one collection.
+pub struct KnowledgeBase {
+    http_client: reqwest::Client,
+    config: KnowledgeConfig,
+}
+
+impl KnowledgeBase {
+    pub fn new(config: KnowledgeConfig) -> Self {
+        Self {
+            http_client: reqwest::Client::new(),
+            config,
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let url = format!(
+            "{}/collections/{}{}",
+            self.config.qdrant_url.trim_end_matches('/'),
+            self.config.collection,
+            path
+        );
+        let builder = self.http_client.request(method, url);
+        match &self.config.qdrant_api_key {
+            Some(api_key) => builder.header("api-key", api_key),
+            None => builder,
+        }
+    }
+
+    /// Sends a request and returns Qdrant's `result` field.
+    async fn send(&self, builder: reqwest::RequestBuilder) -> Result<Value, APIError> {
+        self.send_response(builder.send().await?).await
+    }
+
+    async fn send_response(&self, response: reqwest::Response) -> Result<Value, APIError> {
+        let status = response.status();
+        let body: Value = response.json().await.unwrap_or(Value::Null);
+        if !status.is_success() {
+            return Err(APIError::VectorStoreError(format!(
+                "Qdrant returned {}: {}",
+                status,
+                body["status"]["error"].as_str().unwrap_or("unknown error")
+            )));
+        }
+        Ok(body["result"].clone())
+    }
+
+    /// The collection's description, or `None` if it does not exist yet.
+    async fn collection(&self) -> Result<Option<Value>, APIError> {
+        let response = self.request(reqwest::Method::GET, "").send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        self.send_response(response).await.map(Some)
+    }
+
+    /// Creates the collection for `dimensions`-sized vectors if it does not
+    /// exist, or checks that the existing one matches.
+    async fn ensure_collection(&self, dimensions: usize) -> Result<(), APIError> {
+        let Some(collection) = self.collection().await? else {
+            self.send(self.request(reqwest::Method::PUT, "").json(&json!({
+                "vectors": {"size": dimensions, "distance": "Cosine"}
+            })))
+            .await?;
+            return Ok(());
+        };
+
+        let size = collection["config"]["params"]["vectors"]["size"].as_u64();
+        match size {
+            Some(size) if size as usize != dimensions => Err(APIError::InvalidRequest(format!(
+                "Collection {} stores {}-dimensional vectors but the embedding model produces {}",
+                self.config.collection, size, dimensions
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Chunks, embeds and upserts a document, replacing any previous version.
+    ///
+    /// The new chunks are written first and the previous version is removed
+    /// only once they all are, so a failed ingest leaves it in place.
+    pub async fn ingest(
+        &self,
+        gaia_client: &GaiaNodeClient,
+        request: IngestRequest,
+    ) -> Result<DocumentInfo, APIError> {
+        let chunk_size = request.chunk_size.unwrap_or(self.config.chunk_size);
+        let chunk_overlap = request.chunk_overlap.unwrap_or(self.config.chunk_overlap);
+        check_chunking(chunk_size, chunk_overlap)?;
+
+        let chunks = chunk_text(&request.content, request.format, chunk_size, chunk_overlap);
+        if chunks.is_empty() {
+            return Err(APIError::InvalidParameters(
+                "content must not be empty".to_string(),
+            ));
+        }
+
+        let replaces = request.document_id.is_some();
+        let document_id = request
+            .document_id
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        let ingest_id = uuid::Uuid::new_v4().to_string();
+        let info = DocumentInfo {
+            document_id,
+            title: request.title,
+            format: request.format,
+            chunk_count: chunks.len(),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            metadata: request.metadata,
+        };
+        let options = EmbeddingOptions {
+            model: request.model,
+            ..Default::default()
+        };
+
+        if let Err(e) = self
+            .upsert_chunks(gaia_client, &chunks, &info, &ingest_id, &options)
+            .await
+        {
+            // Whatever part of this version made it in is removed again.
+            let partial = json!({
+                "must": [{"key": "ingest_id", "match": {"value": ingest_id}}]
+            });
+            let _ = self.delete_matching(partial).await;
+            return Err(e);
+        }
+
+        if replaces {
+            self.delete_matching(json!({
+                "must": [{"key": "document_id", "match": {"value": info.document_id}}],
+                "must_not": [{"key": "ingest_id", "match": {"value": ingest_id}}]
+            }))
+            .await?;
+        }
+
+        Ok(info)
+    }
+
+    /// Embeds `chunks` in batches and upserts them as the chunks of `info`.
+    async fn upsert_chunks(
+        &self,
+        gaia_client: &GaiaNodeClient,
+        chunks: &[String],
+        info: &DocumentInfo,
+        ingest_id: &str,
+        options: &EmbeddingOptions,
+    ) -> Result<(), APIError> {
+        for (batch_index, batch) in chunks.chunks(INGEST_BATCH_SIZE).enumerate() {
+            let embeddings = gaia_client.embeddings(batch.to_vec(), options).await?;
+            if batch_index == 0 {
+                self.ensure_collection(embeddings.dimensions).await?;
+            }
+
+            let points = batch
+                .iter()
+                .zip(embeddings.data)
+                .enumerate()
+                .map(|(i, (chunk, embedding))| {
+                    let EmbeddingValue::Float(vector) = embedding.embedding else {
+                        return Err(APIError::UnexpectedResponse(
+                            "the node returned base64 embeddings instead of floats".to_string(),
+                        ));
+                    };
+                    Ok(json!({
+                        "id": uuid::Uuid::new_v4().to_string(),
+                        "vector": vector,
+                        "payload": ChunkPayload {
+                            source: chunk.clone(),
+                            document_id: info.document_id.clone(),
+                            chunk_index: batch_index * INGEST_BATCH_SIZE + i,
+                            title: info.title.clone(),
+                            format: info.format,
+                            chunk_count: info.chunk_count,
+                            created_at: info.created_at,
+                            metadata: info.metadata.clone(),
+                            ingest_id: Some(ingest_id.to_string()),
+                        },
+                    }))
+                })
+                .collect::<Result<Vec<_>, APIError>>()?;
+            self.send(
+                self.request(reqwest::Method::PUT, "/points?wait=true")
+                    .json(&json!({ "points": points })),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Removes every chunk of a document, returning how many were removed.
+    pub async fn delete_document(&self, document_id: &str) -> Result<u64, APIError> {
+        self.delete_matching(json!({
+            "must": [{"key": "document_id", "match": {"value": document_id}}]
+        }))
+        .await
+    }
+
+    /// Removes the chunks matching a Qdrant `filter`, returning how many were
+    /// removed.
+    async fn delete_matching(&self, filter: Value) -> Result<u64, APIError> {
+        // Nothing to delete before the first document creates the collection.
+        if self.collection().await?.is_none() {
+            return Ok(0);
+        }
+
+        let count = self
+            .send(
+                self.request(reqwest::Method::POST, "/points/count")
+                    .json(&json!({"filter": filter, "exact": true})),
+            )
+            .await?["count"]
+            .as_u64()
+            .unwrap_or(0);
+        if count > 0 {
+            self.send(
+                self.request(reqwest::Method::POST, "/points/delete?wait=true")
+                    .json(&json!({ "filter": filter })),
+            )
+            .await?;
+        }
+        Ok(count)
+    }
+
+    /// Lists documents by scrolling over their first chunks.
+    pub async fn list_documents(
+        &self,
+        limit: usize,
+        offset: Option<Value>,
+    ) -> Result<DocumentList, APIError> {
+        if self.collection().await?.is_none() {
+            return Ok(DocumentList {
+                documents: Vec::new(),
+                next_offset: None,
+            });
+        }
+
+        let result = self
+            .send(
+                self.request(reqwest::Method::POST, "/points/scroll")
+                    .json(&json!({
+                        "filter": {"must": [{"key": "chunk_index", "match": {"value": 0}}]},
+                        "limit": limit.clamp(1, MAX_LIST_LIMIT),
+                        "offset": offset,
+                        "with_payload": true,
+                        "with_vector": false,
+                    })),
+            )
+            .await?;
+
+        let documents = result["points"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|point| {
+                serde_json::from_value::<ChunkPayload>(point["payload"].clone()).ok()
+            })
+            .map(|payload| DocumentInfo {
+                document_id: payload.document_id,
+                title: payload.title,
+                format: payload.format,
+                chunk_count: payload.chunk_count,
+                created_at: payload.created_at,
+                metadata: payload.metadata,
+            })
+            .collect();
+        let next_offset = Some(result["next_page_offset"].clone()).filter(|o| !o.is_null());
+
+        Ok(DocumentList {
+            documents,
+            next_offset,
+        })
+    }
+
+    /// Returns the `limit` chunks most similar to `query`, best first,
+    /// optionally restricted by a Qdrant `filter`.
+    ///
+    /// An empty or missing collection yields no hits.
+    pub async fn search(
+        &self,
+        gaia_client: &GaiaNodeClient,
+        query: &str,
+        limit: usize,
+        filter: Option<Value>,
+        model: Option<String>,
+    ) -> Result<Vec<SearchHit>, APIError> {
+        if self.collection().await?.is_none() {
+            return Ok(Vec::new());
+        }
+
+        let options = EmbeddingOptions {
+            model,
+            ..Default::default()
+        };
+        let embeddings = gaia_client
+            .embeddings(vec![query.to_string()], &options)
+            .await?;
+        let Some(EmbeddingValue::Float(vector)) = embeddings
+            .data
+            .into_iter()
+            .next()
+            .map(|embedding| embedding.embedding)
+        else {
+            return Err(APIError::UnexpectedResponse(
+                "no embedding returned for the query".to_string(),
+            ));
+        };
+
+        let result = self
+            .send(
+                self.request(reqwest::Method::POST, "/points/search")
+                    .json(&json!({
+                        "vector": vector,
+                        "filter": filter,
+                        "limit": limit.clamp(1, MAX_LIST_LIMIT),
+                        "with_payload": true,
+                    })),
+            )
+            .await?;
+
+        Ok(result
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|point| {
+                let payload =
+                    serde_json::from_value::<ChunkPayload>(point["payload"].clone()).ok()?;
+                Some(SearchHit {
+                    document_id: payload.document_id,
+                    title: payload.title,
+                    text: payload.source,
+                    score: point["score"].as_f64().unwrap_or_default() as f32,
+                    metadata: payload.metadata,
+                })
+            })
+            .collect())
+    }
+
+    /// Takes a snapshot of the collection and streams it to `path`.
+    ///
+    /// Returns the snapshot's size in bytes and its hex SHA-256 checksum.
+    pub async fn save_snapshot(&self, path: &Path) -> Result<(u64, String), APIError> {
+        let result = self
+            .send(self.request(reqwest::Method::POST, "/snapshots?wait=true"))
+            .await?;
+        let name = result["name"]
+            .as_str()
+            .ok_or_else(|| {
+                APIError::VectorStoreError("Qdrant did not return a snapshot name".to_string())
+            })?
+            .to_string();
+
+        let snapshot_path = format!("/snapshots/{}", name);
+        let mut response = self
+            .request(reqwest::Method::GET, &snapshot_path)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(APIError::VectorStoreError(format!(
+                "Failed to download snapshot {}: {}",
+                name,
+                response.status()
+            )));
+        }
+
+        let io_error = |e: std::io::Error| APIError::IOError(e.to_string());
+        let mut file = tokio::fs::File::create(path).await.map_err(io_error)?;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        while let Some(chunk) = response.chunk().await? {
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+            file.write_all(&chunk).await.map_err(io_error)?;
+        }
+        file.flush().await.map_err(io_error)?;
+
+        // The copy kept by Qdrant is no longer needed once it is on disk.
+        let _ = self
+            .send(self.request(reqwest::Method::DELETE, &snapshot_path))
+            .await;
+
+        let checksum = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Ok((size, checksum))
+    }
+
+    /// Drops the whole collection.
+    pub async fn delete_collection(&self) -> Result<(), APIError> {
+        self.send(self.request(reqwest::Method::DELETE, "")).await?;
+        Ok(())
+    }
+}
+
+/// Rejects chunk sizes [`chunk_text`] cannot make progress with.
+fn check_chunking(size: usize, overlap: usize) -> Result<(), APIError> {
+    if size == 0 {
+        return Err(APIError::InvalidParameters(
+            "chunk_size must be greater than 0".to_string(),
+        ));
+    }
+    if overlap >= size {
+        return Err(APIError::InvalidParameters(format!(
+            "chunk_overlap must be smaller than chunk_size ({})",
+            size
+        )));
+    }
+    Ok(())
+}
+
+/// Splits `text` into chunks of at most `size` characters, each sharing up to
+/// `overlap` characters with the previous one.
+///
+/// Chunks end at a paragraph break, sentence end or whitespace when one falls
+/// in the second half of the window.
+pub fn chunk_text(text: &str, format: DocumentFormat, size: usize, overlap: usize) -> Vec<String> {
+    let text = match format {
+        DocumentFormat::PdfText => normalize_pdf_text(text),
+        _ => text.to_string(),
+    };
+    let sections = match format {
+        DocumentFormat::Markdown => markdown_sections(&text),
+        _ => vec![text.as_str()],
+    };
+
+    sections
+        .into_iter()
+        .flat_map(|section| split_with_overlap(section, size, overlap))
+        .collect()
+}
+
+fn split_with_overlap(text: &str, size: usize, overlap: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < chars.len() {
+        let mut end = (start + size).min(chars.len());
+        if end < chars.len() {
+            if let Some(at) = break_point(&chars[start..end]) {
+                end = start + at;
+            }
+        }
+
+        let chunk: String = chars[start..end].iter().collect();
+        let chunk = chunk.trim();
+        if !chunk.is_empty() {
+            chunks.push(chunk.to_string());
+        }
+        if end == chars.len() {
+            break;
+        }
+        start = end.saturating_sub(overlap).max(start + 1);
+    }
+
+    chunks
+}
+
+/// Best place to end a chunk within `window`, searching its second half.
+fn break_point(window: &[char]) -> Option<usize> {
+    let earliest = (window.len() / 2).max(2);
+    let candidates = (earliest..window.len()).rev();
+
+    let paragraph = |i: usize| window[i - 1] == '\n' && window[i - 2] == '\n';
+    let sentence = |i: usize| matches!(window[i - 1], '.' | '!' | '?') && window[i].is_whitespace();
+    let whitespace = |i: usize| window[i].is_whitespace();
+
+    candidates
+        .clone()
+        .find(|&i| paragraph(i))
+        .or_else(|| candidates.clone().find(|&i| sentence(i)))
+        .or_else(|| candidates.clone().find(|&i| whitespace(i)))
+}
+
+/// Splits markdown before every heading, leaving code blocks intact.
+fn markdown_sections(text: &str) -> Vec<&str> {
+    let mut sections = Vec::new();
+    let mut section_start = 0;
+    let mut in_code_block = false;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            in_code_block = !in_code_block;
+        } else if !in_code_block && trimmed.starts_with('#') && offset > section_start {
+            sections.push(&text[section_start..offset]);
+            section_start = offset;
+        }
+        offset += line.len();
+    }
+    sections.push(&text[section_start..]);
+    sections
+}
+
+/// Rejoins hard-wrapped lines and hyphenated words, keeping paragraph breaks.
+fn normalize_pdf_text(text: &str) -> String {
+    text.replace("\r\n", "\n")
+        .split("\n\n")
+        .map(|paragraph| {
+            paragraph
+                .replace("-\n", "")
+                .split('\n')
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Registers the knowledge-base endpoints under `/knowledge`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/knowledge")
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(MAX_DOCUMENT_BYTES)
+                    .error_handler(error::json_error_handler),
+            )
+            .route("/documents", web::get().to(list_documents))
+            .route("/documents", web::post().to(upload_document))
+            .route("/documents/{id}", web::delete().to(delete_document)),
+    );
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    limit: Option<usize>,
+    /// Point id returned as `next_offset` by the previous page.
+    offset: Option<String>,
+}
+
+async fn upload_document(
+    app_state: web::Data<AppState>,
+    request: web::Json<IngestRequest>,
+) -> HttpResponse {
+    match app_state
+        .knowledge
+        .ingest(&app_state.gaia_client, request.into_inner())
+        .await
+    {
+        Ok(info) => HttpResponse::Created().json(info),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn list_documents(
+    app_state: web::Data<AppState>,
+    query: web::Query<ListQuery>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    // Qdrant offsets are point ids, either unsigned integers or UUIDs.
+    let offset = query.offset.map(|offset| match offset.parse::<u64>() {
+        Ok(id) => json!(id),
+        Err(_) => json!(offset),
+    });
+    match app_state
+        .knowledge
+        .list_documents(query.limit.unwrap_or(100), offset)
+        .await
+    {
+        Ok(list) => HttpResponse::Ok().json(list),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn delete_document(app_state: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+    match app_state.knowledge.delete_document(&id).await {
+        Ok(0) => error_response(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            "document_not_found",
+            format!("No document with id {}", id),
+        ),
+        Ok(chunks) => HttpResponse::Ok().json(json!({
+            "document_id": id.into_inner(),
+            "deleted_chunks": chunks,
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(text: &str, size: usize, overlap: usize) -> Vec<String> {
+        chunk_text(text, DocumentFormat::Text, size, overlap)
+    }
+
+    #[test]
+    fn chunks_stay_within_the_size() {
+        let text = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. ".repeat(20);
+        let chunks = chunks(&text, 50, 10);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 50));
+    }
+
+    #[test]
+    fn consecutive_chunks_overlap() {
+        let text = "abcdefghijklmnopqrstuvwxyz".repeat(2);
+        let chunks = chunks(&text, 10, 3);
+        assert_eq!(chunks[0], "abcdefghij");
+        assert_eq!(chunks[1], "hijklmnopq");
+        for pair in chunks.windows(2) {
+            assert!(pair[1].starts_with(&pair[0][pair[0].len() - 3..]));
+        }
+        assert!(chunks.last().unwrap().ends_with("xyz"));
+    }
+
+    #[test]
+    fn chunks_end_at_paragraphs_then_sentences() {
+        assert_eq!(
+            chunks(
+                "First paragraph here.\n\nSecond paragraph is longer.",
+                30,
+                0
+            ),
+            ["First paragraph here.", "Second paragraph is longer."]
+        );
+        assert_eq!(
+            chunks("One two three. Four five six seven", 20, 0),
+            ["One two three.", "Four five six seven"]
+        );
+    }
+
+    #[test]
+    fn markdown_is_split_at_headings_outside_code() {
+        let text = "# A\nintro\n```\n# not a heading\n```\n## B\nbody\n";
+        assert_eq!(
+            chunk_text(text, DocumentFormat::Markdown, 1000, 0),
+            ["# A\nintro\n```\n# not a heading\n```", "## B\nbody"]
+        );
+    }
+
+    #[test]
+    fn multibyte_text_is_split_by_characters() {
+        let text = "héllo wörld ünïcode ✓✓✓ ñandú ĳssel";
+        let chunks = chunks(text, 8, 2);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 8));
+        assert!(chunks.iter().all(|chunk| text.contains(chunk.as_str())));
+        assert_eq!(chunks[0], "héllo");
+        assert!(chunks.last().unwrap().ends_with("ĳssel"));
+    }
+
+    #[test]
+    fn pdf_text_is_unwrapped() {
+        assert_eq!(
+            normalize_pdf_text("Hyphen-\nated words\nwrap here.\r\n\r\nNew para."),
+            "Hyphenated words wrap here.\n\nNew para."
+        );
+    }
+
+    #[test]
+    fn overlap_must_be_smaller_than_the_chunk() {
+        assert!(check_chunking(100, 20).is_ok());
+        assert!(check_chunking(100, 100).is_err());
+        assert!(check_chunking(100, 200).is_err());
+        assert!(check_chunking(0, 0).is_err());
+    }
+}
@@ -1,3 +1,18 @@
+pub mod agent;
+pub mod auth;
+pub mod config;
+pub mod context;
+pub mod error;
 pub mod gaia_client;
+pub mod knowledge;
+pub mod net;
+pub mod openai;
+pub mod personas;
+pub mod queue;
+pub mod rate_limit;
 pub mod server;
+pub mod sessions;
+pub mod sse;
+pub mod tools;
 pub mod types;
+pub mod upstream;
@@ -0,0 +1,125 @@
+use color_eyre::{eyre::eyre, Result};
+use std::net::{IpAddr, SocketAddr};
+use url::{Host, Url};
+
+/// Whether `ip` is a routable public address.
+///
+/// Loopback, private, link-local, carrier-grade NAT, documentation, multicast
+/// and unspecified addresses are not, so user-supplied URLs cannot reach the
+/// node, its Qdrant instance or the cloud metadata service.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Resolves the host of an `http(s)` URL, failing unless every address it
+/// resolves to is public.
+pub async fn resolve_public(url: &Url) -> Result<Vec<SocketAddr>> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(eyre!("Unsupported URL scheme {}", url.scheme()));
+    }
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| eyre!("{} has no port", url))?;
+    let addrs: Vec<SocketAddr> = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        Some(Host::Ipv6(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        Some(Host::Domain(domain)) => tokio::net::lookup_host((domain, port))
+            .await
+            .map_err(|e| eyre!("Failed to resolve {}: {}", domain, e))?
+            .collect(),
+        None => return Err(eyre!("{} has no host", url)),
+    };
+    if addrs.is_empty() {
+        return Err(eyre!("{} did not resolve to any address", url));
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        return Err(eyre!(
+            "{} resolves to non-public address {}",
+            url,
+            addr.ip()
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Builds a client that only connects to the public addresses `url` resolves
+/// to right now, so a second DNS lookup cannot point it elsewhere.
+///
+/// Redirects are disabled since their targets would skip the check.
+pub async fn public_client(url: &Url, builder: reqwest::ClientBuilder) -> Result<reqwest::Client> {
+    let addrs = resolve_public(url).await?;
+    let builder = builder.redirect(reqwest::redirect::Policy::none());
+    let builder = match url.host() {
+        Some(Host::Domain(domain)) => builder.resolve_to_addrs(domain, &addrs),
+        _ => builder,
+    };
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_and_local_addresses_are_not_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{} is not public", ip);
+        }
+    }
+
+    #[test]
+    fn routable_addresses_are_public() {
+        for ip in ["1.1.1.1", "8.8.8.8", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{} is public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn literal_private_hosts_are_rejected() {
+        for url in [
+            "http://127.0.0.1:8080/v1",
+            "http://[::1]/",
+            "http://169.254.169.254/latest/meta-data",
+            "file:///etc/passwd",
+        ] {
+            assert!(resolve_public(&Url::parse(url).unwrap()).await.is_err());
+        }
+    }
+}
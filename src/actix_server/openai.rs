@@ -0,0 +1,272 @@
+use actix_multipart::Multipart;
+use actix_web::{
+    http::header::TryIntoHeaderPair, web, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use futures::StreamExt;
+use openai_dive::v1::resources::{
+    chat::ChatCompletionParameters,
+    embedding::EmbeddingParameters,
+    image::{CreateImageParameters, EditImageParameters},
+    shared::FileUpload,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{
+    gaia_client::APIError,
+    rate_limit::{json_with_usage, ReportsUsage, UsageMeter},
+    server::{AppState, CONTEXT_REPORT_HEADER},
+    sse,
+    types::ChatOptions,
+};
+
+/// Registers the OpenAI-compatible `/v1` routes so stock SDKs can use the
+/// server as their base URL.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/v1")
+            .route("/chat/completions", web::post().to(chat_completions))
+            .route("/images/generations", web::post().to(image_generations))
+            .route("/images/edits", web::post().to(image_edits))
+            .route("/embeddings", web::post().to(embeddings))
+            .route("/models", web::get().to(models)),
+    );
+}
+
+fn openai_response<R: Serialize + ReportsUsage>(result: Result<R, APIError>) -> HttpResponse {
+    match result {
+        Ok(response) => json_with_usage(&response),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn chat_completions(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+    parameters: web::Json<ChatCompletionParameters>,
+) -> HttpResponse {
+    let mut parameters = parameters.into_inner();
+    let client = &app_state.gaia_client;
+    let meter = req.extensions().get::<UsageMeter>().cloned();
+
+    // Fitted with the configured strategy, since the OpenAI schema has no field for it.
+    let options = ChatOptions {
+        model: Some(parameters.model.clone()),
+        max_tokens: parameters.max_tokens,
+        ..Default::default()
+    };
+    let messages = std::mem::take(&mut parameters.messages);
+    let context = match client.fit_context(messages, &options, None).await {
+        Ok((messages, context)) => {
+            parameters.messages = messages;
+            context
+        }
+        Err(e) => return e.error_response(),
+    };
+    let summary_tokens = context.as_ref().map_or(0, |c| c.summary_tokens);
+    let report = context.and_then(|c| serde_json::to_string(&c).ok());
+
+    let mut response = if parameters.stream == Some(true) {
+        let prompt_tokens = client.estimate_tokens(&parameters.messages);
+        match client.chat_completion_stream(parameters).await {
+            Ok(upstream) => {
+                if let Some(meter) = meter.as_ref().filter(|_| summary_tokens > 0) {
+                    meter.record(summary_tokens);
+                }
+                HttpResponse::Ok()
+                    .content_type("text/event-stream")
+                    .insert_header(("Cache-Control", "no-cache"))
+                    .streaming(sse::chat_completion_events(upstream, meter, prompt_tokens))
+            }
+            Err(e) => return e.error_response(),
+        }
+    } else {
+        match client.chat_completion(parameters).await {
+            Ok(mut completion) => {
+                if let Some(usage) = completion.usage.as_mut() {
+                    usage.total_tokens += summary_tokens;
+                }
+                json_with_usage(&completion)
+            }
+            Err(e) => return e.error_response(),
+        }
+    };
+    if let Some(Ok((name, value))) =
+        report.map(|report| (CONTEXT_REPORT_HEADER, report).try_into_pair())
+    {
+        response.headers_mut().insert(name, value);
+    }
+    response
+}
+
+async fn image_generations(
+    app_state: web::Data<AppState>,
+    parameters: web::Json<CreateImageParameters>,
+) -> HttpResponse {
+    openai_response(
+        app_state
+            .gaia_client
+            .generate_images(parameters.into_inner())
+            .await,
+    )
+}
+
+async fn image_edits(app_state: web::Data<AppState>, payload: Multipart) -> HttpResponse {
+    let mut uploads = Vec::new();
+    let result = async {
+        let parameters = read_edit_form(payload, &mut uploads).await?;
+        app_state.gaia_client.edit_images(parameters).await
+    }
+    .await;
+
+    for upload in uploads {
+        let _ = tokio::fs::remove_file(upload).await;
+    }
+
+    openai_response(result)
+}
+
+async fn embeddings(
+    app_state: web::Data<AppState>,
+    parameters: web::Json<EmbeddingParameters>,
+) -> HttpResponse {
+    openai_response(
+        app_state
+            .gaia_client
+            .create_embeddings(parameters.into_inner())
+            .await,
+    )
+}
+
+async fn models(app_state: web::Data<AppState>) -> HttpResponse {
+    openai_response(app_state.gaia_client.list_models().await)
+}
+
+/// Largest image or mask accepted by `/v1/images/edits`.
+const MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+/// Largest text field, such as the prompt, accepted by `/v1/images/edits`.
+const MAX_FORM_TEXT_BYTES: usize = 4 * 1024;
+/// Largest `/v1/images/edits` body, counting every field.
+const MAX_FORM_BYTES: usize = 2 * MAX_UPLOAD_BYTES + 64 * 1024;
+
+/// Parses the `multipart/form-data` body of `/v1/images/edits`.
+///
+/// Uploaded files are written to the temp directory and their paths pushed to
+/// `uploads` so the caller can remove them once the request is done. Fields
+/// and the whole body are size-limited while they are read.
+async fn read_edit_form(
+    mut payload: Multipart,
+    uploads: &mut Vec<String>,
+) -> Result<EditImageParameters, APIError> {
+    let mut image = None;
+    let mut mask = None;
+    let mut prompt = None;
+    let mut model = None;
+    let mut n = None;
+    let mut size = None;
+    let mut response_format = None;
+    let mut user = None;
+    let mut total = 0;
+
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(|e| APIError::InvalidRequest(e.to_string()))?;
+        let name = field.name().unwrap_or_default().to_string();
+        let file_name = field
+            .content_disposition()
+            .and_then(|cd| cd.get_filename())
+            .map(str::to_string);
+
+        let limit = match name.as_str() {
+            "image" | "mask" => MAX_UPLOAD_BYTES,
+            _ => MAX_FORM_TEXT_BYTES,
+        };
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| APIError::InvalidRequest(e.to_string()))?;
+            total += chunk.len();
+            if total > MAX_FORM_BYTES {
+                return Err(APIError::PayloadTooLarge(format!(
+                    "The form is larger than {} bytes",
+                    MAX_FORM_BYTES
+                )));
+            }
+            bytes.extend_from_slice(&chunk);
+            if bytes.len() > limit {
+                return Err(APIError::PayloadTooLarge(format!(
+                    "Field {} is larger than {} bytes",
+                    name, limit
+                )));
+            }
+        }
+
+        match name.as_str() {
+            "image" | "mask" => {
+                let path = save_upload(&bytes, &name, file_name.as_deref()).await?;
+                uploads.push(path.clone());
+                if name == "image" {
+                    image = Some(path);
+                } else {
+                    mask = Some(path);
+                }
+            }
+            "prompt" => prompt = Some(form_text(bytes, &name)?),
+            "model" => model = Some(form_text(bytes, &name)?),
+            "user" => user = Some(form_text(bytes, &name)?),
+            "n" => {
+                let value = form_text(bytes, &name)?;
+                n = Some(value.parse::<u32>().map_err(|_| {
+                    APIError::InvalidParameters(format!("Invalid number for n: {}", value))
+                })?);
+            }
+            "size" => size = Some(form_enum(bytes, &name)?),
+            "response_format" => response_format = Some(form_enum(bytes, &name)?),
+            _ => {}
+        }
+    }
+
+    Ok(EditImageParameters {
+        image: FileUpload::File(
+            image.ok_or_else(|| APIError::InvalidParameters("Missing image file".to_string()))?,
+        ),
+        prompt: prompt.ok_or_else(|| APIError::InvalidParameters("Missing prompt".to_string()))?,
+        mask: mask.map(FileUpload::File),
+        model,
+        n,
+        size,
+        response_format,
+        user,
+    })
+}
+
+fn form_text(bytes: Vec<u8>, name: &str) -> Result<String, APIError> {
+    String::from_utf8(bytes)
+        .map_err(|_| APIError::InvalidParameters(format!("Field {} is not valid UTF-8", name)))
+}
+
+/// Parses a form field into one of openai_dive's string-valued enums.
+fn form_enum<T: DeserializeOwned>(bytes: Vec<u8>, name: &str) -> Result<T, APIError> {
+    let value = form_text(bytes, name)?;
+    serde_json::from_value(serde_json::Value::String(value.clone()))
+        .map_err(|_| APIError::InvalidParameters(format!("Invalid value for {}: {}", name, value)))
+}
+
+async fn save_upload(
+    bytes: &[u8],
+    prefix: &str,
+    file_name: Option<&str>,
+) -> Result<String, APIError> {
+    // Only keep the final component so a crafted filename cannot escape the temp dir.
+    let file_name = format!(
+        "temp_{}_{}_{}",
+        prefix,
+        uuid::Uuid::new_v4(),
+        file_name
+            .and_then(|name| std::path::Path::new(name).file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "upload.png".to_string())
+    );
+    let temp_path = std::env::temp_dir().join(file_name);
+    tokio::fs::write(&temp_path, bytes)
+        .await
+        .map_err(|e| APIError::IOError(e.to_string()))?;
+    Ok(temp_path.to_string_lossy().into_owned())
+}
@@ -0,0 +1,378 @@
+use actix_web::{http::StatusCode, web, HttpResponse, ResponseError};
+use color_eyre::{eyre::eyre, Result};
+use openai_dive::v1::resources::chat::{ChatMessage, ChatMessageContent};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{
+    error::error_response,
+    gaia_client::APIError,
+    server::AppState,
+    tools::ToolRegistry,
+    types::{ChatOptions, ChatRequest},
+};
+
+/// Longest accepted persona name.
+const MAX_NAME_LENGTH: usize = 64;
+
+/// A named agent: a system prompt with the model, sampling defaults and
+/// tools it runs with. Requests select one with `"agent": "<name>"`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Persona {
+    pub name: String,
+    pub description: Option<String>,
+    /// Sent ahead of the request's own messages.
+    pub system_prompt: String,
+    /// Model used when the request does not name one.
+    pub model: Option<String>,
+    /// Defaults for options the request leaves unset.
+    #[serde(default)]
+    pub parameters: PersonaParameters,
+    /// Server-side tools the persona may run; all registered tools when unset.
+    pub allowed_tools: Option<Vec<String>>,
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub updated_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PersonaParameters {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub stop: Option<Vec<String>>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+}
+
+impl Persona {
+    /// Checks the name and that the defaults are valid chat options.
+    pub fn validate(&self) -> Result<(), APIError> {
+        if self.name.is_empty()
+            || self.name.len() > MAX_NAME_LENGTH
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(APIError::InvalidParameters(format!(
+                "name must be 1 to {} letters, digits, '-' or '_'",
+                MAX_NAME_LENGTH
+            )));
+        }
+        if self.system_prompt.trim().is_empty() {
+            return Err(APIError::InvalidParameters(
+                "system_prompt must not be empty".to_string(),
+            ));
+        }
+        self.apply(Vec::new(), ChatOptions::default()).1.validate()
+    }
+
+    /// Prepends the system prompt to `messages` and fills the options the
+    /// request left unset from the persona's defaults.
+    pub fn apply(
+        &self,
+        messages: Vec<ChatMessage>,
+        mut options: ChatOptions,
+    ) -> (Vec<ChatMessage>, ChatOptions) {
+        let parameters = &self.parameters;
+        options.model = options.model.or_else(|| self.model.clone());
+        options.temperature = options.temperature.or(parameters.temperature);
+        options.top_p = options.top_p.or(parameters.top_p);
+        options.max_tokens = options.max_tokens.or(parameters.max_tokens);
+        options.stop = options.stop.or_else(|| parameters.stop.clone());
+        options.presence_penalty = options.presence_penalty.or(parameters.presence_penalty);
+        options.frequency_penalty = options.frequency_penalty.or(parameters.frequency_penalty);
+
+        let prompt = ChatMessage::System {
+            content: ChatMessageContent::Text(self.system_prompt.clone()),
+            name: None,
+        };
+        (std::iter::once(prompt).chain(messages).collect(), options)
+    }
+
+    /// The subset of `registry` this persona may use.
+    pub fn tools(&self, registry: &ToolRegistry) -> ToolRegistry {
+        match &self.allowed_tools {
+            Some(names) => registry.restricted_to(names),
+            None => registry.clone(),
+        }
+    }
+}
+
+/// File-backed registry of personas, keyed by name.
+///
+/// Like the API key store, the whole file is rewritten on every change.
+/// Changes are made to a copy of the registry, which replaces the one in
+/// memory only once it has been written, so a failed write changes nothing.
+pub struct PersonaStore {
+    path: PathBuf,
+    personas: RwLock<BTreeMap<String, Persona>>,
+}
+
+impl PersonaStore {
+    /// Opens the store at `path`, starting empty if it does not exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let personas: Vec<Persona> = if path.exists() {
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| eyre!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&contents)
+                .map_err(|e| eyre!("Invalid agent store {}: {}", path.display(), e))?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            path,
+            personas: RwLock::new(
+                personas
+                    .into_iter()
+                    .map(|persona| (persona.name.clone(), persona))
+                    .collect(),
+            ),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Persona> {
+        self.personas.read().get(name).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Persona> {
+        self.personas.read().values().cloned().collect()
+    }
+
+    /// Adds a persona. Returns `false` if the name is already taken.
+    pub fn create(&self, mut persona: Persona) -> Result<bool> {
+        let mut personas = self.personas.write();
+        if personas.contains_key(&persona.name) {
+            return Ok(false);
+        }
+        let now = now();
+        persona.created_at = now;
+        persona.updated_at = now;
+        let mut updated = personas.clone();
+        updated.insert(persona.name.clone(), persona);
+        self.persist(&updated)?;
+        *personas = updated;
+        Ok(true)
+    }
+
+    /// Replaces a persona, keeping its creation time. Returns the stored
+    /// persona, or `None` if there is none with that name.
+    pub fn update(&self, mut persona: Persona) -> Result<Option<Persona>> {
+        let mut personas = self.personas.write();
+        let Some(existing) = personas.get(&persona.name) else {
+            return Ok(None);
+        };
+        persona.created_at = existing.created_at;
+        persona.updated_at = now();
+        let mut updated = personas.clone();
+        updated.insert(persona.name.clone(), persona.clone());
+        self.persist(&updated)?;
+        *personas = updated;
+        Ok(Some(persona))
+    }
+
+    pub fn delete(&self, name: &str) -> Result<bool> {
+        let mut personas = self.personas.write();
+        let mut updated = personas.clone();
+        if updated.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.persist(&updated)?;
+        *personas = updated;
+        Ok(true)
+    }
+
+    fn persist(&self, personas: &BTreeMap<String, Persona>) -> Result<()> {
+        let contents = serde_json::to_string_pretty(&personas.values().collect::<Vec<_>>())?;
+        // Write to a sibling file first so a crash never leaves a truncated store.
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents)
+            .map_err(|e| eyre!("Failed to write {}: {}", tmp_path.display(), e))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .map_err(|e| eyre!("Failed to replace {}: {}", self.path.display(), e))?;
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Looks up the persona a request selected.
+///
+/// # Errors
+///
+/// Returns [`APIError::AgentNotFound`] if there is no such persona or the
+/// registry is disabled.
+pub(super) fn resolve(app_state: &AppState, name: &str) -> Result<Persona, APIError> {
+    app_state
+        .personas
+        .as_ref()
+        .and_then(|store| store.get(name))
+        .ok_or_else(|| APIError::AgentNotFound(name.to_string()))
+}
+
+/// Applies the persona a chat request selected, if any, and returns the
+/// server-side tools the request may run.
+pub(super) fn apply_to_chat(
+    app_state: &AppState,
+    request: &mut ChatRequest,
+) -> Result<Arc<ToolRegistry>, APIError> {
+    let Some(agent) = request.agent.take() else {
+        return Ok(app_state.tools.clone());
+    };
+    let persona = resolve(app_state, &agent)?;
+    (request.messages, request.options) = persona.apply(
+        std::mem::take(&mut request.messages),
+        std::mem::take(&mut request.options),
+    );
+    Ok(Arc::new(persona.tools(&app_state.tools)))
+}
+
+/// Registers the persona management endpoints under `/admin/agents`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin/agents")
+            .route("", web::get().to(list_personas))
+            .route("", web::post().to(create_persona))
+            .route("/{name}", web::get().to(get_persona))
+            .route("/{name}", web::put().to(update_persona))
+            .route("/{name}", web::delete().to(delete_persona)),
+    );
+}
+
+fn store_unavailable() -> HttpResponse {
+    error_response(
+        StatusCode::NOT_FOUND,
+        "invalid_request_error",
+        "agents_disabled",
+        "The agent registry is not enabled on this server".to_string(),
+    )
+}
+
+fn internal_error(e: color_eyre::Report) -> HttpResponse {
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "api_error",
+        "internal_error",
+        e.to_string(),
+    )
+}
+
+async fn list_personas(app_state: web::Data<AppState>) -> HttpResponse {
+    match &app_state.personas {
+        Some(store) => HttpResponse::Ok().json(store.list()),
+        None => store_unavailable(),
+    }
+}
+
+async fn get_persona(app_state: web::Data<AppState>, name: web::Path<String>) -> HttpResponse {
+    if app_state.personas.is_none() {
+        return store_unavailable();
+    }
+    match resolve(&app_state, &name) {
+        Ok(persona) => HttpResponse::Ok().json(persona),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn create_persona(
+    app_state: web::Data<AppState>,
+    request: web::Json<Persona>,
+) -> HttpResponse {
+    let Some(store) = &app_state.personas else {
+        return store_unavailable();
+    };
+    let persona = request.into_inner();
+    if let Err(e) = persona.validate() {
+        return e.error_response();
+    }
+
+    match store.create(persona.clone()) {
+        Ok(true) => HttpResponse::Created().json(store.get(&persona.name).unwrap_or(persona)),
+        Ok(false) => error_response(
+            StatusCode::CONFLICT,
+            "invalid_request_error",
+            "agent_exists",
+            format!("An agent named {} already exists", persona.name),
+        ),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Replaces a persona; the name in the path wins over the one in the body.
+async fn update_persona(
+    app_state: web::Data<AppState>,
+    name: web::Path<String>,
+    request: web::Json<Persona>,
+) -> HttpResponse {
+    let Some(store) = &app_state.personas else {
+        return store_unavailable();
+    };
+    let mut persona = request.into_inner();
+    persona.name = name.into_inner();
+    if let Err(e) = persona.validate() {
+        return e.error_response();
+    }
+
+    match store.update(persona.clone()) {
+        Ok(Some(persona)) => HttpResponse::Ok().json(persona),
+        Ok(None) => APIError::AgentNotFound(persona.name).error_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn delete_persona(app_state: web::Data<AppState>, name: web::Path<String>) -> HttpResponse {
+    let Some(store) = &app_state.personas else {
+        return store_unavailable();
+    };
+
+    match store.delete(&name) {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => APIError::AgentNotFound(name.into_inner()).error_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn persona(name: &str) -> Persona {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "system_prompt": "You are helpful.",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn failed_writes_leave_the_store_unchanged() {
+        let dir = std::env::temp_dir().join(format!("gaia-personas-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = PersonaStore::open(dir.join("agents.json")).unwrap();
+        assert!(store.create(persona("kept")).unwrap());
+
+        // The store can no longer be written once its directory is gone.
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(store.create(persona("added")).is_err());
+        assert!(store.update(persona("kept")).is_err());
+        assert!(store.delete("kept").is_err());
+
+        assert!(store.get("added").is_none());
+        let kept = store.get("kept").unwrap();
+        assert_eq!(kept.created_at, kept.updated_at);
+        assert_eq!(store.list().len(), 1);
+    }
+}
@@ -0,0 +1,668 @@
+use actix_web::{http::StatusCode, web, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use color_eyre::{eyre::eyre, Result};
+use gadget_sdk::info;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Mutex};
+
+use super::{
+    auth::{ApiKey, Scope},
+    error::error_response,
+    gaia_client::APIError,
+    net, personas,
+    rate_limit::{self, ReportsUsage},
+    server::AppState,
+    tools,
+    types::{
+        ChatCompletionWithContext, ChatRequest, CreateImageRequest, EditImageRequest,
+        EmbeddingsRequest,
+    },
+};
+
+/// Workers draining the queue when not configured.
+pub const DEFAULT_JOB_WORKERS: usize = 4;
+/// Jobs one caller may have waiting at once when not configured.
+pub const DEFAULT_MAX_QUEUED_JOBS: usize = 100;
+
+/// Finished jobs older than this are removed when the queue is opened.
+const JOB_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_ATTEMPTS: u32 = 3;
+/// Unix time the webhook payload was signed at.
+const WEBHOOK_TIMESTAMP_HEADER: &str = "X-Gaia-Timestamp";
+/// `sha256=` and the hex HMAC-SHA256 of `<timestamp>.<body>` under the webhook secret.
+const WEBHOOK_SIGNATURE_HEADER: &str = "X-Gaia-Signature";
+
+/// A request accepted by `POST /jobs`, tagged with the endpoint it would
+/// have been sent to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "request", rename_all = "snake_case")]
+pub enum QueuedRequest {
+    Chat(ChatRequest),
+    AnalyzeImage(String),
+    CreateImage(CreateImageRequest),
+    EditImage(EditImageRequest),
+    Embeddings(EmbeddingsRequest),
+}
+
+impl QueuedRequest {
+    fn scope(&self) -> Scope {
+        match self {
+            QueuedRequest::CreateImage(_) | QueuedRequest::EditImage(_) => Scope::Images,
+            _ => Scope::Chat,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// Why a job failed, in the same shape as an HTTP error body.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobFailure {
+    pub r#type: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl From<&APIError> for JobFailure {
+    fn from(e: &APIError) -> Self {
+        let (r#type, code) = e.kind();
+        Self {
+            r#type: r#type.to_string(),
+            code: code.to_string(),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// A queued request and, once it has run, its outcome.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    pub request: QueuedRequest,
+    /// The response the synchronous endpoint would have returned.
+    pub result: Option<Value>,
+    pub error: Option<JobFailure>,
+    /// Receives the job as JSON once it has finished.
+    pub webhook_url: Option<String>,
+    /// Id of the API key that created the job, when authentication is on.
+    pub owner: Option<String>,
+    /// Rate-limit subjects charged for the tokens the job uses.
+    subjects: Vec<String>,
+    pub created_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+}
+
+/// What `GET /jobs/{id}` and webhooks report about a job.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobView {
+    pub id: String,
+    pub status: JobStatus,
+    pub result: Option<Value>,
+    pub error: Option<JobFailure>,
+    pub created_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+}
+
+impl From<&Job> for JobView {
+    fn from(job: &Job) -> Self {
+        Self {
+            id: job.id.clone(),
+            status: job.status,
+            result: job.result.clone(),
+            error: job.error.clone(),
+            created_at: job.created_at,
+            started_at: job.started_at,
+            finished_at: job.finished_at,
+        }
+    }
+}
+
+impl Job {
+    /// Who the job counts against for [`JobQueue`]'s per-caller limit: its
+    /// API key, or the client IP without authentication.
+    fn queued_by(&self) -> Option<&str> {
+        self.subjects.first().map(String::as_str)
+    }
+
+    /// Jobs without an owner are visible to everyone; admins see all.
+    fn visible_to(&self, key: Option<&ApiKey>) -> bool {
+        match (&self.owner, key) {
+            (None, _) => true,
+            (Some(_), Some(key)) if key.allows(Scope::Admin) => true,
+            (Some(owner), Some(key)) => *owner == key.id,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Jobs persisted in a sled database, with a channel of ids to run.
+///
+/// Jobs that were queued or running when the server stopped are queued
+/// again when it starts. Each caller may have at most `max_queued` jobs
+/// waiting, which bounds both the database and the channel.
+pub struct JobQueue {
+    db: sled::Db,
+    sender: mpsc::UnboundedSender<String>,
+    receiver: Mutex<mpsc::UnboundedReceiver<String>>,
+    max_queued: usize,
+    /// Waiting jobs per [`Job::queued_by`].
+    queued: parking_lot::Mutex<HashMap<String, usize>>,
+    /// Signs webhook payloads; webhooks are refused without it.
+    webhook_secret: Option<String>,
+}
+
+impl JobQueue {
+    pub fn open(path: &Path, max_queued: usize, webhook_secret: Option<String>) -> Result<Self> {
+        let db = sled::open(path)
+            .map_err(|e| eyre!("Failed to open job queue {}: {}", path.display(), e))?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let queue = Self {
+            db,
+            sender,
+            receiver: Mutex::new(receiver),
+            max_queued,
+            queued: parking_lot::Mutex::new(HashMap::new()),
+            webhook_secret,
+        };
+
+        let now = now();
+        let mut pending = Vec::new();
+        for bytes in queue.db.iter().values() {
+            let mut job: Job = serde_json::from_slice(&bytes?)?;
+            match job.status {
+                JobStatus::Queued | JobStatus::Running => {
+                    job.status = JobStatus::Queued;
+                    job.started_at = None;
+                    queue.put(&job)?;
+                    pending.push(job);
+                }
+                JobStatus::Succeeded | JobStatus::Failed => {
+                    let finished_at = job.finished_at.unwrap_or(job.created_at);
+                    if now.saturating_sub(finished_at) > JOB_RETENTION.as_secs() {
+                        queue.db.remove(job.id.as_bytes())?;
+                    }
+                }
+            }
+        }
+        pending.sort_by_key(|job| job.created_at);
+        if !pending.is_empty() {
+            info!("Requeued {} unfinished jobs", pending.len());
+        }
+        for job in pending {
+            // Requeued jobs count towards the limit but are never refused.
+            if let Some(caller) = job.queued_by() {
+                *queue.queued.lock().entry(caller.to_string()).or_default() += 1;
+            }
+            let _ = queue.sender.send(job.id);
+        }
+
+        Ok(queue)
+    }
+
+    fn put(&self, job: &Job) -> Result<()> {
+        self.db
+            .insert(job.id.as_bytes(), serde_json::to_vec(job)?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<Job>> {
+        match self.db.get(id.as_bytes())? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn accepts_webhooks(&self) -> bool {
+        self.webhook_secret.is_some()
+    }
+
+    /// Stores a job and queues it behind the ones already waiting.
+    ///
+    /// Returns `None` without storing anything when the caller already has
+    /// `max_queued` jobs waiting.
+    pub fn enqueue(
+        &self,
+        request: QueuedRequest,
+        webhook_url: Option<String>,
+        owner: Option<String>,
+        subjects: Vec<String>,
+    ) -> Result<Option<Job>> {
+        let job = Job {
+            id: uuid::Uuid::new_v4().to_string(),
+            status: JobStatus::Queued,
+            request,
+            result: None,
+            error: None,
+            webhook_url,
+            owner,
+            subjects,
+            created_at: now(),
+            started_at: None,
+            finished_at: None,
+        };
+        if let Some(caller) = job.queued_by() {
+            let mut queued = self.queued.lock();
+            let count = queued.entry(caller.to_string()).or_default();
+            if *count >= self.max_queued {
+                return Ok(None);
+            }
+            *count += 1;
+        }
+        let stored = self.put(&job).and_then(|()| {
+            self.sender
+                .send(job.id.clone())
+                .map_err(|_| eyre!("The job queue is closed"))
+        });
+        if let Err(e) = stored {
+            self.dequeued(&job);
+            return Err(e);
+        }
+        Ok(Some(job))
+    }
+
+    /// Releases the job's slot in its caller's limit once it stops waiting.
+    fn dequeued(&self, job: &Job) {
+        let Some(caller) = job.queued_by() else {
+            return;
+        };
+        let mut queued = self.queued.lock();
+        if let Some(count) = queued.get_mut(caller) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                queued.remove(caller);
+            }
+        }
+    }
+
+    /// Waits for the next job id to run.
+    async fn next(&self) -> Option<String> {
+        self.receiver.lock().await.recv().await
+    }
+
+    /// Posts the finished job to its webhook, signed with the webhook secret
+    /// and retrying with backoff.
+    ///
+    /// The host must resolve to public addresses only, and redirects are not
+    /// followed.
+    async fn notify(&self, job: &Job) {
+        let (Some(url), Some(secret)) = (&job.webhook_url, &self.webhook_secret) else {
+            return;
+        };
+        let client = match url::Url::parse(url) {
+            Ok(parsed) => {
+                let builder = reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT);
+                net::public_client(&parsed, builder).await
+            }
+            Err(e) => Err(e.into()),
+        };
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                info!("Not calling the webhook of job {}: {}", job.id, e);
+                return;
+            }
+        };
+        let body = match serde_json::to_vec(&JobView::from(job)) {
+            Ok(body) => body,
+            Err(e) => {
+                info!("Failed to serialize job {}: {}", job.id, e);
+                return;
+            }
+        };
+        for attempt in 1..=WEBHOOK_ATTEMPTS {
+            let timestamp = now().to_string();
+            let signature = sign(secret, &timestamp, &body);
+            let request = client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(WEBHOOK_TIMESTAMP_HEADER, &timestamp)
+                .header(WEBHOOK_SIGNATURE_HEADER, format!("sha256={}", signature))
+                .body(body.clone());
+            match request.send().await {
+                Ok(response) if response.status().is_success() => return,
+                Ok(response) => info!(
+                    "Webhook for job {} returned {} (attempt {})",
+                    job.id,
+                    response.status(),
+                    attempt
+                ),
+                Err(e) => info!(
+                    "Webhook for job {} failed: {} (attempt {})",
+                    job.id, e, attempt
+                ),
+            }
+            if attempt < WEBHOOK_ATTEMPTS {
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+            }
+        }
+    }
+}
+
+/// Hex HMAC-SHA256 of `<timestamp>.<body>`, so receivers can check both the
+/// payload and its age.
+fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut message = Vec::with_capacity(timestamp.len() + 1 + body.len());
+    message.extend_from_slice(timestamp.as_bytes());
+    message.push(b'.');
+    message.extend_from_slice(body);
+    hmac_sha256(secret.as_bytes(), &message)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// HMAC (RFC 2104) over SHA-256.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&outer.finalize());
+    digest
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Serializes a response the way the synchronous endpoint would, along with
+/// the tokens it used.
+fn output<R: Serialize + ReportsUsage>(response: R) -> Result<(Value, Option<u32>), APIError> {
+    Ok((serde_json::to_value(&response)?, response.total_tokens()))
+}
+
+/// Runs a queued request as its synchronous endpoint would.
+async fn execute(
+    app_state: &AppState,
+    request: QueuedRequest,
+) -> Result<(Value, Option<u32>), APIError> {
+    let client = &app_state.gaia_client;
+    match request {
+        QueuedRequest::Chat(mut request) => {
+            let registry = personas::apply_to_chat(app_state, &mut request)?;
+            if request.run_tools {
+                output(
+                    tools::run_tool_loop(
+                        client,
+                        &registry,
+                        request.messages,
+                        &request.options,
+                        request.max_iterations,
+                    )
+                    .await?,
+                )
+            } else {
+                let (completion, context) = client
+                    .chat_with_context(request.messages, &request.options)
+                    .await?;
+                output(ChatCompletionWithContext {
+                    completion,
+                    context,
+                })
+            }
+        }
+        QueuedRequest::AnalyzeImage(image_url) => output(client.analyze_image(image_url).await?),
+        QueuedRequest::CreateImage(request) => output(
+            client
+                .create_image(
+                    request.prompt,
+                    request.n,
+                    request.quality,
+                    request.size,
+                    request.style,
+                )
+                .await?,
+        ),
+        QueuedRequest::EditImage(request) => output(
+            client
+                .edit_image(
+                    request.image_path,
+                    request.prompt,
+                    request.mask_path,
+                    request.n,
+                    request.size,
+                )
+                .await?,
+        ),
+        QueuedRequest::Embeddings(request) => output(
+            client
+                .embeddings(request.input.into_vec(), &request.options)
+                .await?,
+        ),
+    }
+}
+
+/// Runs queued jobs one at a time until the queue closes.
+///
+/// Start one per worker; they share the queue.
+pub async fn run_worker(app_state: web::Data<AppState>) {
+    let Some(queue) = app_state.jobs.clone() else {
+        return;
+    };
+    while let Some(id) = queue.next().await {
+        let mut job = match queue.get(&id) {
+            Ok(Some(job)) if job.status == JobStatus::Queued => job,
+            Ok(_) => continue,
+            Err(e) => {
+                info!("Failed to load job {}: {}", id, e);
+                continue;
+            }
+        };
+        queue.dequeued(&job);
+
+        job.status = JobStatus::Running;
+        job.started_at = Some(now());
+        if let Err(e) = queue.put(&job) {
+            info!("Failed to update job {}: {}", id, e);
+        }
+
+        // Quotas may have run out while the job was waiting.
+        let quota = match &app_state.rate_limiter {
+            Some(limiter) => limiter.check_quota(&job.subjects),
+            None => Ok(()),
+        };
+        let outcome = match quota {
+            Ok(()) => execute(&app_state, job.request.clone())
+                .await
+                .map_err(|e| JobFailure::from(&e)),
+            Err(denial) => Err(JobFailure {
+                r#type: "insufficient_quota".to_string(),
+                code: "quota_exceeded".to_string(),
+                message: denial.message().to_string(),
+            }),
+        };
+
+        match outcome {
+            Ok((result, tokens)) => {
+                if let (Some(limiter), Some(tokens)) = (&app_state.rate_limiter, tokens) {
+                    limiter.record(&job.subjects, tokens);
+                }
+                job.status = JobStatus::Succeeded;
+                job.result = Some(result);
+            }
+            Err(failure) => {
+                job.status = JobStatus::Failed;
+                job.error = Some(failure);
+            }
+        }
+        job.finished_at = Some(now());
+        if let Err(e) = queue.put(&job) {
+            info!("Failed to store the result of job {}: {}", id, e);
+        }
+        queue.notify(&job).await;
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateJobRequest {
+    #[serde(flatten)]
+    request: QueuedRequest,
+    webhook_url: Option<String>,
+}
+
+/// Registers the job endpoints under `/jobs`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/jobs")
+            .route("", web::post().to(create_job))
+            .route("/{id}", web::get().to(get_job)),
+    );
+}
+
+fn jobs_unavailable() -> HttpResponse {
+    error_response(
+        StatusCode::NOT_FOUND,
+        "invalid_request_error",
+        "jobs_disabled",
+        "The job queue is not enabled on this server".to_string(),
+    )
+}
+
+fn internal_error(e: color_eyre::Report) -> HttpResponse {
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "api_error",
+        "internal_error",
+        e.to_string(),
+    )
+}
+
+async fn create_job(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+    request: web::Json<CreateJobRequest>,
+) -> HttpResponse {
+    let Some(queue) = &app_state.jobs else {
+        return jobs_unavailable();
+    };
+    let request = request.into_inner();
+    let key = req.extensions().get::<ApiKey>().cloned();
+
+    let scope = request.request.scope();
+    if let Some(key) = key.as_ref().filter(|key| !key.allows(scope)) {
+        return error_response(
+            StatusCode::FORBIDDEN,
+            "permission_error",
+            "insufficient_scope",
+            format!("API key {} is missing the {:?} scope", key.id, scope),
+        );
+    }
+    if let QueuedRequest::Chat(chat) = &request.request {
+        if chat.stream {
+            return APIError::InvalidParameters("Queued chats cannot be streamed".to_string())
+                .error_response();
+        }
+    }
+    if let Some(url) = &request.webhook_url {
+        if !queue.accepts_webhooks() {
+            return APIError::InvalidParameters(
+                "Webhooks are disabled because no webhook secret is configured".to_string(),
+            )
+            .error_response();
+        }
+        let checked = match url::Url::parse(url) {
+            Ok(parsed) => net::resolve_public(&parsed).await.map(|_| ()),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = checked {
+            return APIError::InvalidParameters(format!("Invalid webhook_url {}: {}", url, e))
+                .error_response();
+        }
+    }
+
+    let subjects = rate_limit::subjects(key.as_ref(), req.peer_addr());
+    match queue.enqueue(
+        request.request,
+        request.webhook_url,
+        key.map(|key| key.id),
+        subjects,
+    ) {
+        Ok(Some(job)) => HttpResponse::Accepted().json(JobView::from(&job)),
+        Ok(None) => error_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            "requests",
+            "too_many_queued_jobs",
+            format!(
+                "At most {} jobs may be queued at once, please retry later",
+                queue.max_queued
+            ),
+        ),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn get_job(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let Some(queue) = &app_state.jobs else {
+        return jobs_unavailable();
+    };
+    let key = req.extensions().get::<ApiKey>().cloned();
+    match queue.get(&id) {
+        Ok(Some(job)) if job.visible_to(key.as_ref()) => {
+            HttpResponse::Ok().json(JobView::from(&job))
+        }
+        Ok(_) => error_response(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            "job_not_found",
+            format!("No job with id {}", id),
+        ),
+        Err(e) => internal_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        let digest = hmac_sha256(&[0x0b; 20], b"Hi There");
+        let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(
+            hex,
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+    }
+
+    #[test]
+    fn signature_covers_timestamp_and_body() {
+        let signature = sign("secret", "1700000000", b"{}");
+        assert_eq!(signature.len(), 64);
+        assert_ne!(signature, sign("secret", "1700000001", b"{}"));
+        assert_ne!(signature, sign("secret", "1700000000", b"[]"));
+        assert_ne!(signature, sign("other", "1700000000", b"{}"));
+    }
+}
@@ -0,0 +1,648 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, HeaderValue},
+        StatusCode,
+    },
+    middleware::Next,
+    web, Error, HttpMessage, HttpResponse,
+};
+use color_eyre::{eyre::eyre, Result};
+use gadget_sdk::info;
+use openai_dive::v1::resources::{
+    chat::ChatCompletionResponse, embedding::EmbeddingResponse, image::ImageResponse,
+    model::ListModelResponse,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::{
+    agent::AgentRunResponse,
+    auth::{self, ApiKey, Scope},
+    error::error_response,
+    server::AppState,
+    tools::ToolLoopResponse,
+    types::{ChatCompletionWithContext, EmbeddingsResponse},
+};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// Limits applied independently to every API key and every client IP.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RateLimitConfig {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+    pub daily_token_quota: Option<u64>,
+    pub monthly_token_quota: Option<u64>,
+    /// Where quota counters are persisted; kept in memory only when unset.
+    pub state_file: Option<PathBuf>,
+}
+
+impl RateLimitConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.requests_per_minute.is_some()
+            || self.tokens_per_minute.is_some()
+            || self.daily_token_quota.is_some()
+            || self.monthly_token_quota.is_some()
+    }
+}
+
+/// Total tokens consumed by a request, attached to the response extensions by
+/// handlers so the rate limiter can charge them after the fact.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenUsage(pub u32);
+
+/// Responses that can report how many tokens they consumed.
+pub trait ReportsUsage {
+    fn total_tokens(&self) -> Option<u32>;
+}
+
+impl ReportsUsage for ChatCompletionResponse {
+    fn total_tokens(&self) -> Option<u32> {
+        self.usage.as_ref().map(|usage| usage.total_tokens)
+    }
+}
+
+impl ReportsUsage for ChatCompletionWithContext {
+    fn total_tokens(&self) -> Option<u32> {
+        self.completion.total_tokens()
+    }
+}
+
+impl ReportsUsage for EmbeddingResponse {
+    fn total_tokens(&self) -> Option<u32> {
+        self.usage.as_ref().map(|usage| usage.total_tokens)
+    }
+}
+
+impl ReportsUsage for EmbeddingsResponse {
+    fn total_tokens(&self) -> Option<u32> {
+        self.usage.as_ref().map(|usage| usage.total_tokens)
+    }
+}
+
+impl ReportsUsage for AgentRunResponse {
+    fn total_tokens(&self) -> Option<u32> {
+        Some(self.total_tokens)
+    }
+}
+
+impl ReportsUsage for ToolLoopResponse {
+    fn total_tokens(&self) -> Option<u32> {
+        Some(self.total_tokens)
+    }
+}
+
+impl ReportsUsage for ImageResponse {
+    fn total_tokens(&self) -> Option<u32> {
+        None
+    }
+}
+
+impl ReportsUsage for ListModelResponse {
+    fn total_tokens(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// Serializes `response` as JSON, recording its token usage for the limiter.
+pub fn json_with_usage<R: Serialize + ReportsUsage>(response: &R) -> HttpResponse {
+    let mut http_response = HttpResponse::Ok().json(response);
+    if let Some(tokens) = response.total_tokens() {
+        http_response.extensions_mut().insert(TokenUsage(tokens));
+    }
+    http_response
+}
+
+/// Charges a streamed response's tokens once its body has been sent.
+///
+/// Streamed bodies outlive the middleware, so handlers take this from the
+/// request extensions and hand it to the stream instead of setting
+/// [`TokenUsage`].
+#[derive(Clone)]
+pub struct UsageMeter {
+    limiter: Arc<RateLimiter>,
+    subjects: Arc<[String]>,
+}
+
+impl UsageMeter {
+    pub fn record(&self, tokens: u32) {
+        self.limiter.record(&self.subjects, tokens);
+    }
+}
+
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn per_minute(limit: u32) -> Self {
+        Self {
+            capacity: limit as f64,
+            tokens: limit as f64,
+            refill_per_sec: limit as f64 / 60.0,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.updated_at = now;
+    }
+
+    /// Time until `amount` tokens are available, or zero if they already are.
+    fn wait_for(&mut self, amount: f64) -> Duration {
+        self.refill();
+        if self.tokens >= amount {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((amount - self.tokens) / self.refill_per_sec)
+        }
+    }
+
+    /// Whether the bucket has refilled completely, i.e. is as good as new.
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    /// Removes tokens; the balance may go negative so large responses are
+    /// paid back before the next request is admitted.
+    fn take(&mut self, amount: f64) {
+        self.refill();
+        self.tokens -= amount;
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn is_idle(&mut self) -> bool {
+        self.requests.as_mut().map_or(true, TokenBucket::is_full)
+            && self.tokens.as_mut().map_or(true, TokenBucket::is_full)
+    }
+}
+
+/// Token counters for the current UTC day and month.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct QuotaUsage {
+    day: u64,
+    day_tokens: u64,
+    month: u64,
+    month_tokens: u64,
+}
+
+impl QuotaUsage {
+    /// Resets counters whose period has ended.
+    fn roll(&mut self, now: u64) {
+        let day = now / SECONDS_PER_DAY;
+        if self.day != day {
+            self.day = day;
+            self.day_tokens = 0;
+        }
+        let month = month_index(now);
+        if self.month != month {
+            self.month = month;
+            self.month_tokens = 0;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.day_tokens == 0 && self.month_tokens == 0
+    }
+}
+
+pub struct Denial {
+    code: &'static str,
+    r#type: &'static str,
+    message: String,
+    retry_after: u64,
+}
+
+impl Denial {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    fn into_response(self) -> HttpResponse {
+        let mut response = error_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            self.r#type,
+            self.code,
+            self.message,
+        );
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(self.retry_after.max(1)),
+        );
+        response
+    }
+}
+
+/// Enforces per-subject request/token rates and token quotas.
+///
+/// A subject is either `key:<id>` for an authenticated API key or `ip:<addr>`
+/// for the client address. Rate buckets live in memory; quota counters are
+/// flushed to `state_file` so they survive restarts.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<String, Buckets>>,
+    usage: Mutex<HashMap<String, QuotaUsage>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Result<Self> {
+        let usage = match &config.state_file {
+            Some(path) if path.exists() => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| eyre!("Failed to read {}: {}", path.display(), e))?;
+                serde_json::from_str(&contents)
+                    .map_err(|e| eyre!("Invalid rate limit state {}: {}", path.display(), e))?
+            }
+            _ => HashMap::new(),
+        };
+
+        Ok(Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+            usage: Mutex::new(usage),
+        })
+    }
+
+    /// Admits a request for all `subjects`, or explains why it must wait.
+    pub fn check(&self, subjects: &[String]) -> Result<(), Denial> {
+        self.check_quota(subjects)?;
+
+        let mut buckets = self.buckets.lock();
+        for subject in subjects {
+            let buckets = buckets
+                .entry(subject.clone())
+                .or_insert_with(|| self.new_buckets());
+            if let Some(requests) = &mut buckets.requests {
+                let wait = requests.wait_for(1.0);
+                if !wait.is_zero() {
+                    return Err(Denial {
+                        code: "rate_limit_exceeded",
+                        r#type: "requests",
+                        message: "Request rate limit reached, please retry later".to_string(),
+                        retry_after: wait.as_secs_f64().ceil() as u64,
+                    });
+                }
+            }
+            if let Some(tokens) = &mut buckets.tokens {
+                // Any positive balance admits a request; its real cost is charged afterwards.
+                let wait = tokens.wait_for(f64::MIN_POSITIVE);
+                if !wait.is_zero() {
+                    return Err(Denial {
+                        code: "rate_limit_exceeded",
+                        r#type: "tokens",
+                        message: "Token rate limit reached, please retry later".to_string(),
+                        retry_after: wait.as_secs_f64().ceil() as u64,
+                    });
+                }
+            }
+        }
+
+        for subject in subjects {
+            if let Some(requests) = buckets
+                .get_mut(subject)
+                .and_then(|buckets| buckets.requests.as_mut())
+            {
+                requests.take(1.0);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails if any of `subjects` has used up its daily or monthly token quota.
+    ///
+    /// A request's cost is only known once it finishes, so nothing is
+    /// reserved: requests are admitted while any quota is left, and the ones
+    /// in flight when it runs out can overrun it by their own size.
+    ///
+    /// Unlike [`RateLimiter::check`] this takes nothing from the rate buckets,
+    /// so it suits work admitted earlier, like queued jobs.
+    pub fn check_quota(&self, subjects: &[String]) -> Result<(), Denial> {
+        let now = unix_now();
+        let mut usage = self.usage.lock();
+        for subject in subjects {
+            let counters = usage.entry(subject.clone()).or_default();
+            counters.roll(now);
+            if let Some(quota) = self.config.daily_token_quota {
+                if counters.day_tokens >= quota {
+                    return Err(Denial {
+                        code: "insufficient_quota",
+                        r#type: "insufficient_quota",
+                        message: format!("Daily token quota of {} exceeded", quota),
+                        retry_after: (counters.day + 1) * SECONDS_PER_DAY - now,
+                    });
+                }
+            }
+            if let Some(quota) = self.config.monthly_token_quota {
+                if counters.month_tokens >= quota {
+                    return Err(Denial {
+                        code: "insufficient_quota",
+                        r#type: "insufficient_quota",
+                        message: format!("Monthly token quota of {} exceeded", quota),
+                        retry_after: next_month_start(now) - now,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Charges the tokens a completed request consumed to every subject.
+    pub fn record(&self, subjects: &[String], tokens: u32) {
+        let mut buckets = self.buckets.lock();
+        for subject in subjects {
+            if let Some(bucket) = buckets
+                .get_mut(subject)
+                .and_then(|buckets| buckets.tokens.as_mut())
+            {
+                bucket.take(tokens as f64);
+            }
+        }
+        drop(buckets);
+
+        let now = unix_now();
+        let mut usage = self.usage.lock();
+        for subject in subjects {
+            let counters = usage.entry(subject.clone()).or_default();
+            counters.roll(now);
+            counters.day_tokens += tokens as u64;
+            counters.month_tokens += tokens as u64;
+        }
+    }
+
+    /// Drops subjects whose buckets have refilled and whose counters have
+    /// rolled over, since recreating them yields the same state.
+    pub fn evict_idle(&self) {
+        self.buckets.lock().retain(|_, buckets| !buckets.is_idle());
+
+        let now = unix_now();
+        self.usage.lock().retain(|_, counters| {
+            counters.roll(now);
+            !counters.is_empty()
+        });
+    }
+
+    /// Writes quota counters to the state file, if one is configured.
+    pub fn persist(&self) -> Result<()> {
+        let Some(path) = &self.config.state_file else {
+            return Ok(());
+        };
+        let contents = serde_json::to_string(&*self.usage.lock())?;
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents)
+            .map_err(|e| eyre!("Failed to write {}: {}", tmp_path.display(), e))?;
+        std::fs::rename(&tmp_path, path)
+            .map_err(|e| eyre!("Failed to replace {}: {}", path.display(), e))?;
+        Ok(())
+    }
+
+    fn new_buckets(&self) -> Buckets {
+        Buckets {
+            requests: self.config.requests_per_minute.map(TokenBucket::per_minute),
+            tokens: self.config.tokens_per_minute.map(TokenBucket::per_minute),
+        }
+    }
+}
+
+/// Periodically flushes quota counters so a crash loses at most `interval` of
+/// usage, evicting idle subjects first so neither map grows without bound.
+pub async fn persist_periodically(limiter: Arc<RateLimiter>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        limiter.evict_idle();
+        if let Err(e) = limiter.persist() {
+            info!("Failed to persist rate limit state: {}", e);
+        }
+    }
+}
+
+/// Middleware applying the configured [`RateLimiter`] to metered routes.
+///
+/// Must run after [`auth::require_api_key`] so the caller's key is known.
+pub async fn enforce_rate_limits(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let limiter = req
+        .app_data::<web::Data<AppState>>()
+        .and_then(|state| state.rate_limiter.clone());
+    let metered = matches!(auth::required_scope(&req), Scope::Chat | Scope::Images);
+    let Some(limiter) = limiter.filter(|_| metered) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+
+    let subjects = subjects(req.extensions().get::<ApiKey>(), req.peer_addr());
+
+    if let Err(denial) = limiter.check(&subjects) {
+        return Ok(req
+            .into_response(denial.into_response())
+            .map_into_right_body());
+    }
+
+    let subjects: Arc<[String]> = subjects.into();
+    req.extensions_mut().insert(UsageMeter {
+        limiter: limiter.clone(),
+        subjects: subjects.clone(),
+    });
+
+    let response = next.call(req).await?;
+    if let Some(TokenUsage(tokens)) = response.response().extensions().get::<TokenUsage>() {
+        limiter.record(&subjects, *tokens);
+    }
+    Ok(response.map_into_left_body())
+}
+
+/// Who a request is charged to: its API key, if any, and its client IP.
+pub fn subjects(key: Option<&ApiKey>, peer: Option<SocketAddr>) -> Vec<String> {
+    let mut subjects = Vec::with_capacity(2);
+    if let Some(key) = key {
+        subjects.push(format!("key:{}", key.id));
+    }
+    if let Some(peer) = peer {
+        subjects.push(format!("ip:{}", peer.ip()));
+    }
+    subjects
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Months since January 1970 for a Unix timestamp (UTC).
+fn month_index(timestamp: u64) -> u64 {
+    let (year, month) = civil_year_month(timestamp / SECONDS_PER_DAY);
+    (year - 1970) * 12 + (month - 1)
+}
+
+/// Unix timestamp of the first second of the month after `timestamp` (UTC).
+fn next_month_start(timestamp: u64) -> u64 {
+    let (year, month) = civil_year_month(timestamp / SECONDS_PER_DAY);
+    let (year, month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    days_from_civil(year, month) * SECONDS_PER_DAY
+}
+
+/// Converts days since the Unix epoch to a `(year, month)` pair.
+///
+/// Uses Howard Hinnant's `civil_from_days` algorithm, restricted to dates
+/// after 1970.
+fn civil_year_month(days: u64) -> (u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month)
+}
+
+/// Days since the Unix epoch of the first day of `month` in `year`.
+fn days_from_civil(year: u64, month: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JAN_1_2024: u64 = 1_704_067_200;
+    const FEB_29_2024: u64 = 1_709_164_800;
+    const MAR_1_2024: u64 = 1_709_251_200;
+
+    fn subjects() -> Vec<String> {
+        vec!["key:test".to_string(), "ip:127.0.0.1".to_string()]
+    }
+
+    #[test]
+    fn months_roll_over_at_new_year() {
+        let last_second_of_2023 = JAN_1_2024 - 1;
+        assert_eq!(
+            civil_year_month(last_second_of_2023 / SECONDS_PER_DAY),
+            (2023, 12)
+        );
+        assert_eq!(civil_year_month(JAN_1_2024 / SECONDS_PER_DAY), (2024, 1));
+        assert_eq!(month_index(last_second_of_2023), 53 * 12 + 11);
+        assert_eq!(month_index(JAN_1_2024), 54 * 12);
+        assert_eq!(next_month_start(last_second_of_2023), JAN_1_2024);
+        assert_eq!(days_from_civil(2024, 1) * SECONDS_PER_DAY, JAN_1_2024);
+        assert_eq!(days_from_civil(1970, 1), 0);
+    }
+
+    #[test]
+    fn leap_days_belong_to_february() {
+        assert_eq!(civil_year_month(FEB_29_2024 / SECONDS_PER_DAY), (2024, 2));
+        assert_eq!(civil_year_month(MAR_1_2024 / SECONDS_PER_DAY), (2024, 3));
+        assert_eq!(next_month_start(FEB_29_2024), MAR_1_2024);
+        assert_eq!(month_index(FEB_29_2024) + 1, month_index(MAR_1_2024));
+        // 2100 is not a leap year.
+        assert_eq!(days_from_civil(2100, 3) * SECONDS_PER_DAY, 4_107_542_400);
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let mut bucket = TokenBucket::per_minute(60);
+        bucket.take(60.0);
+        assert!(!bucket.wait_for(1.0).is_zero());
+
+        bucket.updated_at -= Duration::from_secs(30);
+        assert!(bucket.wait_for(1.0).is_zero());
+        assert!((bucket.tokens - 30.0).abs() < 1.0);
+        assert!(!bucket.is_full());
+
+        bucket.updated_at -= Duration::from_secs(60);
+        assert!(bucket.is_full());
+        assert_eq!(bucket.tokens, 60.0);
+    }
+
+    #[test]
+    fn requests_are_rejected_once_the_quota_is_used() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            daily_token_quota: Some(100),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert!(limiter.check(&subjects()).is_ok());
+        limiter.record(&subjects(), 60);
+        assert!(limiter.check(&subjects()).is_ok());
+        limiter.record(&subjects(), 60);
+
+        let denial = limiter.check(&subjects()).unwrap_err();
+        assert_eq!(denial.code, "insufficient_quota");
+        assert!(denial.retry_after <= SECONDS_PER_DAY);
+        assert!(limiter.check(&["key:other".to_string()]).is_ok());
+    }
+
+    #[test]
+    fn request_rate_is_limited_per_minute() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            requests_per_minute: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert!(limiter.check(&subjects()).is_ok());
+        assert!(limiter.check(&subjects()).is_ok());
+        let denial = limiter.check(&subjects()).unwrap_err();
+        assert_eq!(denial.code, "rate_limit_exceeded");
+        assert!(denial.retry_after >= 1);
+    }
+
+    #[test]
+    fn idle_subjects_are_evicted() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            tokens_per_minute: Some(1000),
+            ..Default::default()
+        })
+        .unwrap();
+        let busy = vec!["key:busy".to_string()];
+        let idle = vec!["key:idle".to_string()];
+        limiter.check(&busy).unwrap();
+        limiter.check(&idle).unwrap();
+        limiter.record(&busy, 100);
+
+        limiter.evict_idle();
+        let buckets = limiter.buckets.lock();
+        assert!(buckets.contains_key("key:busy"));
+        assert!(!buckets.contains_key("key:idle"));
+        let usage = limiter.usage.lock();
+        assert!(usage.contains_key("key:busy"));
+        assert!(!usage.contains_key("key:idle"));
+    }
+}
@@ -1,53 +1,179 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{
+    http::StatusCode, middleware, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer,
+    Responder, ResponseError,
+};
 use color_eyre::Result;
 use gadget_sdk::info;
+use std::future::Future;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
 
 use super::{
+    agent::{self, AgentMemory},
+    auth::{self, ApiKeyStore},
+    config::{api_base_url, ServerConfig},
+    error::{self, error_response},
     gaia_client::{APIError, GaiaNodeClient},
-    types::{ChatRequest, CreateImageRequest, EditImageRequest},
+    knowledge::{self, KnowledgeBase, KnowledgeConfig},
+    openai,
+    personas::{self, PersonaStore},
+    queue::{self, JobQueue},
+    rate_limit::{self, json_with_usage, RateLimiter, ReportsUsage, UsageMeter},
+    sessions::{self, SessionStore},
+    sse,
+    tools::{self, ToolRegistry},
+    types::{
+        ChatCompletionWithContext, ChatRequest, CreateImageRequest, EditImageRequest,
+        EmbeddingsRequest,
+    },
+    upstream,
 };
 
-struct AppState {
-    gaia_client: Arc<Mutex<GaiaNodeClient>>,
-    service_id: u64,
+/// Seconds between flushes of the rate limiter's quota counters.
+const RATE_LIMIT_PERSIST_INTERVAL: u64 = 30;
+
+/// Carries the JSON [`super::context::ContextReport`] of chats that cannot
+/// include it in their body.
+pub(super) const CONTEXT_REPORT_HEADER: &str = "X-Context-Report";
+
+pub(super) struct AppState {
+    pub(super) gaia_client: Arc<GaiaNodeClient>,
+    pub(super) tools: Arc<ToolRegistry>,
+    /// `tools` plus the agent's built-in tools.
+    pub(super) agent_tools: Arc<ToolRegistry>,
+    pub(super) agent_memory: Arc<AgentMemory>,
+    pub(super) knowledge: Arc<KnowledgeBase>,
+    /// `None` when authentication is disabled.
+    pub(super) api_keys: Option<Arc<ApiKeyStore>>,
+    /// `None` when no rate limits or quotas are configured.
+    pub(super) rate_limiter: Option<Arc<RateLimiter>>,
+    /// `None` when the agent registry is disabled.
+    pub(super) personas: Option<Arc<PersonaStore>>,
+    /// `None` when sessions are disabled.
+    pub(super) sessions: Option<Arc<SessionStore>>,
+    /// `None` when the job queue is disabled.
+    pub(super) jobs: Option<Arc<JobQueue>>,
+    pub(super) service_id: u64,
 }
 
 async fn handle_gaia_request<T, F, Fut, R>(
     app_state: web::Data<AppState>,
     request: web::Json<T>,
     operation: F,
-) -> impl Responder
+) -> HttpResponse
 where
-    F: FnOnce(Arc<Mutex<GaiaNodeClient>>, T) -> Fut,
+    F: FnOnce(Arc<GaiaNodeClient>, T) -> Fut,
     Fut: std::future::Future<Output = Result<R, APIError>>,
-    R: serde::Serialize,
+    R: serde::Serialize + ReportsUsage,
 {
     let gaia_client = app_state.gaia_client.clone();
     match operation(gaia_client, request.into_inner()).await {
-        Ok(response) => HttpResponse::Ok().json(response),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Error: {}", e)),
+        Ok(response) => json_with_usage(&response),
+        Err(e) => e.error_response(),
     }
 }
 
 async fn chat(
     app_state: web::Data<AppState>,
+    req: HttpRequest,
     chat_request: web::Json<ChatRequest>,
 ) -> impl Responder {
-    handle_gaia_request(app_state, chat_request, |client, request| async move {
-        let client = client.lock().await;
-        client.chat(request.messages).await
-    })
+    let mut request = chat_request.into_inner();
+    let registry = match personas::apply_to_chat(&app_state, &mut request) {
+        Ok(registry) => registry,
+        Err(e) => return e.error_response(),
+    };
+
+    if request.stream {
+        if request.run_tools {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                "invalid_parameters",
+                "run_tools cannot be combined with stream".to_string(),
+            );
+        }
+        let meter = req.extensions().get::<UsageMeter>().cloned();
+        return stream_chat(app_state, request, meter).await;
+    }
+
+    if request.run_tools {
+        return handle_gaia_request(
+            app_state,
+            web::Json(request),
+            |client, request| async move {
+                tools::run_tool_loop(
+                    &client,
+                    &registry,
+                    request.messages,
+                    &request.options,
+                    request.max_iterations,
+                )
+                .await
+            },
+        )
+        .await;
+    }
+
+    handle_gaia_request(
+        app_state,
+        web::Json(request),
+        |client, request| async move {
+            let (completion, context) = client
+                .chat_with_context(request.messages, &request.options)
+                .await?;
+            Ok(ChatCompletionWithContext {
+                completion,
+                context,
+            })
+        },
+    )
     .await
 }
 
+async fn stream_chat(
+    app_state: web::Data<AppState>,
+    request: ChatRequest,
+    meter: Option<UsageMeter>,
+) -> HttpResponse {
+    let prompt_tokens = app_state.gaia_client.estimate_tokens(&request.messages);
+    // The returned stream owns its connection and is dropped if the client goes away.
+    let upstream = app_state
+        .gaia_client
+        .chat_stream(request.messages, &request.options)
+        .await;
+
+    match upstream {
+        Ok((upstream, context)) => {
+            let mut response = HttpResponse::Ok();
+            response
+                .content_type("text/event-stream")
+                .insert_header(("Cache-Control", "no-cache"));
+            let prompt_tokens = context
+                .as_ref()
+                .map_or(prompt_tokens, |c| c.estimated_tokens_after);
+            // The summary is not part of the stream's usage, so it is charged now.
+            if let (Some(meter), Some(context)) = (&meter, &context) {
+                if context.summary_tokens > 0 {
+                    meter.record(context.summary_tokens);
+                }
+            }
+            // Headers are the only place for metadata ahead of the deltas.
+            if let Some(report) = context.and_then(|c| serde_json::to_string(&c).ok()) {
+                response.insert_header((CONTEXT_REPORT_HEADER, report));
+            }
+            response.streaming(sse::chat_completion_events(upstream, meter, prompt_tokens))
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
 async fn analyze_image(
     app_state: web::Data<AppState>,
     image_url: web::Json<String>,
 ) -> impl Responder {
     handle_gaia_request(app_state, image_url, |client, url| async move {
-        let client = client.lock().await;
         client.analyze_image(url).await
     })
     .await
@@ -58,7 +184,6 @@ async fn create_image(
     create_request: web::Json<CreateImageRequest>,
 ) -> impl Responder {
     handle_gaia_request(app_state, create_request, |client, request| async move {
-        let client = client.lock().await;
         client
             .create_image(
                 request.prompt,
@@ -77,7 +202,6 @@ async fn edit_image(
     edit_request: web::Json<EditImageRequest>,
 ) -> impl Responder {
     handle_gaia_request(app_state, edit_request, |client, request| async move {
-        let client = client.lock().await;
         client
             .edit_image(
                 request.image_path,
@@ -91,33 +215,234 @@ async fn edit_image(
     .await
 }
 
-pub async fn run_server(service_id: u64, model: String) -> Result<()> {
+async fn embeddings(
+    app_state: web::Data<AppState>,
+    embeddings_request: web::Json<EmbeddingsRequest>,
+) -> impl Responder {
+    handle_gaia_request(
+        app_state,
+        embeddings_request,
+        |client, request| async move {
+            client
+                .embeddings(request.input.into_vec(), &request.options)
+                .await
+        },
+    )
+    .await
+}
+
+/// Health and load of every upstream node, grouped by the model routed to it.
+///
+/// The default pool is listed first with a `null` model.
+async fn upstream_status(app_state: web::Data<AppState>) -> impl Responder {
+    let client = &app_state.gaia_client;
+    let mut pools = vec![serde_json::json!({
+        "model": null,
+        "kind": null,
+        "upstreams": client.pool().statuses(),
+    })];
+    pools.extend(
+        client
+            .routed_pools()
+            .into_iter()
+            .map(|(model, kind, pool)| {
+                serde_json::json!({
+                    "model": model,
+                    "kind": kind,
+                    "upstreams": pool.statuses(),
+                })
+            }),
+    );
+    HttpResponse::Ok().json(pools)
+}
+
+/// Runs the HTTP server until it stops on its own or `shutdown` resolves.
+///
+/// On shutdown the listener is closed and in-flight requests are given up to
+/// `config.shutdown_timeout` seconds to complete.
+pub async fn run_server(
+    service_id: u64,
+    config: ServerConfig,
+    tools: ToolRegistry,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let gaia_client = Arc::new(
+        GaiaNodeClient::with_upstreams(
+            &config.upstreams,
+            config.gaia_api_key.clone(),
+            config.default_model.clone(),
+            config.max_concurrency,
+            config.pool.clone(),
+        )?
+        .with_model_routes(&config.models)
+        .with_request_timeout(Duration::from_secs(config.request_timeout))
+        .with_context(config.context.clone()),
+    );
+    tokio::spawn(follow_node_public_url(
+        gaia_client.clone(),
+        crate::node_public_url(),
+    ));
+    let pools = std::iter::once(gaia_client.pool()).chain(
+        gaia_client
+            .routed_pools()
+            .into_iter()
+            .map(|(_, _, pool)| pool),
+    );
+    for pool in pools {
+        tokio::spawn(upstream::run_health_checks(
+            pool,
+            Duration::from_secs(config.health_check_interval),
+        ));
+    }
+
+    let api_keys = match &config.api_keys_file {
+        Some(path) => Some(Arc::new(ApiKeyStore::open(path)?)),
+        None => None,
+    };
+
+    let personas = match &config.agents_file {
+        Some(path) => Some(Arc::new(PersonaStore::open(path)?)),
+        None => None,
+    };
+
+    let sessions = match &config.sessions_db {
+        Some(path) => Some(Arc::new(SessionStore::open(path)?)),
+        None => None,
+    };
+
+    let jobs = match &config.jobs_db {
+        Some(path) => Some(Arc::new(JobQueue::open(
+            path,
+            config.max_queued_jobs,
+            config.webhook_secret.clone(),
+        )?)),
+        None => None,
+    };
+
+    let rate_limiter = if config.rate_limits.is_enabled() {
+        let limiter = Arc::new(RateLimiter::new(config.rate_limits.clone())?);
+        tokio::spawn(rate_limit::persist_periodically(
+            limiter.clone(),
+            Duration::from_secs(RATE_LIMIT_PERSIST_INTERVAL),
+        ));
+        Some(limiter)
+    } else {
+        None
+    };
+
+    let knowledge = Arc::new(KnowledgeBase::new(config.knowledge.clone()));
+    let agent_tools = agent::agent_tools(
+        &tools,
+        gaia_client.clone(),
+        knowledge.clone(),
+        &config.agent,
+    )?;
+    let agent_memory = AgentMemory::new(KnowledgeBase::new(KnowledgeConfig {
+        collection: config.agent.memory_collection.clone(),
+        ..config.knowledge.clone()
+    }));
+
     let app_state = web::Data::new(AppState {
-        gaia_client: Arc::new(Mutex::new(GaiaNodeClient::new(
-            "https://YOUR-NODE-ID.us.gaianet.network/v1".to_string(),
-            "".to_string(),
-            model,
-        ))),
+        gaia_client,
+        tools: Arc::new(tools),
+        agent_tools: Arc::new(agent_tools),
+        agent_memory: Arc::new(agent_memory),
+        knowledge,
+        api_keys,
+        rate_limiter: rate_limiter.clone(),
+        personas,
+        sessions,
+        jobs,
         service_id,
     });
 
+    if app_state.jobs.is_some() {
+        for _ in 0..config.job_workers {
+            tokio::spawn(queue::run_worker(app_state.clone()));
+        }
+    }
+
     info!(
-        "Starting server with base URL: {} and service ID: {}",
-        app_state.gaia_client.lock().await.base_url,
+        "Starting server on {}://{}:{} with base URL: {} ({} upstreams) and service ID: {}",
+        if config.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        },
+        config.host,
+        config.port,
+        app_state.gaia_client.base_url(),
+        config.upstreams.len(),
         app_state.service_id
     );
 
-    HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            .app_data(web::JsonConfig::default().error_handler(error::json_error_handler))
+            // Middleware runs in reverse registration order: request id, auth, then rate limits.
+            .wrap(middleware::from_fn(rate_limit::enforce_rate_limits))
+            .wrap(middleware::from_fn(auth::require_api_key))
+            .wrap(middleware::from_fn(error::assign_request_id))
             .route("/chat", web::post().to(chat))
             .route("/analyze_image", web::post().to(analyze_image))
             .route("/create_image", web::post().to(create_image))
             .route("/edit_image", web::post().to(edit_image))
+            .route("/embeddings", web::post().to(embeddings))
+            .route("/admin/upstreams", web::get().to(upstream_status))
+            .configure(openai::configure)
+            .configure(agent::configure)
+            .configure(auth::configure)
+            .configure(personas::configure)
+            .configure(knowledge::configure)
+            .configure(sessions::configure)
+            .configure(queue::configure)
     })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await?;
+    .shutdown_timeout(config.shutdown_timeout);
+
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+
+    let address = (config.host.as_str(), config.port);
+    let mut server = match &config.tls {
+        Some(tls) => server.bind_rustls_0_23(address, tls.load()?)?,
+        None => server.bind(address)?,
+    }
+    .run();
+    let handle = server.handle();
+
+    tokio::select! {
+        result = &mut server => result?,
+        _ = shutdown => {
+            info!("Shutting down server, draining in-flight requests");
+            // The server future must keep being polled while it drains.
+            let (_, result) = tokio::join!(handle.stop(true), &mut server);
+            result?;
+        }
+    }
+
+    if let Some(limiter) = rate_limiter {
+        limiter.persist()?;
+    }
 
     Ok(())
 }
+
+/// Points the client at the node's public URL, starting with the one
+/// recovered at startup and following each new one job 1 reports.
+async fn follow_node_public_url(
+    gaia_client: Arc<GaiaNodeClient>,
+    mut public_url: watch::Receiver<Option<String>>,
+) {
+    loop {
+        if let Some(url) = public_url.borrow_and_update().clone() {
+            let base_url = api_base_url(&url);
+            info!("Gaia node public URL changed, using base URL: {}", base_url);
+            gaia_client.set_base_url(base_url);
+        }
+        if public_url.changed().await.is_err() {
+            break;
+        }
+    }
+}
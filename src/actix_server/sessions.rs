@@ -0,0 +1,693 @@
+use actix_web::{http::StatusCode, web, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use color_eyre::{eyre::eyre, Result};
+use openai_dive::v1::resources::chat::{ChatCompletionResponse, ChatMessage, ChatMessageContent};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
+};
+use sled::Transactional;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{
+    auth::{ApiKey, Scope},
+    context::{ContextReport, ContextSummary},
+    error::error_response,
+    gaia_client::APIError,
+    personas,
+    rate_limit::{json_with_usage, ReportsUsage},
+    server::AppState,
+    types::ChatOptions,
+};
+
+/// A conversation whose history is kept on the server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    pub title: Option<String>,
+    /// Model used for turns that do not name one.
+    pub model: Option<String>,
+    /// Persona used for turns that do not name one.
+    pub agent: Option<String>,
+    /// Id of the API key that created the session, when authentication is on.
+    pub owner: Option<String>,
+    /// Session this one was copied from.
+    pub forked_from: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub messages: Vec<ChatMessage>,
+    /// Summary of the earliest messages, reused by later turns that do not
+    /// fit the context window.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_summary: Option<ContextSummary>,
+}
+
+/// Session metadata returned by the list endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionSummary {
+    pub id: String,
+    pub title: Option<String>,
+    pub model: Option<String>,
+    pub agent: Option<String>,
+    pub forked_from: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub message_count: usize,
+}
+
+impl From<&Session> for SessionSummary {
+    fn from(session: &Session) -> Self {
+        Self {
+            id: session.id.clone(),
+            title: session.title.clone(),
+            model: session.model.clone(),
+            agent: session.agent.clone(),
+            forked_from: session.forked_from.clone(),
+            created_at: session.created_at,
+            updated_at: session.updated_at,
+            message_count: session.messages.len(),
+        }
+    }
+}
+
+/// A session's entry in the summaries tree.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct IndexedSummary {
+    owner: Option<String>,
+    summary: SessionSummary,
+}
+
+impl Session {
+    /// Sessions without an owner are visible to everyone; admins see all.
+    fn visible_to(&self, key: Option<&ApiKey>) -> bool {
+        match (&self.owner, key) {
+            (None, _) => true,
+            (Some(_), Some(key)) if key.allows(Scope::Admin) => true,
+            (Some(owner), Some(key)) => *owner == key.id,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Sessions persisted in a sled database, keyed by session id.
+///
+/// Summaries and an index of sessions by owner are kept in their own trees,
+/// written in the same transaction as the session, so listing never reads
+/// message histories.
+pub struct SessionStore {
+    db: sled::Db,
+    /// Session id to [`IndexedSummary`].
+    summaries: sled::Tree,
+    /// `<owner>\0<session id>` keys, with an empty owner for unowned sessions.
+    owners: sled::Tree,
+}
+
+impl SessionStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = sled::open(path)
+            .map_err(|e| eyre!("Failed to open session store {}: {}", path.display(), e))?;
+        let store = Self {
+            summaries: db.open_tree("summaries")?,
+            owners: db.open_tree("owners")?,
+            db,
+        };
+
+        // Stores written before the index existed only hold full sessions.
+        if store.summaries.is_empty() && !store.db.is_empty() {
+            for bytes in store.db.iter().values() {
+                store.put(&serde_json::from_slice(&bytes?)?)?;
+            }
+        }
+        Ok(store)
+    }
+
+    fn put(&self, session: &Session) -> Result<()> {
+        (&*self.db, &self.summaries, &self.owners).transaction(
+            |(sessions, summaries, owners)| write_session(sessions, summaries, owners, session),
+        )?;
+        Ok(())
+    }
+
+    pub fn create(
+        &self,
+        title: Option<String>,
+        model: Option<String>,
+        agent: Option<String>,
+        system_prompt: Option<String>,
+        owner: Option<String>,
+    ) -> Result<Session> {
+        let now = now();
+        let session = Session {
+            id: uuid::Uuid::new_v4().to_string(),
+            title,
+            model,
+            agent,
+            owner,
+            forked_from: None,
+            created_at: now,
+            updated_at: now,
+            messages: system_prompt
+                .map(|prompt| ChatMessage::System {
+                    content: ChatMessageContent::Text(prompt),
+                    name: None,
+                })
+                .into_iter()
+                .collect(),
+            context_summary: None,
+        };
+        self.put(&session)?;
+        Ok(session)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<Session>> {
+        match self.db.get(id.as_bytes())? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Summaries of the sessions `key` may see, most recently updated first.
+    ///
+    /// Admins see every session; everyone else gets the unowned sessions and,
+    /// with a key, their own, found through the owner index.
+    pub fn list(&self, key: Option<&ApiKey>) -> Result<Vec<SessionSummary>> {
+        let mut entries = if key.is_some_and(|key| key.allows(Scope::Admin)) {
+            self.summaries
+                .iter()
+                .values()
+                .map(|bytes| Ok(serde_json::from_slice::<IndexedSummary>(&bytes?)?))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            let mut prefixes = vec![owner_prefix(None)];
+            prefixes.extend(key.map(|key| owner_prefix(Some(&key.id))));
+            let mut entries = Vec::new();
+            for prefix in prefixes {
+                for index_key in self.owners.scan_prefix(&prefix).keys() {
+                    let index_key = index_key?;
+                    let id = &index_key[prefix.len()..];
+                    if let Some(bytes) = self.summaries.get(id)? {
+                        entries.push(serde_json::from_slice::<IndexedSummary>(&bytes)?);
+                    }
+                }
+            }
+            entries
+        };
+        entries.sort_by(|a, b| b.summary.updated_at.cmp(&a.summary.updated_at));
+        Ok(entries.into_iter().map(|entry| entry.summary).collect())
+    }
+
+    /// Appends `messages` atomically, so concurrent turns never drop each other,
+    /// replacing the session's context summary when a new one is given.
+    pub fn append(
+        &self,
+        id: &str,
+        messages: &[ChatMessage],
+        summary: Option<&ContextSummary>,
+    ) -> Result<Option<Session>> {
+        let session = (&*self.db, &self.summaries, &self.owners).transaction(
+            |(sessions, summaries, owners)| {
+                let Some(bytes) = sessions.get(id.as_bytes())? else {
+                    return Ok(None);
+                };
+                let mut session: Session = serde_json::from_slice(&bytes).map_err(abort)?;
+                session.messages.extend_from_slice(messages);
+                if let Some(summary) = summary {
+                    session.context_summary = Some(summary.clone());
+                }
+                session.updated_at = now();
+                write_session(sessions, summaries, owners, &session)?;
+                Ok(Some(session))
+            },
+        )?;
+        Ok(session)
+    }
+
+    /// Copies a session, keeping its first `keep` messages (all by default).
+    pub fn fork(
+        &self,
+        source: &Session,
+        keep: Option<usize>,
+        owner: Option<String>,
+    ) -> Result<Session> {
+        let now = now();
+        let keep = keep.unwrap_or(source.messages.len());
+        let session = Session {
+            id: uuid::Uuid::new_v4().to_string(),
+            title: source.title.clone(),
+            model: source.model.clone(),
+            agent: source.agent.clone(),
+            owner,
+            forked_from: Some(source.id.clone()),
+            created_at: now,
+            updated_at: now,
+            messages: source.messages.iter().take(keep).cloned().collect(),
+            context_summary: None,
+        };
+        self.put(&session)?;
+        Ok(session)
+    }
+
+    pub fn delete(&self, id: &str) -> Result<bool> {
+        let deleted = (&*self.db, &self.summaries, &self.owners).transaction(
+            |(sessions, summaries, owners)| {
+                if let Some(bytes) = summaries.remove(id.as_bytes())? {
+                    let entry: IndexedSummary = serde_json::from_slice(&bytes).map_err(abort)?;
+                    owners.remove(owner_key(entry.owner.as_deref(), id))?;
+                }
+                Ok(sessions.remove(id.as_bytes())?.is_some())
+            },
+        )?;
+        Ok(deleted)
+    }
+}
+
+/// Writes a session along with its summary and owner index entry.
+fn write_session(
+    sessions: &TransactionalTree,
+    summaries: &TransactionalTree,
+    owners: &TransactionalTree,
+    session: &Session,
+) -> ConflictableTransactionResult<(), serde_json::Error> {
+    let entry = IndexedSummary {
+        owner: session.owner.clone(),
+        summary: SessionSummary::from(session),
+    };
+    sessions.insert(
+        session.id.as_bytes(),
+        serde_json::to_vec(session).map_err(abort)?,
+    )?;
+    summaries.insert(
+        session.id.as_bytes(),
+        serde_json::to_vec(&entry).map_err(abort)?,
+    )?;
+    owners.insert(owner_key(session.owner.as_deref(), &session.id), Vec::new())?;
+    Ok(())
+}
+
+fn abort(e: serde_json::Error) -> ConflictableTransactionError<serde_json::Error> {
+    ConflictableTransactionError::Abort(e)
+}
+
+/// Start of the owner index keys of `owner`'s sessions.
+fn owner_prefix(owner: Option<&str>) -> Vec<u8> {
+    format!("{}\0", owner.unwrap_or_default()).into_bytes()
+}
+
+fn owner_key(owner: Option<&str>, id: &str) -> Vec<u8> {
+    let mut key = owner_prefix(owner);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Deserialize)]
+struct CreateSessionRequest {
+    title: Option<String>,
+    model: Option<String>,
+    agent: Option<String>,
+    system_prompt: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SessionChatRequest {
+    content: String,
+    /// Overrides the session's persona for this turn.
+    agent: Option<String>,
+    #[serde(flatten)]
+    options: ChatOptions,
+}
+
+#[derive(Serialize)]
+struct SessionChatResponse {
+    session_id: String,
+    completion: ChatCompletionResponse,
+    /// What was left out of the history to fit the context window.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<ContextReport>,
+}
+
+impl ReportsUsage for SessionChatResponse {
+    fn total_tokens(&self) -> Option<u32> {
+        self.completion.total_tokens()
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct ForkSessionRequest {
+    /// Number of leading messages to copy; all of them when unset.
+    keep_messages: Option<usize>,
+}
+
+/// Registers the session endpoints under `/sessions`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/sessions")
+            .route("", web::get().to(list_sessions))
+            .route("", web::post().to(create_session))
+            .route("/{id}", web::get().to(get_session))
+            .route("/{id}", web::delete().to(delete_session))
+            .route("/{id}/chat", web::post().to(session_chat))
+            .route("/{id}/fork", web::post().to(fork_session)),
+    );
+}
+
+fn sessions_unavailable() -> HttpResponse {
+    error_response(
+        StatusCode::NOT_FOUND,
+        "invalid_request_error",
+        "sessions_disabled",
+        "Sessions are not enabled on this server".to_string(),
+    )
+}
+
+fn session_not_found(id: &str) -> HttpResponse {
+    error_response(
+        StatusCode::NOT_FOUND,
+        "invalid_request_error",
+        "session_not_found",
+        format!("No session with id {}", id),
+    )
+}
+
+fn internal_error(e: color_eyre::Report) -> HttpResponse {
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "api_error",
+        "internal_error",
+        e.to_string(),
+    )
+}
+
+fn api_key(req: &HttpRequest) -> Option<ApiKey> {
+    req.extensions().get::<ApiKey>().cloned()
+}
+
+/// Loads a session the caller is allowed to see, or the response to send.
+fn load_session(
+    store: &SessionStore,
+    req: &HttpRequest,
+    id: &str,
+) -> std::result::Result<Session, HttpResponse> {
+    match store.get(id) {
+        Ok(Some(session)) if session.visible_to(api_key(req).as_ref()) => Ok(session),
+        Ok(_) => Err(session_not_found(id)),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+async fn create_session(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+    request: web::Json<CreateSessionRequest>,
+) -> HttpResponse {
+    let Some(store) = &app_state.sessions else {
+        return sessions_unavailable();
+    };
+    let request = request.into_inner();
+    if let Some(agent) = &request.agent {
+        if let Err(e) = personas::resolve(&app_state, agent) {
+            return e.error_response();
+        }
+    }
+    let owner = api_key(&req).map(|key| key.id);
+    match store.create(
+        request.title,
+        request.model,
+        request.agent,
+        request.system_prompt,
+        owner,
+    ) {
+        Ok(session) => HttpResponse::Created().json(session),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn list_sessions(app_state: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    let Some(store) = &app_state.sessions else {
+        return sessions_unavailable();
+    };
+    match store.list(api_key(&req).as_ref()) {
+        Ok(summaries) => HttpResponse::Ok().json(summaries),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn get_session(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let Some(store) = &app_state.sessions else {
+        return sessions_unavailable();
+    };
+    match load_session(store, &req, &id) {
+        Ok(session) => HttpResponse::Ok().json(session),
+        Err(response) => response,
+    }
+}
+
+async fn delete_session(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let Some(store) = &app_state.sessions else {
+        return sessions_unavailable();
+    };
+    if let Err(response) = load_session(store, &req, &id) {
+        return response;
+    }
+    match store.delete(&id) {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => session_not_found(&id),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn fork_session(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    request: Option<web::Json<ForkSessionRequest>>,
+) -> HttpResponse {
+    let Some(store) = &app_state.sessions else {
+        return sessions_unavailable();
+    };
+    let source = match load_session(store, &req, &id) {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+    let request = request.map(web::Json::into_inner).unwrap_or_default();
+    let owner = api_key(&req).map(|key| key.id);
+    match store.fork(&source, request.keep_messages, owner) {
+        Ok(session) => HttpResponse::Created().json(session),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Sends a user turn with the session's history and stores both turns.
+async fn session_chat(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    request: web::Json<SessionChatRequest>,
+) -> HttpResponse {
+    let Some(store) = &app_state.sessions else {
+        return sessions_unavailable();
+    };
+    let session = match load_session(store, &req, &id) {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+
+    let request = request.into_inner();
+    let mut options = request.options;
+    if options.model.is_none() {
+        options.model = session.model.clone();
+    }
+    let user_message = ChatMessage::User {
+        content: ChatMessageContent::Text(request.content),
+        name: None,
+    };
+
+    let mut history = session.messages;
+    history.push(user_message.clone());
+    // The persona's prompt is sent with every turn but never stored.
+    if let Some(agent) = request.agent.or(session.agent) {
+        let persona = match personas::resolve(&app_state, &agent) {
+            Ok(persona) => persona,
+            Err(e) => return e.error_response(),
+        };
+        (history, options) = persona.apply(history, options);
+    }
+
+    // Only the prompt is shortened; the stored history stays complete, and
+    // the summary of its oldest turns is kept for the next ones.
+    let (completion, context) = match app_state
+        .gaia_client
+        .chat_with_summary(history, &options, session.context_summary.as_ref())
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => return e.error_response(),
+    };
+    let Some(reply) = completion
+        .choices
+        .first()
+        .map(|choice| choice.message.clone())
+    else {
+        return APIError::UnexpectedResponse("completion has no choices".to_string())
+            .error_response();
+    };
+
+    let summary = context
+        .as_ref()
+        .and_then(|context| context.summary.as_ref())
+        .filter(|&summary| session.context_summary.as_ref() != Some(summary));
+    match store.append(&session.id, &[user_message, reply], summary) {
+        Ok(Some(_)) => json_with_usage(&SessionChatResponse {
+            session_id: session.id,
+            completion,
+            context,
+        }),
+        // Deleted while the model was answering.
+        Ok(None) => session_not_found(&session.id),
+        Err(e) => internal_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: &str, scopes: &[Scope]) -> ApiKey {
+        ApiKey {
+            id: id.to_string(),
+            name: id.to_string(),
+            key_hash: String::new(),
+            scopes: scopes.to_vec(),
+            created_at: 0,
+            revoked: false,
+        }
+    }
+
+    fn ids(summaries: Vec<SessionSummary>) -> Vec<String> {
+        let mut ids: Vec<_> = summaries.into_iter().map(|summary| summary.id).collect();
+        ids.sort();
+        ids
+    }
+
+    fn sorted(ids: &[&String]) -> Vec<String> {
+        let mut ids: Vec<_> = ids.iter().map(|id| id.to_string()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn listing_follows_the_owner_index() {
+        let dir = std::env::temp_dir().join(format!("gaia-sessions-{}", uuid::Uuid::new_v4()));
+        let store = SessionStore::open(&dir).unwrap();
+        let shared = store.create(None, None, None, None, None).unwrap();
+        let alice = store
+            .create(None, None, None, None, Some("alice".to_string()))
+            .unwrap();
+        let bob = store
+            .create(None, None, None, None, Some("bob".to_string()))
+            .unwrap();
+
+        let alice_key = key("alice", &[Scope::Chat]);
+        let admin_key = key("admin", &[Scope::Admin]);
+        assert_eq!(ids(store.list(None).unwrap()), sorted(&[&shared.id]));
+        assert_eq!(
+            ids(store.list(Some(&alice_key)).unwrap()),
+            sorted(&[&shared.id, &alice.id])
+        );
+        assert_eq!(
+            ids(store.list(Some(&admin_key)).unwrap()),
+            sorted(&[&shared.id, &alice.id, &bob.id])
+        );
+
+        let message = ChatMessage::User {
+            content: ChatMessageContent::Text("hi".to_string()),
+            name: None,
+        };
+        store.append(&alice.id, &[message], None).unwrap().unwrap();
+        let listed = store.list(Some(&alice_key)).unwrap();
+        let summary = listed.iter().find(|s| s.id == alice.id).unwrap();
+        assert_eq!(summary.message_count, 1);
+
+        assert!(store.delete(&alice.id).unwrap());
+        assert!(!store.delete(&alice.id).unwrap());
+        assert_eq!(
+            ids(store.list(Some(&alice_key)).unwrap()),
+            sorted(&[&shared.id])
+        );
+        assert!(store.append(&alice.id, &[], None).unwrap().is_none());
+
+        drop(store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stores_without_an_index_are_indexed_on_open() {
+        let dir = std::env::temp_dir().join(format!("gaia-sessions-{}", uuid::Uuid::new_v4()));
+        let session = {
+            let store = SessionStore::open(&dir).unwrap();
+            let session = store
+                .create(None, None, None, None, Some("alice".to_string()))
+                .unwrap();
+            store.summaries.clear().unwrap();
+            store.owners.clear().unwrap();
+            store.db.flush().unwrap();
+            session
+        };
+
+        let store = SessionStore::open(&dir).unwrap();
+        let listed = store.list(Some(&key("alice", &[Scope::Chat]))).unwrap();
+        assert_eq!(ids(listed), sorted(&[&session.id]));
+
+        drop(store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn context_summaries_are_kept_until_replaced() {
+        let dir = std::env::temp_dir().join(format!("gaia-sessions-{}", uuid::Uuid::new_v4()));
+        let store = SessionStore::open(&dir).unwrap();
+        let session = store.create(None, None, None, None, None).unwrap();
+        let message = || ChatMessage::User {
+            content: ChatMessageContent::Text("hi".to_string()),
+            name: None,
+        };
+        let summary = ContextSummary {
+            messages: 1,
+            text: "Said hi.".to_string(),
+        };
+
+        store
+            .append(&session.id, &[message()], Some(&summary))
+            .unwrap()
+            .unwrap();
+        let session = store
+            .append(&session.id, &[message()], None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.context_summary, Some(summary));
+        assert_eq!(
+            store.get(&session.id).unwrap().unwrap().context_summary,
+            session.context_summary
+        );
+
+        let fork = store.fork(&session, None, None).unwrap();
+        assert!(fork.context_summary.is_none());
+
+        drop(store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
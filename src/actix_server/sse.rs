@@ -0,0 +1,222 @@
+use actix_web::web::Bytes;
+use futures::{stream, Stream, StreamExt};
+use std::convert::Infallible;
+
+use super::{
+    error::{current_request_id, ErrorBody},
+    gaia_client::ChatCompletionStream,
+    rate_limit::UsageMeter,
+};
+
+/// Rough characters-per-token ratio used when upstream reports no usage.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Terminal frame sent once the upstream stream is exhausted.
+const DONE_FRAME: &[u8] = b"data: [DONE]\n\n";
+
+/// Converts an upstream chat completion stream into Server-Sent Events frames.
+///
+/// Every delta is forwarded as a `data:` frame. The stream ends with
+/// `data: [DONE]` on success, or with a single `event: error` frame if the
+/// upstream fails mid-stream.
+///
+/// When `meter` is set, the stream's tokens are charged once it ends or the
+/// client disconnects: the usage upstream reports in its final chunk if any,
+/// otherwise `prompt_tokens` plus an estimate from the streamed content.
+pub fn chat_completion_events(
+    upstream: ChatCompletionStream,
+    meter: Option<UsageMeter>,
+    prompt_tokens: u32,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    // The body is polled after the handler returns, outside the request-id scope.
+    let request_id = current_request_id();
+    let usage = StreamUsage {
+        meter,
+        prompt_tokens,
+        completion_chars: 0,
+        reported: None,
+    };
+    stream::unfold(Some((upstream, usage)), move |state| {
+        let request_id = request_id.clone();
+        async move {
+            let (mut upstream, mut usage) = state?;
+            let frame = match upstream.next().await {
+                Some(Ok(chunk)) => match serde_json::to_value(&chunk) {
+                    Ok(json) => {
+                        usage.observe(&json);
+                        return Some((data_frame(&json.to_string()), Some((upstream, usage))));
+                    }
+                    Err(e) => error_frame("internal_error", &e.to_string(), request_id),
+                },
+                Some(Err(e)) => error_frame("upstream_stream_error", &e.to_string(), request_id),
+                None => Bytes::from_static(DONE_FRAME),
+            };
+            Some((frame, None))
+        }
+    })
+    .map(Ok)
+}
+
+/// Tracks what a stream has consumed and charges it when dropped.
+struct StreamUsage {
+    meter: Option<UsageMeter>,
+    prompt_tokens: u32,
+    completion_chars: usize,
+    reported: Option<u32>,
+}
+
+impl StreamUsage {
+    fn observe(&mut self, chunk: &serde_json::Value) {
+        if let Some(total) = chunk["usage"]["total_tokens"].as_u64() {
+            self.reported = Some(total as u32);
+        }
+        if let Some(choices) = chunk["choices"].as_array() {
+            self.completion_chars += choices
+                .iter()
+                .filter_map(|choice| choice["delta"]["content"].as_str())
+                .map(str::len)
+                .sum::<usize>();
+        }
+    }
+
+    fn total_tokens(&self) -> u32 {
+        self.reported.unwrap_or_else(|| {
+            let completion = (self.completion_chars / CHARS_PER_TOKEN) as u32;
+            self.prompt_tokens + completion
+        })
+    }
+}
+
+impl Drop for StreamUsage {
+    fn drop(&mut self) {
+        if let Some(meter) = self.meter.take() {
+            meter.record(self.total_tokens());
+        }
+    }
+}
+
+fn data_frame(json: &str) -> Bytes {
+    Bytes::from(format!("data: {}\n\n", json))
+}
+
+/// Builds an `event: error` frame for failures that happen after headers were sent.
+pub fn error_frame(code: &'static str, message: &str, request_id: Option<String>) -> Bytes {
+    let mut body = ErrorBody::new("upstream_error", code, message.to_string());
+    body.error.request_id = request_id;
+    let json = serde_json::to_string(&body).unwrap_or_else(|_| "{}".to_string());
+    Bytes::from(format!("event: error\ndata: {}\n\n", json))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actix_server::gaia_client::GaiaNodeClient;
+    use openai_dive::v1::resources::chat::{
+        ChatCompletionParametersBuilder, ChatMessage, ChatMessageContent,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const HEL: &str = r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1700000000,"model":"mock","system_fingerprint":null,"choices":[{"index":0,"delta":{"role":"assistant","content":"Hel"},"logprobs":null,"finish_reason":null}]}"#;
+    const LO: &str = r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1700000000,"model":"mock","system_fingerprint":null,"choices":[{"index":0,"delta":{"role":"assistant","content":"lo"},"logprobs":null,"finish_reason":"stop"}]}"#;
+
+    /// Serves `body` as a `text/event-stream` response to every request and
+    /// returns the base URL of the mock node.
+    async fn mock_upstream(body: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let body = body.clone();
+                tokio::spawn(async move {
+                    read_request(&mut socket).await;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\
+                         cache-control: no-cache\r\nconnection: close\r\n\r\n{}",
+                        body
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                    let _ = socket.shutdown().await;
+                });
+            }
+        });
+        format!("http://{}/v1", addr)
+    }
+
+    /// Reads the request head and its `content-length` body.
+    async fn read_request(socket: &mut tokio::net::TcpStream) {
+        let mut request = Vec::new();
+        let mut buffer = [0; 4096];
+        loop {
+            let Ok(read) = socket.read(&mut buffer).await else {
+                return;
+            };
+            if read == 0 {
+                return;
+            }
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Streams a chat completion from a mock node serving `body` and returns
+    /// the SSE frames sent to the client.
+    async fn relay(body: String) -> Vec<String> {
+        let base_url = mock_upstream(body).await;
+        let client =
+            GaiaNodeClient::new(base_url, "key".to_string(), "mock".to_string(), 1).unwrap();
+        let parameters = ChatCompletionParametersBuilder::default()
+            .model("mock")
+            .messages(vec![ChatMessage::User {
+                content: ChatMessageContent::Text("Hi".to_string()),
+                name: None,
+            }])
+            .build()
+            .unwrap();
+        let upstream = client.chat_completion_stream(parameters).await.unwrap();
+
+        chat_completion_events(upstream, None, 0)
+            .map(|frame| String::from_utf8(frame.unwrap().to_vec()).unwrap())
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn deltas_are_forwarded_and_terminated() {
+        let frames = relay(format!("data: {}\n\ndata: {}\n\ndata: [DONE]\n\n", HEL, LO)).await;
+
+        assert_eq!(frames.len(), 3, "{:?}", frames);
+        for (frame, content) in frames.iter().zip(["Hel", "lo"]) {
+            let json = frame
+                .strip_prefix("data: ")
+                .and_then(|frame| frame.strip_suffix("\n\n"))
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_str(json).unwrap();
+            assert_eq!(json["choices"][0]["delta"]["content"], content);
+        }
+        assert_eq!(frames[2].as_bytes(), DONE_FRAME);
+    }
+
+    #[tokio::test]
+    async fn upstream_errors_end_the_stream_with_an_error_frame() {
+        let frames = relay(format!("data: {}\n\ndata: {{\"broken\n\n", HEL)).await;
+
+        assert_eq!(frames.len(), 2, "{:?}", frames);
+        assert!(frames[0].starts_with("data: "));
+        assert!(frames[1].starts_with("event: error\ndata: "));
+        assert!(frames[1].contains("upstream_stream_error"));
+    }
+}
@@ -0,0 +1,491 @@
+use async_trait::async_trait;
+use openai_dive::v1::resources::chat::{
+    ChatCompletionResponse, ChatCompletionTool, ChatMessage, ToolCall,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use thiserror::Error;
+
+use super::{
+    context::{ContextReport, ContextSummary},
+    gaia_client::{APIError, GaiaNodeClient},
+    types::ChatOptions,
+};
+
+/// Number of model round-trips allowed when the request does not set one.
+pub const DEFAULT_MAX_ITERATIONS: u32 = 8;
+
+/// Hard upper bound on model round-trips, regardless of what the request asks for.
+pub const MAX_ITERATIONS_LIMIT: u32 = 32;
+
+#[derive(Error, Debug)]
+pub enum ToolError {
+    #[error("Invalid arguments: {0}")]
+    InvalidArguments(String),
+
+    #[error("Tool execution failed: {0}")]
+    Execution(String),
+}
+
+/// A tool implemented in-process that the model can call during a chat.
+///
+/// # Example
+///
+/// ```ignore
+/// struct Echo;
+///
+/// #[async_trait::async_trait]
+/// impl ToolHandler for Echo {
+///     fn definition(&self) -> ChatCompletionTool {
+///         ChatCompletionTool {
+///             r#type: ChatCompletionToolType::Function,
+///             function: ChatCompletionFunction {
+///                 name: "echo".to_string(),
+///                 description: Some("Echoes its input".to_string()),
+///                 parameters: serde_json::json!({
+///                     "type": "object",
+///                     "properties": { "text": { "type": "string" } },
+///                 }),
+///             },
+///         }
+///     }
+///
+///     async fn call(&self, arguments: serde_json::Value) -> Result<String, ToolError> {
+///         Ok(arguments["text"].as_str().unwrap_or_default().to_string())
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait ToolHandler: Send + Sync {
+    /// The function definition advertised to the model.
+    fn definition(&self) -> ChatCompletionTool;
+
+    /// Executes the tool with the JSON arguments produced by the model.
+    async fn call(&self, arguments: serde_json::Value) -> Result<String, ToolError>;
+}
+
+/// Tool handlers available to the server-side agent loop, keyed by function name.
+#[derive(Default, Clone)]
+pub struct ToolRegistry {
+    handlers: HashMap<String, Arc<dyn ToolHandler>>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler under the function name from its definition,
+    /// replacing any previous handler with the same name.
+    pub fn register<H: ToolHandler + 'static>(&mut self, handler: H) -> &mut Self {
+        let name = handler.definition().function.name;
+        self.handlers.insert(name, Arc::new(handler));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn ToolHandler>> {
+        self.handlers.get(name).cloned()
+    }
+
+    /// A registry with only the handlers named in `names`.
+    pub fn restricted_to(&self, names: &[String]) -> Self {
+        Self {
+            handlers: self
+                .handlers
+                .iter()
+                .filter(|(name, _)| names.contains(name))
+                .map(|(name, handler)| (name.clone(), handler.clone()))
+                .collect(),
+        }
+    }
+
+    /// A registry without the handler called `name`.
+    pub fn without(&self, name: &str) -> Self {
+        let mut registry = self.clone();
+        registry.handlers.remove(name);
+        registry
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// Definitions of every registered tool, in the form sent upstream.
+    pub fn definitions(&self) -> Vec<ChatCompletionTool> {
+        self.handlers
+            .values()
+            .map(|handler| handler.definition())
+            .collect()
+    }
+}
+
+/// A tool call made by the model and executed by the server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExecutedToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+    pub output: String,
+    pub is_error: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolLoopResponse {
+    /// The final completion returned by the model.
+    pub completion: ChatCompletionResponse,
+    /// Number of model round-trips that were made.
+    pub iterations: u32,
+    /// Tokens used across all round-trips.
+    pub total_tokens: u32,
+    pub tool_calls: Vec<ExecutedToolCall>,
+    /// What was left out of the last round-trip that did not fit the context
+    /// window.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<ContextReport>,
+}
+
+/// Runs chat completions, executing registered tools, until the model answers.
+///
+/// Registered tool definitions are merged into `options.tools`. Each time the
+/// model responds with tool calls, the matching handlers run and their outputs
+/// are appended as `tool` messages before asking the model again. If the model
+/// calls a tool that is not registered, the response is returned as-is so the
+/// caller can execute it client-side.
+///
+/// # Errors
+///
+/// Returns [`APIError::MaxIterationsExceeded`] if the model still requests
+/// tools after `max_iterations` round-trips.
+pub async fn run_tool_loop(
+    client: &GaiaNodeClient,
+    registry: &ToolRegistry,
+    messages: Vec<ChatMessage>,
+    options: &ChatOptions,
+    max_iterations: Option<u32>,
+) -> Result<ToolLoopResponse, APIError> {
+    drive_tool_loop(
+        registry,
+        messages,
+        options,
+        max_iterations,
+        |messages, options, summary| async move {
+            client
+                .chat_with_summary(messages, &options, summary.as_ref())
+                .await
+        },
+    )
+    .await
+}
+
+/// [`run_tool_loop`] with the model behind `chat`, which is given the
+/// messages, options and cached summary of each round-trip.
+async fn drive_tool_loop<F, Fut>(
+    registry: &ToolRegistry,
+    mut messages: Vec<ChatMessage>,
+    options: &ChatOptions,
+    max_iterations: Option<u32>,
+    mut chat: F,
+) -> Result<ToolLoopResponse, APIError>
+where
+    F: FnMut(Vec<ChatMessage>, ChatOptions, Option<ContextSummary>) -> Fut,
+    Fut: Future<Output = Result<(ChatCompletionResponse, Option<ContextReport>), APIError>>,
+{
+    let max_iterations = max_iterations
+        .unwrap_or(DEFAULT_MAX_ITERATIONS)
+        .clamp(1, MAX_ITERATIONS_LIMIT);
+
+    let mut options = options.clone();
+    let mut tools = options.tools.take().unwrap_or_default();
+    for definition in registry.definitions() {
+        if !tools
+            .iter()
+            .any(|tool| tool.function.name == definition.function.name)
+        {
+            tools.push(definition);
+        }
+    }
+    options.tools = (!tools.is_empty()).then_some(tools);
+
+    let mut executed = Vec::new();
+    let mut total_tokens = 0;
+    let mut context: Option<ContextReport> = None;
+    for iteration in 1..=max_iterations {
+        // Messages only grow, so an earlier summary still covers their start.
+        let summary = context.as_ref().and_then(|context| context.summary.clone());
+        let (completion, report) = chat(messages.clone(), options.clone(), summary).await?;
+        context = report.or(context);
+        total_tokens += completion
+            .usage
+            .as_ref()
+            .map_or(0, |usage| usage.total_tokens);
+
+        let tool_calls = match completion.choices.first().map(|choice| &choice.message) {
+            Some(ChatMessage::Assistant {
+                tool_calls: Some(tool_calls),
+                ..
+            }) if !tool_calls.is_empty() => tool_calls.clone(),
+            _ => {
+                return Ok(ToolLoopResponse {
+                    completion,
+                    iterations: iteration,
+                    total_tokens,
+                    tool_calls: executed,
+                    context,
+                })
+            }
+        };
+
+        if tool_calls
+            .iter()
+            .any(|call| registry.get(&call.function.name).is_none())
+        {
+            return Ok(ToolLoopResponse {
+                completion,
+                iterations: iteration,
+                total_tokens,
+                tool_calls: executed,
+                context,
+            });
+        }
+
+        messages.push(completion.choices[0].message.clone());
+        for call in tool_calls {
+            let result = execute_tool_call(registry, &call).await;
+            messages.push(ChatMessage::Tool {
+                content: result.output.clone(),
+                tool_call_id: result.id.clone(),
+            });
+            executed.push(result);
+        }
+    }
+
+    Err(APIError::MaxIterationsExceeded(max_iterations))
+}
+
+/// Executes a single tool call, turning handler failures into an error
+/// message the model can read rather than aborting the loop.
+async fn execute_tool_call(registry: &ToolRegistry, call: &ToolCall) -> ExecutedToolCall {
+    let name = call.function.name.clone();
+    let result = match registry.get(&name) {
+        Some(handler) => match serde_json::from_str(&call.function.arguments) {
+            Ok(arguments) => handler.call(arguments).await,
+            Err(e) => Err(ToolError::InvalidArguments(e.to_string())),
+        },
+        None => Err(ToolError::Execution(format!("Unknown tool: {}", name))),
+    };
+
+    let (output, is_error) = match result {
+        Ok(output) => (output, false),
+        Err(e) => (format!("Error: {}", e), true),
+    };
+
+    ExecutedToolCall {
+        id: call.id.clone(),
+        name,
+        arguments: call.function.arguments.clone(),
+        output,
+        is_error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openai_dive::v1::resources::chat::{
+        ChatCompletionFunction, ChatCompletionToolType, ChatMessageContent,
+    };
+    use parking_lot::Mutex;
+    use serde_json::{json, Value};
+
+    struct Echo;
+
+    #[async_trait]
+    impl ToolHandler for Echo {
+        fn definition(&self) -> ChatCompletionTool {
+            tool("echo", "Echoes its input")
+        }
+
+        async fn call(&self, arguments: Value) -> Result<String, ToolError> {
+            Ok(arguments["text"].as_str().unwrap_or_default().to_string())
+        }
+    }
+
+    fn tool(name: &str, description: &str) -> ChatCompletionTool {
+        ChatCompletionTool {
+            r#type: ChatCompletionToolType::Function,
+            function: ChatCompletionFunction {
+                name: name.to_string(),
+                description: Some(description.to_string()),
+                parameters: json!({ "type": "object" }),
+            },
+        }
+    }
+
+    fn registry() -> ToolRegistry {
+        let mut registry = ToolRegistry::new();
+        registry.register(Echo);
+        registry
+    }
+
+    fn completion(message: Value) -> ChatCompletionResponse {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "mock",
+            "system_fingerprint": null,
+            "choices": [{
+                "index": 0,
+                "message": message,
+                "finish_reason": "stop",
+                "logprobs": null,
+            }],
+            "usage": { "prompt_tokens": 5, "completion_tokens": 5, "total_tokens": 10 },
+        }))
+        .unwrap()
+    }
+
+    fn answer(text: &str) -> ChatCompletionResponse {
+        completion(json!({ "role": "assistant", "content": text }))
+    }
+
+    fn call(name: &str, arguments: &str) -> ChatCompletionResponse {
+        completion(json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": name, "arguments": arguments },
+            }],
+        }))
+    }
+
+    fn user(text: &str) -> Vec<ChatMessage> {
+        vec![ChatMessage::User {
+            content: ChatMessageContent::Text(text.to_string()),
+            name: None,
+        }]
+    }
+
+    /// Runs the loop against a model replying with `replies` in turn, and
+    /// returns the result along with every request the model received.
+    async fn run(
+        options: &ChatOptions,
+        max_iterations: Option<u32>,
+        replies: Vec<ChatCompletionResponse>,
+    ) -> (
+        Result<ToolLoopResponse, APIError>,
+        Vec<(Vec<ChatMessage>, ChatOptions)>,
+    ) {
+        let requests = Mutex::new(Vec::new());
+        let result = drive_tool_loop(
+            &registry(),
+            user("hi"),
+            options,
+            max_iterations,
+            |messages, options, _| {
+                let mut requests = requests.lock();
+                let reply = replies[requests.len().min(replies.len() - 1)].clone();
+                requests.push((messages, options));
+                async move { Ok((reply, None)) }
+            },
+        )
+        .await;
+        (result, requests.into_inner())
+    }
+
+    #[tokio::test]
+    async fn tool_results_are_sent_back_to_the_model() {
+        let (result, requests) = run(
+            &ChatOptions::default(),
+            None,
+            vec![call("echo", r#"{"text":"pong"}"#), answer("done")],
+        )
+        .await;
+
+        let response = result.unwrap();
+        assert_eq!(response.iterations, 2);
+        assert_eq!(response.total_tokens, 20);
+        assert_eq!(response.tool_calls.len(), 1);
+        assert_eq!(response.tool_calls[0].output, "pong");
+        assert!(!response.tool_calls[0].is_error);
+        assert!(matches!(
+            requests[1].0.last(),
+            Some(ChatMessage::Tool { content, .. }) if content == "pong"
+        ));
+    }
+
+    #[tokio::test]
+    async fn request_tools_take_precedence_over_registered_ones() {
+        let options = ChatOptions {
+            tools: Some(vec![
+                tool("echo", "Defined by the request"),
+                tool("search", "Run by the client"),
+            ]),
+            ..Default::default()
+        };
+        let (result, requests) = run(&options, None, vec![answer("done")]).await;
+
+        assert!(result.is_ok());
+        let tools = requests[0].1.tools.clone().unwrap();
+        assert_eq!(tools.len(), 2);
+        let echo = tools
+            .iter()
+            .find(|tool| tool.function.name == "echo")
+            .unwrap();
+        assert_eq!(
+            echo.function.description.as_deref(),
+            Some("Defined by the request")
+        );
+    }
+
+    #[tokio::test]
+    async fn unregistered_tool_calls_are_returned_to_the_caller() {
+        let (result, requests) =
+            run(&ChatOptions::default(), None, vec![call("search", "{}")]).await;
+
+        let response = result.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(response.iterations, 1);
+        assert!(response.tool_calls.is_empty());
+        assert!(matches!(
+            &response.completion.choices[0].message,
+            ChatMessage::Assistant { tool_calls: Some(calls), .. }
+                if calls[0].function.name == "search"
+        ));
+    }
+
+    #[tokio::test]
+    async fn loops_stop_after_max_iterations() {
+        let (result, requests) = run(
+            &ChatOptions::default(),
+            Some(2),
+            vec![call("echo", r#"{"text":"again"}"#)],
+        )
+        .await;
+
+        assert!(matches!(result, Err(APIError::MaxIterationsExceeded(2))));
+        assert_eq!(requests.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn invalid_arguments_become_error_output() {
+        let call: ToolCall = serde_json::from_value(json!({
+            "id": "call_1",
+            "type": "function",
+            "function": { "name": "echo", "arguments": "{not json" },
+        }))
+        .unwrap();
+
+        let result = execute_tool_call(&registry(), &call).await;
+        assert!(result.is_error);
+        assert!(result.output.starts_with("Error: Invalid arguments"));
+
+        let result = execute_tool_call(&ToolRegistry::new(), &call).await;
+        assert!(result.is_error);
+        assert!(result.output.contains("Unknown tool: echo"));
+    }
+}
@@ -1,12 +1,135 @@
 use openai_dive::v1::resources::{
-    chat::ChatMessage,
+    chat::{ChatCompletionResponse, ChatCompletionTool, ChatCompletionToolChoice, ChatMessage},
     image::{ImageQuality, ImageSize, ImageStyle},
+    shared::Usage,
 };
 use serde::{Deserialize, Serialize};
 
+use super::{
+    context::{ContextReport, ContextStrategy},
+    gaia_client::APIError,
+};
+
+/// Maximum number of stop sequences accepted by OpenAI-compatible backends.
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// Maximum number of inputs in one embeddings request, as in the OpenAI API.
+pub const MAX_EMBEDDING_INPUTS: usize = 2048;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatRequest {
     pub messages: Vec<ChatMessage>,
+    /// Forward token deltas as Server-Sent Events instead of a single response.
+    #[serde(default)]
+    pub stream: bool,
+    /// Execute registered server-side tools and feed their results back to
+    /// the model until it produces a final answer.
+    #[serde(default)]
+    pub run_tools: bool,
+    /// Maximum model round-trips when `run_tools` is set.
+    pub max_iterations: Option<u32>,
+    /// Persona whose system prompt, defaults and tools apply to the request.
+    pub agent: Option<String>,
+    #[serde(flatten)]
+    pub options: ChatOptions,
+}
+
+/// Sampling and formatting options for a chat completion.
+///
+/// Every field is optional; unset fields are left out of the upstream request
+/// so the node's own defaults apply.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChatOptions {
+    /// Overrides the client's default model for this request.
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<u32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    #[serde(default)]
+    pub response_format: ChatResponseFormat,
+    pub tools: Option<Vec<ChatCompletionTool>>,
+    pub tool_choice: Option<ChatCompletionToolChoice>,
+    /// Overrides the configured strategy for fitting the context window.
+    pub context_strategy: Option<ContextStrategy>,
+}
+
+/// A chat completion with a report of what was left out of the request to
+/// fit the context window, if anything was.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatCompletionWithContext {
+    #[serde(flatten)]
+    pub completion: ChatCompletionResponse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<ContextReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatResponseFormat {
+    #[default]
+    Text,
+    JsonObject,
+}
+
+impl ChatOptions {
+    /// Checks every set option against the ranges accepted by the OpenAI API.
+    pub fn validate(&self) -> Result<(), APIError> {
+        if let Some(model) = &self.model {
+            if model.trim().is_empty() {
+                return Err(APIError::InvalidParameters(
+                    "model must not be empty".to_string(),
+                ));
+            }
+        }
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                return Err(APIError::InvalidParameters(format!(
+                    "temperature must be between 0.0 and 2.0, got {}",
+                    temperature
+                )));
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if top_p <= 0.0 || top_p > 1.0 {
+                return Err(APIError::InvalidParameters(format!(
+                    "top_p must be greater than 0.0 and at most 1.0, got {}",
+                    top_p
+                )));
+            }
+        }
+        if self.max_tokens == Some(0) {
+            return Err(APIError::InvalidParameters(
+                "max_tokens must be greater than 0".to_string(),
+            ));
+        }
+        if let Some(stop) = &self.stop {
+            if stop.len() > MAX_STOP_SEQUENCES {
+                return Err(APIError::InvalidParameters(format!(
+                    "At most {} stop sequences are allowed, got {}",
+                    MAX_STOP_SEQUENCES,
+                    stop.len()
+                )));
+            }
+        }
+        for (name, penalty) in [
+            ("presence_penalty", self.presence_penalty),
+            ("frequency_penalty", self.frequency_penalty),
+        ] {
+            if let Some(penalty) = penalty {
+                if !(-2.0..=2.0).contains(&penalty) {
+                    return Err(APIError::InvalidParameters(format!(
+                        "{} must be between -2.0 and 2.0, got {}",
+                        name, penalty
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -31,3 +154,87 @@ pub struct EditImageRequest {
     pub n: u32,
     pub size: ImageSize,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingsRequest {
+    pub input: EmbeddingsInput,
+    #[serde(flatten)]
+    pub options: EmbeddingOptions,
+}
+
+/// A single text or a batch of texts to embed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum EmbeddingsInput {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+impl EmbeddingsInput {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            EmbeddingsInput::Single(input) => vec![input],
+            EmbeddingsInput::Batch(inputs) => inputs,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EmbeddingOptions {
+    /// Overrides the default embedding model for this request.
+    pub model: Option<String>,
+    #[serde(default)]
+    pub encoding_format: EmbeddingEncoding,
+    /// Requested vector size, for models that support shortening embeddings.
+    pub dimensions: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingEncoding {
+    #[default]
+    Float,
+    /// Little-endian `f32` values encoded as base64, as returned by OpenAI.
+    Base64,
+}
+
+impl EmbeddingOptions {
+    pub fn validate(&self) -> Result<(), APIError> {
+        if let Some(model) = &self.model {
+            if model.trim().is_empty() {
+                return Err(APIError::InvalidParameters(
+                    "model must not be empty".to_string(),
+                ));
+            }
+        }
+        if self.dimensions == Some(0) {
+            return Err(APIError::InvalidParameters(
+                "dimensions must be greater than 0".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingsResponse {
+    pub model: String,
+    /// Length of every returned vector.
+    pub dimensions: usize,
+    /// One entry per input, in input order.
+    pub data: Vec<EmbeddingVector>,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingVector {
+    pub index: u32,
+    pub embedding: EmbeddingValue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum EmbeddingValue {
+    Float(Vec<f32>),
+    Base64(String),
+}
@@ -0,0 +1,499 @@
+use gadget_sdk::info;
+use openai_dive::v1::api::Client;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long a single active health check may take.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A Gaia node given as `URL` or `URL;weight=N`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamSpec {
+    pub url: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl FromStr for UpstreamSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (url, weight) = match s.split_once(";weight=") {
+            Some((url, weight)) => (
+                url,
+                weight
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid weight in upstream {}", s))?,
+            ),
+            None => (s, 1),
+        };
+        if weight == 0 {
+            return Err(format!("Upstream weight must be greater than 0: {}", s));
+        }
+        url::Url::parse(url).map_err(|e| format!("Invalid upstream URL {}: {}", url, e))?;
+        Ok(Self {
+            url: url.trim_end_matches('/').to_string(),
+            weight,
+        })
+    }
+}
+
+/// What a routed model is used for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelKind {
+    #[default]
+    Chat,
+    Embedding,
+    /// Chat model that accepts images, used by `/analyze_image`.
+    Vision,
+    Image,
+}
+
+/// Sends requests naming `model` to their own set of nodes.
+///
+/// The first route of each kind is also the default for endpoints that do
+/// not name a model, such as `/create_image`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelRoute {
+    pub model: String,
+    #[serde(default)]
+    pub kind: ModelKind,
+    pub upstreams: Vec<UpstreamSpec>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BalancingStrategy {
+    /// Spread requests proportionally to each node's weight.
+    #[default]
+    WeightedRoundRobin,
+    /// Send each request to the node with the fewest requests in flight.
+    LeastInFlight,
+}
+
+impl FromStr for BalancingStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "weighted_round_robin" => Ok(Self::WeightedRoundRobin),
+            "least_in_flight" => Ok(Self::LeastInFlight),
+            _ => Err(format!(
+                "Invalid balancing strategy: {}. Must be either 'weighted_round_robin' or 'least_in_flight'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PoolSettings {
+    pub strategy: BalancingStrategy,
+    /// Consecutive failures after which a node is ejected.
+    pub max_failures: u32,
+    /// How long an ejected node is kept out of rotation.
+    pub ejection_duration: Duration,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            strategy: BalancingStrategy::default(),
+            max_failures: 3,
+            ejection_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+/// One Gaia node in the pool along with its health bookkeeping.
+pub struct Upstream {
+    pub url: String,
+    pub weight: u32,
+    client: Client,
+    in_flight: AtomicUsize,
+    consecutive_failures: AtomicU32,
+    healthy: AtomicBool,
+    ejected_until: Mutex<Option<Instant>>,
+    requests: AtomicU64,
+    failures: AtomicU64,
+}
+
+/// Point-in-time view of an upstream, as reported by the admin endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpstreamStatus {
+    pub url: String,
+    pub weight: u32,
+    pub healthy: bool,
+    pub ejected: bool,
+    pub in_flight: usize,
+    pub consecutive_failures: u32,
+    pub requests: u64,
+    pub failures: u64,
+}
+
+impl Upstream {
+    fn new(spec: &UpstreamSpec, api_key: &str, http_client: &reqwest::Client) -> Self {
+        let mut client = Client::new_with_base(&spec.url, api_key.to_string());
+        client.http_client = http_client.clone();
+        Self {
+            url: spec.url.clone(),
+            weight: spec.weight,
+            client,
+            in_flight: AtomicUsize::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            healthy: AtomicBool::new(true),
+            ejected_until: Mutex::new(None),
+            requests: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    /// Cheap handle to the node's client; clones share the connection pool.
+    pub fn client(&self) -> Client {
+        self.client.clone()
+    }
+
+    fn is_available(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+            && self
+                .ejected_until
+                .lock()
+                .map_or(true, |until| Instant::now() >= until)
+    }
+
+    /// Marks a request as started; the returned guard ends it when dropped.
+    pub fn start(self: &Arc<Self>) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(self.clone())
+    }
+
+    pub fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+    }
+
+    pub fn status(&self) -> UpstreamStatus {
+        UpstreamStatus {
+            url: self.url.clone(),
+            weight: self.weight,
+            healthy: self.healthy.load(Ordering::Relaxed),
+            ejected: self
+                .ejected_until
+                .lock()
+                .is_some_and(|until| Instant::now() < until),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            consecutive_failures: self.consecutive_failures.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Keeps an upstream's in-flight count up while a request is running.
+pub struct InFlightGuard(Arc<Upstream>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A set of Gaia nodes requests are balanced across.
+///
+/// The first node is the one managed by this operator; its URL follows the
+/// public URL reported by job 1.
+pub struct UpstreamPool {
+    upstreams: RwLock<Vec<Arc<Upstream>>>,
+    settings: PoolSettings,
+    next: AtomicUsize,
+    api_key: String,
+    http_client: reqwest::Client,
+}
+
+impl UpstreamPool {
+    pub fn new(
+        specs: &[UpstreamSpec],
+        api_key: String,
+        http_client: reqwest::Client,
+        settings: PoolSettings,
+    ) -> Self {
+        let upstreams = specs
+            .iter()
+            .map(|spec| Arc::new(Upstream::new(spec, &api_key, &http_client)))
+            .collect();
+        Self {
+            upstreams: RwLock::new(upstreams),
+            settings,
+            next: AtomicUsize::new(0),
+            api_key,
+            http_client,
+        }
+    }
+
+    /// Builds another pool over `specs` sharing this pool's connection pool,
+    /// API key and settings.
+    pub fn sibling(&self, specs: &[UpstreamSpec]) -> Self {
+        Self::new(
+            specs,
+            self.api_key.clone(),
+            self.http_client.clone(),
+            self.settings.clone(),
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.upstreams.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upstreams.read().is_empty()
+    }
+
+    /// URL of the operator's own node.
+    pub fn primary_url(&self) -> Option<String> {
+        self.upstreams
+            .read()
+            .first()
+            .map(|upstream| upstream.url.clone())
+    }
+
+    /// Points the operator's own node at a new URL, resetting its health.
+    pub fn replace_primary(&self, url: String) {
+        let mut upstreams = self.upstreams.write();
+        let weight = upstreams.first().map_or(1, |upstream| upstream.weight);
+        let upstream = Arc::new(Upstream::new(
+            &UpstreamSpec { url, weight },
+            &self.api_key,
+            &self.http_client,
+        ));
+        match upstreams.first_mut() {
+            Some(primary) => *primary = upstream,
+            None => upstreams.push(upstream),
+        }
+    }
+
+    /// Picks the node for the next attempt, skipping URLs already `tried`.
+    ///
+    /// Unhealthy or ejected nodes are only used when no healthy node is left,
+    /// so a false negative never takes the whole pool offline.
+    pub fn select(&self, tried: &[String]) -> Option<Arc<Upstream>> {
+        let upstreams = self.upstreams.read();
+        let untried: Vec<&Arc<Upstream>> = upstreams
+            .iter()
+            .filter(|upstream| !tried.contains(&upstream.url))
+            .collect();
+        let available: Vec<&Arc<Upstream>> = untried
+            .iter()
+            .copied()
+            .filter(|upstream| upstream.is_available())
+            .collect();
+        let candidates = if available.is_empty() {
+            untried
+        } else {
+            available
+        };
+
+        match self.settings.strategy {
+            BalancingStrategy::LeastInFlight => candidates
+                .into_iter()
+                .min_by_key(|upstream| upstream.in_flight.load(Ordering::Relaxed))
+                .cloned(),
+            BalancingStrategy::WeightedRoundRobin => {
+                let total_weight: u64 = candidates.iter().map(|u| u.weight as u64).sum();
+                if total_weight == 0 {
+                    return None;
+                }
+                let mut position = self.next.fetch_add(1, Ordering::Relaxed) as u64 % total_weight;
+                candidates
+                    .into_iter()
+                    .find(|upstream| {
+                        if position < upstream.weight as u64 {
+                            true
+                        } else {
+                            position -= upstream.weight as u64;
+                            false
+                        }
+                    })
+                    .cloned()
+            }
+        }
+    }
+
+    /// Counts a failed request against `upstream`, ejecting it once it has
+    /// failed `max_failures` times in a row.
+    pub fn record_failure(&self, upstream: &Upstream) {
+        upstream.failures.fetch_add(1, Ordering::Relaxed);
+        let failures = upstream
+            .consecutive_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        if failures >= self.settings.max_failures {
+            *upstream.ejected_until.lock() = Some(Instant::now() + self.settings.ejection_duration);
+            upstream.consecutive_failures.store(0, Ordering::Relaxed);
+            info!(
+                "Ejecting upstream {} for {:?} after {} consecutive failures",
+                upstream.url, self.settings.ejection_duration, failures
+            );
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<UpstreamStatus> {
+        self.upstreams
+            .read()
+            .iter()
+            .map(|upstream| upstream.status())
+            .collect()
+    }
+
+    /// Probes `GET {url}/models` on every node and updates its health flag.
+    pub async fn check_health(&self) {
+        let upstreams = self.upstreams.read().clone();
+        for upstream in upstreams {
+            let healthy = self
+                .http_client
+                .get(format!("{}/models", upstream.url))
+                .bearer_auth(&self.api_key)
+                .timeout(HEALTH_CHECK_TIMEOUT)
+                .send()
+                .await
+                .is_ok_and(|response| response.status().is_success());
+
+            if upstream.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                info!(
+                    "Upstream {} is now {}",
+                    upstream.url,
+                    if healthy { "healthy" } else { "unhealthy" }
+                );
+            }
+        }
+    }
+}
+
+/// Runs active health checks against every node in `pool` forever.
+pub async fn run_health_checks(pool: Arc<UpstreamPool>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        pool.check_health().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: &str = "http://a.test/v1";
+    const B: &str = "http://b.test/v1";
+
+    fn pool(weights: &[u32], settings: PoolSettings) -> UpstreamPool {
+        let specs: Vec<_> = [A, B]
+            .iter()
+            .zip(weights)
+            .map(|(url, &weight)| UpstreamSpec {
+                url: url.to_string(),
+                weight,
+            })
+            .collect();
+        UpstreamPool::new(&specs, "key".to_string(), reqwest::Client::new(), settings)
+    }
+
+    fn selected(pool: &UpstreamPool) -> String {
+        pool.select(&[]).unwrap().url.clone()
+    }
+
+    #[test]
+    fn specs_parse_weights() {
+        let spec: UpstreamSpec = "http://a.test/v1/;weight=3".parse().unwrap();
+        assert_eq!(spec.url, A);
+        assert_eq!(spec.weight, 3);
+        assert_eq!(
+            "http://a.test/v1".parse::<UpstreamSpec>().unwrap().weight,
+            1
+        );
+        assert!("http://a.test/v1;weight=0".parse::<UpstreamSpec>().is_err());
+        assert!("not a url".parse::<UpstreamSpec>().is_err());
+    }
+
+    #[test]
+    fn round_robin_follows_weights() {
+        let pool = pool(&[3, 1], PoolSettings::default());
+        let picks: Vec<_> = (0..8).map(|_| selected(&pool)).collect();
+        assert_eq!(picks.iter().filter(|url| *url == A).count(), 6);
+        assert_eq!(picks.iter().filter(|url| *url == B).count(), 2);
+    }
+
+    #[test]
+    fn least_in_flight_avoids_busy_nodes() {
+        let pool = pool(
+            &[1, 1],
+            PoolSettings {
+                strategy: BalancingStrategy::LeastInFlight,
+                ..Default::default()
+            },
+        );
+        let first = pool.select(&[]).unwrap();
+        let guard = first.start();
+        assert_ne!(selected(&pool), first.url);
+
+        drop(guard);
+        assert_eq!(first.status().in_flight, 0);
+    }
+
+    #[test]
+    fn tried_nodes_are_skipped() {
+        let pool = pool(&[1, 1], PoolSettings::default());
+        assert_eq!(pool.select(&[A.to_string()]).unwrap().url, B);
+        assert!(pool.select(&[A.to_string(), B.to_string()]).is_none());
+    }
+
+    #[test]
+    fn failing_nodes_are_ejected_and_readmitted() {
+        let pool = pool(
+            &[1, 1],
+            PoolSettings {
+                max_failures: 2,
+                ejection_duration: Duration::from_millis(50),
+                ..Default::default()
+            },
+        );
+        let a = pool.select(&[B.to_string()]).unwrap();
+
+        pool.record_failure(&a);
+        a.record_success();
+        pool.record_failure(&a);
+        assert!(!a.status().ejected, "a success resets the count");
+
+        pool.record_failure(&a);
+        let status = a.status();
+        assert!(status.ejected);
+        assert_eq!(status.failures, 3);
+        assert_eq!(status.consecutive_failures, 0);
+        assert!((0..4).all(|_| selected(&pool) == B));
+        // With every other node tried, an ejected node is still used.
+        assert_eq!(pool.select(&[B.to_string()]).unwrap().url, A);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!a.status().ejected);
+        assert!((0..4).any(|_| selected(&pool) == A));
+    }
+
+    #[test]
+    fn unhealthy_nodes_are_skipped() {
+        let pool = pool(&[1, 1], PoolSettings::default());
+        let a = pool.select(&[B.to_string()]).unwrap();
+        a.healthy.store(false, Ordering::Relaxed);
+        assert!((0..4).all(|_| selected(&pool) == B));
+
+        a.healthy.store(true, Ordering::Relaxed);
+        assert!((0..4).any(|_| selected(&pool) == A));
+    }
+}
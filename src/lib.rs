@@ -1,10 +1,48 @@
-use color_eyre::Result;
-use gadget_sdk::executor::process::manager::GadgetProcessManager;
+use gadget_sdk::info;
 use serde::{Deserialize, Serialize};
-use std::convert::Infallible;
+use std::sync::LazyLock;
+use thiserror::Error;
+use tokio::sync::watch;
 
 pub mod actix_server;
+pub mod lifecycle;
 pub mod runner;
+pub mod snapshot;
+
+/// Latest public URL reported by a successful run of job 1.
+static NODE_PUBLIC_URL: LazyLock<watch::Sender<Option<String>>> =
+    LazyLock::new(|| watch::channel(None).0);
+
+/// Subscribes to the public URL of the Gaia node started by this operator.
+///
+/// The value is `None` until [`run_gaia_node_job`] has completed once, or
+/// [`recover_node_state`] found the node already running.
+pub fn node_public_url() -> watch::Receiver<Option<String>> {
+    NODE_PUBLIC_URL.subscribe()
+}
+
+/// Loads the node's lifecycle state after a gadget restart and republishes
+/// its public URL if it is still running.
+pub async fn recover_node_state() -> Result<lifecycle::NodeState, lifecycle::LifecycleError> {
+    let lifecycle = lifecycle::shared().await?;
+    publish_public_url(&lifecycle);
+    Ok(lifecycle.state())
+}
+
+fn publish_public_url(lifecycle: &lifecycle::NodeLifecycle) {
+    if lifecycle.state() != lifecycle::NodeState::Running {
+        return;
+    }
+    if let Some(public_url) = lifecycle.public_url() {
+        NODE_PUBLIC_URL.send_if_modified(|current| {
+            let changed = current.as_deref() != Some(public_url);
+            if changed {
+                *current = Some(public_url.to_string());
+            }
+            changed
+        });
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct ConfigUpdate {
@@ -12,43 +50,161 @@ pub struct ConfigUpdate {
     value: String,
 }
 
-/// Runs a Gaia node and returns the outputs of each step along with the public URL.
+/// Input of the jobs that install the node, e.g. `{"version": "0.4.3"}`.
+#[derive(Deserialize)]
+struct NodeVersion {
+    /// A Gaia node release tag with a pinned installer checksum.
+    version: String,
+}
+
+impl NodeVersion {
+    fn parse(data: &[u8]) -> Result<String, JobError> {
+        let NodeVersion { version } =
+            serde_json::from_slice(data).map_err(|e| JobError::InvalidInput(e.to_string()))?;
+        if version.is_empty()
+            || !version
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        {
+            return Err(JobError::InvalidInput(format!(
+                "invalid version {:?}",
+                version
+            )));
+        }
+        Ok(version)
+    }
+}
+
+#[derive(Serialize)]
+struct SnapshotJobOutput {
+    #[serde(flatten)]
+    snapshot: snapshot::SnapshotInfo,
+    /// Ready to pass to [`update_gaia_config_job`].
+    config_updates: Vec<ConfigUpdate>,
+}
+
+/// Why a job failed.
+///
+/// Failures are not returned as `Err`: [`job_result`] reports them on-chain
+/// as `{"error": {"kind": ..., "message": ...}}` so callers can see what went
+/// wrong. Only a result that cannot be encoded at all is returned as `Err`.
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error("Invalid job input: {0}")]
+    InvalidInput(String),
+
+    #[error("{0}")]
+    Lifecycle(#[from] lifecycle::LifecycleError),
+
+    #[error("Snapshot failed: {0}")]
+    Snapshot(String),
+
+    #[error("Failed to encode job result: {0}")]
+    Serialization(serde_json::Error),
+}
+
+impl JobError {
+    /// Stable identifier for the failure, reported alongside the message.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            JobError::InvalidInput(_) => "invalid_input",
+            JobError::Lifecycle(e) => e.kind(),
+            JobError::Snapshot(_) => "snapshot_error",
+            JobError::Serialization(_) => "serialization_error",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JobFailure<'a> {
+    error: JobFailureDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct JobFailureDetail<'a> {
+    kind: &'static str,
+    message: String,
+    /// What the failing command printed, for node lifecycle failures.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<&'a runner::CommandOutput>,
+}
+
+/// Encodes the outcome of a job as the result submitted on-chain.
+fn job_result<T: Serialize>(job: &str, result: Result<T, JobError>) -> Result<String, JobError> {
+    let encoded = match result {
+        Ok(output) => serde_json::to_string(&output),
+        Err(e) => {
+            info!("Job {} failed: {}", job, e);
+            serde_json::to_string(&JobFailure {
+                error: JobFailureDetail {
+                    kind: e.kind(),
+                    message: e.to_string(),
+                    output: match &e {
+                        JobError::Lifecycle(e) => e.output(),
+                        _ => None,
+                    },
+                },
+            })
+        }
+    };
+    encoded.map_err(JobError::Serialization)
+}
+
+/// Installs the requested version, initializes and starts the Gaia node,
+/// skipping the steps that are already done, and returns the outputs along
+/// with the installed version and the public URL.
 #[gadget_sdk::job(
     id = 1,
     params(data),
     result(_),
     verifier(evm = "GaiaAiAgentBlueprint")
 )]
-pub async fn run_gaia_node_job(data: Vec<u8>) -> Result<String, Infallible> {
-    let mut manager = GadgetProcessManager::new();
-    let (_, outputs) = runner::run_gaia_node(&mut manager).await.unwrap();
-    Ok(serde_json::to_string(&outputs).unwrap())
+pub async fn run_gaia_node_job(data: Vec<u8>) -> Result<String, JobError> {
+    let result = async {
+        let version = NodeVersion::parse(&data)?;
+        let mut lifecycle = lifecycle::shared().await?;
+        let output = lifecycle.start(&version).await?;
+        publish_public_url(&lifecycle);
+        Ok::<_, JobError>(output)
+    }
+    .await;
+    job_result("run_gaia_node", result)
 }
 
-/// Stops the Gaia node using the GadgetProcessManager.
+/// Stops the Gaia node.
 #[gadget_sdk::job(
     id = 2,
     params(data),
     result(_),
     verifier(evm = "GaiaAiAgentBlueprint")
 )]
-pub async fn stop_gaia_node_job(data: Vec<u8>) -> Result<String, Infallible> {
-    let mut manager = GadgetProcessManager::new();
-    let (_, outputs) = runner::stop_gaia_node(&mut manager).await.unwrap();
-    Ok(serde_json::to_string(&outputs).unwrap())
+pub async fn stop_gaia_node_job(data: Vec<u8>) -> Result<String, JobError> {
+    let result = async {
+        let mut lifecycle = lifecycle::shared().await?;
+        Ok::<_, JobError>(lifecycle.stop().await?)
+    }
+    .await;
+    job_result("stop_gaia_node", result)
 }
 
-/// Upgrades the Gaia node.
+/// Upgrades the Gaia node to the requested version and starts it again,
+/// rolling back to the previous install if the upgraded node is unhealthy.
 #[gadget_sdk::job(
     id = 3,
     params(data),
     result(_),
     verifier(evm = "GaiaAiAgentBlueprint")
 )]
-pub async fn upgrade_gaia_node_job(data: Vec<u8>) -> Result<String, Infallible> {
-    let mut manager = GadgetProcessManager::new();
-    let (_, outputs) = runner::upgrade_gaia_node(&mut manager).await.unwrap();
-    Ok(serde_json::to_string(&outputs).unwrap())
+pub async fn upgrade_gaia_node_job(data: Vec<u8>) -> Result<String, JobError> {
+    let result = async {
+        let version = NodeVersion::parse(&data)?;
+        let mut lifecycle = lifecycle::shared().await?;
+        let output = lifecycle.upgrade(&version).await?;
+        publish_public_url(&lifecycle);
+        Ok::<_, JobError>(output)
+    }
+    .await;
+    job_result("upgrade_gaia_node", result)
 }
 
 /// Updates the Gaia node configuration and restarts the node.
@@ -58,15 +214,46 @@ pub async fn upgrade_gaia_node_job(data: Vec<u8>) -> Result<String, Infallible>
     result(_),
     verifier(evm = "GaiaAiAgentBlueprint")
 )]
-pub async fn update_gaia_config_job(config_updates: String) -> Result<String, Infallible> {
-    let mut manager = GadgetProcessManager::new();
-    let config_updates: Vec<ConfigUpdate> = serde_json::from_str(&config_updates).unwrap();
-    let config_updates: Vec<(&str, &str)> = config_updates
-        .iter()
-        .map(|update| (update.key.as_str(), update.value.as_str()))
-        .collect();
-    let (_, outputs) = runner::update_gaia_config(&mut manager, &config_updates)
-        .await
-        .unwrap();
-    Ok(serde_json::to_string(&outputs).unwrap())
+pub async fn update_gaia_config_job(config_updates: String) -> Result<String, JobError> {
+    let result = async {
+        let config_updates: Vec<ConfigUpdate> = serde_json::from_str(&config_updates)
+            .map_err(|e| JobError::InvalidInput(e.to_string()))?;
+        let config_updates: Vec<(&str, &str)> = config_updates
+            .iter()
+            .map(|update| (update.key.as_str(), update.value.as_str()))
+            .collect();
+        let mut lifecycle = lifecycle::shared().await?;
+        let output = lifecycle.update_config(&config_updates).await?;
+        publish_public_url(&lifecycle);
+        Ok::<_, JobError>(output)
+    }
+    .await;
+    job_result("update_gaia_config", result)
+}
+
+/// Builds a knowledge-base snapshot from a corpus with the node's embedding
+/// model and returns its path and checksum.
+#[gadget_sdk::job(
+    id = 5,
+    params(corpus),
+    result(_),
+    verifier(evm = "GaiaAiAgentBlueprint")
+)]
+pub async fn build_snapshot_job(corpus: String) -> Result<String, JobError> {
+    let result = async {
+        let corpus: snapshot::SnapshotCorpus =
+            serde_json::from_str(&corpus).map_err(|e| JobError::InvalidInput(e.to_string()))?;
+        let snapshot = snapshot::build_snapshot(corpus, &snapshot::settings())
+            .await
+            .map_err(|e| JobError::Snapshot(e.to_string()))?;
+        Ok::<_, JobError>(SnapshotJobOutput {
+            config_updates: vec![ConfigUpdate {
+                key: "snapshot".to_string(),
+                value: snapshot.path.clone(),
+            }],
+            snapshot,
+        })
+    }
+    .await;
+    job_result("build_snapshot", result)
 }
@@ -0,0 +1,970 @@
+use color_eyre::{eyre::eyre, Result};
+use gadget_sdk::executor::process::manager::GadgetProcessManager;
+use gadget_sdk::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
+use thiserror::Error;
+use tokio::sync::{Mutex, MutexGuard, OnceCell};
+
+use crate::runner::{self, CommandOutput, LocalNode, RunnerError};
+
+/// How long to wait for the local node when checking whether it is running.
+const NODE_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static NODE_CONFIG: OnceLock<NodeConfig> = OnceLock::new();
+static LIFECYCLE: OnceCell<Mutex<NodeLifecycle>> = OnceCell::const_new();
+
+/// Command line options for the node lifecycle.
+#[derive(StructOpt, Debug, Clone, Default)]
+pub struct NodeArgs {
+    /// Where the node's lifecycle state is kept across gadget restarts.
+    /// Defaults to `$HOME/.gaia-gadget/node-state.json`.
+    #[structopt(
+        long = "node-state-file",
+        env = "GAIA_NODE_STATE_FILE",
+        parse(from_os_str)
+    )]
+    pub node_state_file: Option<PathBuf>,
+
+    /// Gaia node releases this operator may install, as `version=sha256`
+    /// pairs pinning the SHA-256 of each release's `install.sh`.
+    #[structopt(
+        long = "installer-sha256",
+        env = "GAIA_INSTALLER_SHA256",
+        use_delimiter = true
+    )]
+    pub installer_sha256: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct NodeConfig {
+    state_file: Option<PathBuf>,
+    /// Pinned installer checksums by version.
+    installer_sha256: HashMap<String, String>,
+}
+
+/// Applies the node options. Must be called before the first job runs to
+/// take effect.
+pub fn configure(args: NodeArgs) -> Result<()> {
+    let installer_sha256 = parse_installer_pins(&args.installer_sha256)?;
+    let _ = NODE_CONFIG.set(NodeConfig {
+        state_file: args.node_state_file,
+        installer_sha256,
+    });
+    Ok(())
+}
+
+/// Parses `version=sha256` pins into lowercase checksums by version.
+fn parse_installer_pins(pins: &[String]) -> Result<HashMap<String, String>> {
+    let mut installer_sha256 = HashMap::new();
+    for pin in pins {
+        let (version, sha256) = pin
+            .split_once('=')
+            .ok_or_else(|| eyre!("Invalid installer pin {}, expected version=sha256", pin))?;
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(eyre!("Invalid SHA-256 for installer version {}", version));
+        }
+        installer_sha256.insert(version.to_string(), sha256.to_lowercase());
+    }
+    Ok(installer_sha256)
+}
+
+/// Where the Gaia node is in its lifecycle.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeState {
+    #[default]
+    NotInstalled,
+    Installed,
+    Initialized,
+    Running,
+    Stopped,
+    /// An upgrade started and has not finished; only another upgrade can
+    /// leave this state.
+    Upgrading,
+}
+
+impl NodeState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NodeState::NotInstalled => "not_installed",
+            NodeState::Installed => "installed",
+            NodeState::Initialized => "initialized",
+            NodeState::Running => "running",
+            NodeState::Stopped => "stopped",
+            NodeState::Upgrading => "upgrading",
+        }
+    }
+
+    /// Whether the node may move from `self` to `to` in one step.
+    pub fn can_transition(&self, to: NodeState) -> bool {
+        use NodeState::*;
+        matches!(
+            (*self, to),
+            (NotInstalled, Installed)
+                | (Installed, Initialized)
+                // Initializing again applies config changes.
+                | (Initialized, Initialized)
+                | (Initialized, Running)
+                | (Running, Stopped)
+                | (Stopped, Running)
+                | (Stopped, Initialized)
+                | (Installed | Initialized | Stopped, Upgrading)
+                | (Upgrading, Installed)
+        )
+    }
+}
+
+impl fmt::Display for NodeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why a lifecycle operation failed.
+#[derive(Debug, Error)]
+pub enum LifecycleError {
+    #[error("Cannot move the node from {from} to {to}")]
+    InvalidTransition { from: NodeState, to: NodeState },
+
+    #[error("No installer SHA-256 is pinned for version {0}")]
+    UnpinnedVersion(String),
+
+    #[error("Version {installed} is installed, upgrade it to run version {requested}")]
+    VersionMismatch {
+        installed: String,
+        requested: String,
+    },
+
+    #[error(transparent)]
+    Runner(#[from] RunnerError),
+
+    #[error("{0}")]
+    Backup(String),
+
+    #[error("Upgrade failed ({upgrade}) and so did rolling back ({rollback})")]
+    RollbackFailed { upgrade: String, rollback: String },
+
+    #[error("Node state error: {0}")]
+    State(String),
+}
+
+impl LifecycleError {
+    /// Stable identifier for the failure.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LifecycleError::InvalidTransition { .. } => "invalid_transition",
+            LifecycleError::UnpinnedVersion(_) => "unpinned_version",
+            LifecycleError::VersionMismatch { .. } => "version_mismatch",
+            LifecycleError::Runner(e) => e.kind(),
+            LifecycleError::Backup(_) => "backup_failed",
+            LifecycleError::RollbackFailed { .. } => "rollback_failed",
+            LifecycleError::State(_) => "state_error",
+        }
+    }
+
+    /// The captured output of the command that failed, if one did.
+    pub fn output(&self) -> Option<&CommandOutput> {
+        match self {
+            LifecycleError::Runner(e) => e.output(),
+            _ => None,
+        }
+    }
+}
+
+/// What is persisted between gadget restarts.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct NodeRecord {
+    state: NodeState,
+    /// Release installed by the last successful install or upgrade; `None`
+    /// if the node was installed outside of the gadget.
+    #[serde(default)]
+    version: Option<String>,
+    /// Reported by the last successful start.
+    public_url: Option<String>,
+    /// Set while an upgrade is under way.
+    #[serde(default)]
+    rollback: Option<RollbackPoint>,
+    updated_at: u64,
+}
+
+/// What an upgrade rolls back to if it fails.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RollbackPoint {
+    /// Copy of the install and config taken before the upgrade.
+    backup: PathBuf,
+    /// The release the backup holds, if known.
+    version: Option<String>,
+}
+
+/// The result of a lifecycle operation.
+#[derive(Serialize, Debug, Default)]
+pub struct LifecycleOutput {
+    /// The node's state once the operation finished.
+    pub state: NodeState,
+    /// The installed release, if known.
+    pub version: Option<String>,
+    /// Steps that were already done and therefore not run again.
+    pub skipped: Vec<&'static str>,
+    /// How an upgrade went, for upgrades that ran.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upgrade: Option<UpgradeReport>,
+    /// The output of each step that ran, plus `public_url` once the node is running.
+    #[serde(flatten)]
+    pub outputs: HashMap<String, String>,
+}
+
+/// Which path an upgrade took.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpgradePath {
+    /// The new version passed the health check and is running.
+    Upgraded,
+    /// The upgrade failed and the previous install was restored.
+    RolledBack,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UpgradeReport {
+    pub path: UpgradePath,
+    pub from_version: Option<String>,
+    pub to_version: String,
+    /// Why the upgrade was rolled back.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<UpgradeFailure>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UpgradeFailure {
+    pub kind: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<CommandOutput>,
+}
+
+/// Drives the Gaia node through its lifecycle.
+///
+/// Every job goes through the one instance returned by [`shared`], so jobs
+/// run one at a time against the same process manager. The state is written
+/// to disk after every step and checked against the node on startup.
+pub struct NodeLifecycle {
+    path: PathBuf,
+    record: NodeRecord,
+    installer_sha256: HashMap<String, String>,
+    manager: GadgetProcessManager,
+}
+
+/// Locks the lifecycle shared by all jobs, loading it on first use.
+pub async fn shared() -> Result<MutexGuard<'static, NodeLifecycle>, LifecycleError> {
+    let lifecycle = LIFECYCLE
+        .get_or_try_init(|| async {
+            let config = NODE_CONFIG.get().cloned().unwrap_or_default();
+            let path = match config.state_file {
+                Some(path) => path,
+                None => home_dir()?.join(".gaia-gadget").join("node-state.json"),
+            };
+            NodeLifecycle::open(path, config.installer_sha256)
+                .await
+                .map(Mutex::new)
+        })
+        .await?;
+    Ok(lifecycle.lock().await)
+}
+
+impl NodeLifecycle {
+    /// Loads the state at `path` and reconciles it with the node on this host.
+    ///
+    /// Only the installer versions in `installer_sha256` can be installed.
+    pub async fn open(
+        path: impl AsRef<Path>,
+        installer_sha256: HashMap<String, String>,
+    ) -> Result<Self, LifecycleError> {
+        let path = path.as_ref().to_path_buf();
+        let mut record: NodeRecord = if path.exists() {
+            let contents = std::fs::read_to_string(&path).map_err(|e| {
+                LifecycleError::State(format!("Failed to read {}: {}", path.display(), e))
+            })?;
+            serde_json::from_str(&contents).map_err(|e| {
+                LifecycleError::State(format!("Invalid node state {}: {}", path.display(), e))
+            })?
+        } else {
+            NodeRecord::default()
+        };
+
+        let observed = observed_state(record.state).await;
+        if observed != record.state {
+            info!(
+                "Recovered node state {} (last recorded as {})",
+                observed, record.state
+            );
+            record.state = observed;
+            record.updated_at = now();
+            if observed == NodeState::NotInstalled {
+                record.version = None;
+                record.rollback = None;
+            }
+        }
+
+        let lifecycle = Self {
+            path,
+            record,
+            installer_sha256,
+            manager: GadgetProcessManager::new(),
+        };
+        lifecycle.persist()?;
+        Ok(lifecycle)
+    }
+
+    pub fn state(&self) -> NodeState {
+        self.record.state
+    }
+
+    /// The installed release, if known.
+    pub fn version(&self) -> Option<&str> {
+        self.record.version.as_deref()
+    }
+
+    /// The public URL reported when the node was last started.
+    pub fn public_url(&self) -> Option<&str> {
+        self.record.public_url.as_deref()
+    }
+
+    /// Installs `version`, initializes and starts the node, skipping the
+    /// steps that are already done. Does nothing if the node is running.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`LifecycleError::VersionMismatch`] if a different version is
+    /// already installed; that takes an upgrade.
+    pub async fn start(&mut self, version: &str) -> Result<LifecycleOutput, LifecycleError> {
+        if let Some(installed) = self.version() {
+            if self.state() != NodeState::NotInstalled && installed != version {
+                return Err(LifecycleError::VersionMismatch {
+                    installed: installed.to_string(),
+                    requested: version.to_string(),
+                });
+            }
+        }
+        let mut output = LifecycleOutput::default();
+        match self.state() {
+            NodeState::NotInstalled => self.install(version, &mut output).await?,
+            NodeState::Upgrading => {
+                return Err(LifecycleError::InvalidTransition {
+                    from: NodeState::Upgrading,
+                    to: NodeState::Running,
+                })
+            }
+            _ => output.skipped.push("install"),
+        }
+        match self.state() {
+            NodeState::Installed => self.init(&mut output).await?,
+            _ => output.skipped.push("init"),
+        }
+        match self.state() {
+            NodeState::Running => output.skipped.push("start"),
+            _ => self.run(&mut output).await?,
+        }
+        Ok(self.finish(output))
+    }
+
+    /// Stops the node. Does nothing if it is already stopped.
+    pub async fn stop(&mut self) -> Result<LifecycleOutput, LifecycleError> {
+        let mut output = LifecycleOutput::default();
+        match self.state() {
+            NodeState::Stopped => output.skipped.push("stop"),
+            _ => self.halt(&mut output).await?,
+        }
+        Ok(self.finish(output))
+    }
+
+    /// Upgrades the node binary to `version`, then initializes, starts and
+    /// health checks it. If `version` is already installed the node is only
+    /// started.
+    ///
+    /// The installer is verified and the current install and config are
+    /// backed up before a running node is stopped. If any later step or the
+    /// health check fails, the backup is restored and the previous version
+    /// started again; [`LifecycleOutput::upgrade`] reports which path was
+    /// taken. An upgrade interrupted by a restart can be retried and still
+    /// rolls back to the same backup.
+    pub async fn upgrade(&mut self, version: &str) -> Result<LifecycleOutput, LifecycleError> {
+        if self.state() != NodeState::Upgrading && self.version() == Some(version) {
+            let mut output = self.start(version).await?;
+            output.skipped.insert(0, "upgrade");
+            return Ok(output);
+        }
+        if self.state() == NodeState::NotInstalled {
+            return Err(LifecycleError::InvalidTransition {
+                from: NodeState::NotInstalled,
+                to: NodeState::Upgrading,
+            });
+        }
+        let installer = self.installer(version).await?;
+
+        // An interrupted upgrade without a backup has nothing left to roll
+        // back to.
+        let rollback = match self.record.rollback.clone() {
+            Some(point) => Some(point),
+            None if self.state() == NodeState::Upgrading => None,
+            None => Some(self.back_up().await?),
+        };
+        let from_version = match &rollback {
+            Some(point) => point.version.clone(),
+            None => self.record.version.clone(),
+        };
+
+        let mut output = LifecycleOutput::default();
+        if self.state() == NodeState::Running {
+            self.halt(&mut output).await?;
+        }
+        let result = self.upgrade_to(version, &installer, &mut output).await;
+        let report = match (result, rollback) {
+            (Ok(()), _) => UpgradeReport {
+                path: UpgradePath::Upgraded,
+                from_version,
+                to_version: version.to_string(),
+                failure: None,
+            },
+            (Err(e), None) => return Err(e),
+            (Err(e), Some(point)) => {
+                info!("Upgrade to {} failed, rolling back: {}", version, e);
+                let mut rollback_output = LifecycleOutput::default();
+                if let Err(rollback_error) = self.roll_back(&point, &mut rollback_output).await {
+                    return Err(LifecycleError::RollbackFailed {
+                        upgrade: e.to_string(),
+                        rollback: rollback_error.to_string(),
+                    });
+                }
+                output.outputs.extend(
+                    rollback_output
+                        .outputs
+                        .into_iter()
+                        .map(|(step, step_output)| (format!("rollback_{}", step), step_output)),
+                );
+                UpgradeReport {
+                    path: UpgradePath::RolledBack,
+                    from_version,
+                    to_version: version.to_string(),
+                    failure: Some(UpgradeFailure {
+                        kind: e.kind(),
+                        message: e.to_string(),
+                        output: e.output().cloned(),
+                    }),
+                }
+            }
+        };
+        self.record.rollback = None;
+        self.persist()?;
+
+        let mut output = self.finish(output);
+        output.upgrade = Some(report);
+        Ok(output)
+    }
+
+    /// Installs `version` over a stopped node and brings it up again.
+    async fn upgrade_to(
+        &mut self,
+        version: &str,
+        installer: &Path,
+        output: &mut LifecycleOutput,
+    ) -> Result<(), LifecycleError> {
+        if self.state() != NodeState::Upgrading {
+            self.transition(NodeState::Upgrading)?;
+        }
+        let (_, outputs) = runner::upgrade_gaia_node(&mut self.manager, installer).await?;
+        output.outputs.extend(outputs);
+        self.record.version = Some(version.to_string());
+        self.transition(NodeState::Installed)?;
+
+        self.init(output).await?;
+        self.run(output).await?;
+        runner::check_node_health(&LocalNode::load()?).await?;
+        Ok(())
+    }
+
+    /// Copies the current install and config aside and records them as the
+    /// point to roll back to.
+    async fn back_up(&mut self) -> Result<RollbackPoint, LifecycleError> {
+        let point = RollbackPoint {
+            backup: self.state_dir().join("upgrade-backup"),
+            version: self.record.version.clone(),
+        };
+        let (home, backup) = (home_dir()?, point.backup.clone());
+        blocking("Failed to back up the install", move || {
+            backup_install(&home, &backup)
+        })
+        .await?;
+        self.record.rollback = Some(point.clone());
+        self.persist()?;
+        Ok(point)
+    }
+
+    /// Restores the install from `point` and starts the previous version.
+    async fn roll_back(
+        &mut self,
+        point: &RollbackPoint,
+        output: &mut LifecycleOutput,
+    ) -> Result<(), LifecycleError> {
+        if self.state() == NodeState::Running {
+            self.halt(output).await?;
+        }
+        if self.state() != NodeState::Upgrading {
+            self.transition(NodeState::Upgrading)?;
+        }
+        let (home, backup) = (home_dir()?, point.backup.clone());
+        blocking("Failed to restore the install", move || {
+            restore_install(&home, &backup)
+        })
+        .await?;
+        self.record.version = point.version.clone();
+        self.transition(NodeState::Installed)?;
+
+        self.init(output).await?;
+        self.run(output).await
+    }
+
+    /// Applies config updates, then initializes and starts the node again.
+    ///
+    /// The updates are validated before the node is touched. A running node
+    /// is stopped first.
+    pub async fn update_config(
+        &mut self,
+        config_updates: &[(&str, &str)],
+    ) -> Result<LifecycleOutput, LifecycleError> {
+        for (key, value) in config_updates {
+            runner::validate_config_command(key, value)?;
+        }
+        if matches!(self.state(), NodeState::NotInstalled | NodeState::Upgrading) {
+            return Err(LifecycleError::InvalidTransition {
+                from: self.state(),
+                to: NodeState::Initialized,
+            });
+        }
+        let mut output = LifecycleOutput::default();
+        if self.state() == NodeState::Running {
+            self.halt(&mut output).await?;
+        }
+        let (_, outputs) = runner::update_gaia_config(&mut self.manager, config_updates).await?;
+        output.outputs.extend(outputs);
+
+        self.init(&mut output).await?;
+        self.run(&mut output).await?;
+        Ok(self.finish(output))
+    }
+
+    async fn install(
+        &mut self,
+        version: &str,
+        output: &mut LifecycleOutput,
+    ) -> Result<(), LifecycleError> {
+        self.check(NodeState::Installed)?;
+        let installer = self.installer(version).await?;
+        let (_, outputs) = runner::install_gaia_node(&mut self.manager, &installer).await?;
+        output.outputs.extend(outputs);
+        self.record.version = Some(version.to_string());
+        self.transition(NodeState::Installed)
+    }
+
+    /// Downloads and verifies the installer of `version` next to the state file.
+    async fn installer(&self, version: &str) -> Result<PathBuf, LifecycleError> {
+        let sha256 = self
+            .installer_sha256
+            .get(version)
+            .ok_or_else(|| LifecycleError::UnpinnedVersion(version.to_string()))?;
+        let dir = self.state_dir().join("installers");
+        Ok(runner::fetch_installer(version, sha256, &dir).await?)
+    }
+
+    /// The directory holding the state file, installers and backups.
+    fn state_dir(&self) -> PathBuf {
+        self.path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+
+    async fn init(&mut self, output: &mut LifecycleOutput) -> Result<(), LifecycleError> {
+        self.check(NodeState::Initialized)?;
+        let (_, outputs) = runner::init_gaia_node(&mut self.manager).await?;
+        output.outputs.extend(outputs);
+        self.transition(NodeState::Initialized)
+    }
+
+    async fn run(&mut self, output: &mut LifecycleOutput) -> Result<(), LifecycleError> {
+        self.check(NodeState::Running)?;
+        let (_, outputs) = runner::start_gaia_node(&mut self.manager).await?;
+        self.record.public_url = outputs.get("public_url").cloned();
+        output.outputs.extend(outputs);
+        self.transition(NodeState::Running)
+    }
+
+    async fn halt(&mut self, output: &mut LifecycleOutput) -> Result<(), LifecycleError> {
+        self.check(NodeState::Stopped)?;
+        let (_, outputs) = runner::stop_gaia_node(&mut self.manager).await?;
+        output.outputs.extend(outputs);
+        self.transition(NodeState::Stopped)
+    }
+
+    fn finish(&self, mut output: LifecycleOutput) -> LifecycleOutput {
+        output.state = self.state();
+        output.version = self.record.version.clone();
+        if let (NodeState::Running, Some(public_url)) = (self.state(), self.public_url()) {
+            output
+                .outputs
+                .insert("public_url".to_string(), public_url.to_string());
+        }
+        output
+    }
+
+    fn check(&self, to: NodeState) -> Result<(), LifecycleError> {
+        if self.state().can_transition(to) {
+            Ok(())
+        } else {
+            Err(LifecycleError::InvalidTransition {
+                from: self.state(),
+                to,
+            })
+        }
+    }
+
+    fn transition(&mut self, to: NodeState) -> Result<(), LifecycleError> {
+        self.check(to)?;
+        info!("Gaia node {} -> {}", self.record.state, to);
+        self.record.state = to;
+        self.record.updated_at = now();
+        self.persist()
+    }
+
+    fn persist(&self) -> Result<(), LifecycleError> {
+        let write = || -> std::io::Result<()> {
+            if let Some(parent) = self.path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let contents = serde_json::to_string_pretty(&self.record)?;
+            // Write to a sibling file first so a crash never leaves a truncated state.
+            let tmp_path = self.path.with_extension("tmp");
+            std::fs::write(&tmp_path, contents)?;
+            std::fs::rename(&tmp_path, &self.path)
+        };
+        write().map_err(|e| {
+            LifecycleError::State(format!("Failed to write {}: {}", self.path.display(), e))
+        })
+    }
+}
+
+/// Works out the node's actual state from the last recorded one.
+///
+/// The binary must exist for the node to be anything but not installed, and
+/// the local API answering means it is running whatever was recorded. An
+/// interrupted upgrade stays as it is so that it is retried.
+async fn observed_state(recorded: NodeState) -> NodeState {
+    let installed = home_dir()
+        .map(|home| home.join("gaianet").join("bin").join("gaianet").exists())
+        .unwrap_or(false);
+    if !installed || recorded == NodeState::Upgrading {
+        return reconcile(recorded, installed, false);
+    }
+    reconcile(recorded, installed, node_responds().await)
+}
+
+/// [`observed_state`] once the host has been probed.
+fn reconcile(recorded: NodeState, installed: bool, responds: bool) -> NodeState {
+    if !installed {
+        return NodeState::NotInstalled;
+    }
+    if recorded == NodeState::Upgrading {
+        return recorded;
+    }
+    match (recorded, responds) {
+        (_, true) => NodeState::Running,
+        (NodeState::Running, false) => NodeState::Stopped,
+        (NodeState::NotInstalled, false) => NodeState::Installed,
+        (recorded, false) => recorded,
+    }
+}
+
+/// Top-level entries of `$HOME/gaianet` left out of upgrade backups: the
+/// downloaded models, vector data and logs, which an upgrade leaves alone.
+fn excluded_from_backup(name: &str) -> bool {
+    name.ends_with(".gguf") || matches!(name, "qdrant" | "snapshots" | "log")
+}
+
+/// Copies `$HOME/gaianet` and the WasmEdge runtime in `$HOME/.wasmedge` to
+/// `backup`, replacing any earlier backup.
+fn backup_install(home: &Path, backup: &Path) -> std::io::Result<()> {
+    remove_all(backup)?;
+    for entry in std::fs::read_dir(home.join("gaianet"))? {
+        let entry = entry?;
+        if excluded_from_backup(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        copy_all(
+            &entry.path(),
+            &backup.join("gaianet").join(entry.file_name()),
+        )?;
+    }
+    let wasmedge = home.join(".wasmedge");
+    if wasmedge.exists() {
+        copy_all(&wasmedge, &backup.join(".wasmedge"))?;
+    }
+    Ok(())
+}
+
+/// Puts back everything [`backup_install`] copied. Entries the upgrade added
+/// to `$HOME/gaianet` are left in place.
+fn restore_install(home: &Path, backup: &Path) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(backup.join("gaianet"))? {
+        let entry = entry?;
+        let target = home.join("gaianet").join(entry.file_name());
+        remove_all(&target)?;
+        copy_all(&entry.path(), &target)?;
+    }
+    let wasmedge = backup.join(".wasmedge");
+    if wasmedge.exists() {
+        let target = home.join(".wasmedge");
+        remove_all(&target)?;
+        copy_all(&wasmedge, &target)?;
+    }
+    Ok(())
+}
+
+fn copy_all(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let metadata = std::fs::symlink_metadata(from)?;
+    if metadata.is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(from)?, to)
+    } else if metadata.is_dir() {
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            copy_all(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        std::fs::copy(from, to).map(|_| ())
+    }
+}
+
+fn remove_all(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs file work off the async runtime.
+async fn blocking(
+    context: &'static str,
+    work: impl FnOnce() -> std::io::Result<()> + Send + 'static,
+) -> Result<(), LifecycleError> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| LifecycleError::Backup(format!("{}: {}", context, e)))?
+        .map_err(|e| LifecycleError::Backup(format!("{}: {}", context, e)))
+}
+
+/// Whether the local node's API answers, on the port its config sets.
+async fn node_responds() -> bool {
+    let client = match reqwest::Client::builder()
+        .timeout(NODE_PROBE_TIMEOUT)
+        .build()
+    {
+        Ok(client) => client,
+        Err(_) => return false,
+    };
+    let node = LocalNode::load().unwrap_or_default();
+    client
+        .get(format!("{}/models", node.base_url()))
+        .send()
+        .await
+        .map(|response| response.status().is_success())
+        .unwrap_or(false)
+}
+
+fn home_dir() -> Result<PathBuf, LifecycleError> {
+    std::env::var("HOME")
+        .map(PathBuf::from)
+        .map_err(|_| LifecycleError::State("HOME is not set".to_string()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use NodeState::*;
+
+    const STATES: [NodeState; 6] = [
+        NotInstalled,
+        Installed,
+        Initialized,
+        Running,
+        Stopped,
+        Upgrading,
+    ];
+
+    #[test]
+    fn allowed_transitions() {
+        for (from, to) in [
+            (NotInstalled, Installed),
+            (Installed, Initialized),
+            (Initialized, Initialized),
+            (Initialized, Running),
+            (Running, Stopped),
+            (Stopped, Running),
+            (Stopped, Initialized),
+            (Installed, Upgrading),
+            (Initialized, Upgrading),
+            (Stopped, Upgrading),
+            (Upgrading, Installed),
+        ] {
+            assert!(from.can_transition(to), "{} -> {} is allowed", from, to);
+        }
+    }
+
+    #[test]
+    fn rejected_transitions() {
+        for (from, to) in [
+            (NotInstalled, Running),
+            (NotInstalled, Initialized),
+            (Installed, Running),
+            (Running, Initialized),
+            (Running, Upgrading),
+            (Running, Running),
+            (Stopped, Stopped),
+            (Upgrading, Running),
+            (Upgrading, Stopped),
+        ] {
+            assert!(!from.can_transition(to), "{} -> {} is rejected", from, to);
+        }
+        for state in STATES {
+            assert!(!state.can_transition(NotInstalled));
+        }
+    }
+
+    #[test]
+    fn missing_binary_means_not_installed() {
+        for recorded in STATES {
+            assert_eq!(reconcile(recorded, false, false), NotInstalled);
+            assert_eq!(reconcile(recorded, false, true), NotInstalled);
+        }
+    }
+
+    #[test]
+    fn interrupted_upgrade_is_kept() {
+        assert_eq!(reconcile(Upgrading, true, false), Upgrading);
+        assert_eq!(reconcile(Upgrading, true, true), Upgrading);
+    }
+
+    #[test]
+    fn responding_node_is_running() {
+        for recorded in [NotInstalled, Installed, Initialized, Running, Stopped] {
+            assert_eq!(reconcile(recorded, true, true), Running);
+        }
+    }
+
+    #[test]
+    fn silent_node_keeps_its_recorded_state() {
+        assert_eq!(reconcile(Running, true, false), Stopped);
+        assert_eq!(reconcile(NotInstalled, true, false), Installed);
+        for recorded in [Installed, Initialized, Stopped] {
+            assert_eq!(reconcile(recorded, true, false), recorded);
+        }
+    }
+
+    fn write(path: &Path, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn restore_undoes_an_upgrade() {
+        let root = std::env::temp_dir().join(format!("gaia-backup-{}", uuid::Uuid::new_v4()));
+        let (home, backup) = (root.join("home"), root.join("backup"));
+        let gaianet = home.join("gaianet");
+        write(&gaianet.join("bin").join("gaianet"), "0.4.3");
+        write(&gaianet.join("config.json"), "{\"chat_name\": \"old\"}");
+        write(&gaianet.join("model.gguf"), "weights");
+        write(&gaianet.join("qdrant").join("collection"), "vectors");
+        write(
+            &home.join(".wasmedge").join("lib").join("plugin"),
+            "old plugin",
+        );
+
+        backup_install(&home, &backup).unwrap();
+        assert!(!backup.join("gaianet").join("model.gguf").exists());
+        assert!(!backup.join("gaianet").join("qdrant").exists());
+
+        write(&gaianet.join("bin").join("gaianet"), "0.4.4");
+        write(&gaianet.join("config.json"), "{\"chat_name\": \"new\"}");
+        write(&gaianet.join("added-by-upgrade"), "new");
+        remove_all(&home.join(".wasmedge").join("lib")).unwrap();
+
+        restore_install(&home, &backup).unwrap();
+        assert_eq!(read(&gaianet.join("bin").join("gaianet")), "0.4.3");
+        assert_eq!(
+            read(&gaianet.join("config.json")),
+            "{\"chat_name\": \"old\"}"
+        );
+        assert_eq!(read(&gaianet.join("model.gguf")), "weights");
+        assert_eq!(read(&gaianet.join("qdrant").join("collection")), "vectors");
+        assert_eq!(read(&gaianet.join("added-by-upgrade")), "new");
+        assert_eq!(
+            read(&home.join(".wasmedge").join("lib").join("plugin")),
+            "old plugin"
+        );
+
+        remove_all(&root).unwrap();
+    }
+
+    #[test]
+    fn backup_replaces_an_earlier_one() {
+        let root = std::env::temp_dir().join(format!("gaia-backup-{}", uuid::Uuid::new_v4()));
+        let (home, backup) = (root.join("home"), root.join("backup"));
+        write(&home.join("gaianet").join("config.json"), "{}");
+        write(&backup.join("gaianet").join("stale"), "stale");
+
+        backup_install(&home, &backup).unwrap();
+        assert!(!backup.join("gaianet").join("stale").exists());
+        assert!(!backup.join(".wasmedge").exists());
+        assert_eq!(read(&backup.join("gaianet").join("config.json")), "{}");
+
+        remove_all(&root).unwrap();
+    }
+
+    #[test]
+    fn installer_pins_are_parsed() {
+        let sha256 = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD";
+        let pins = parse_installer_pins(&[format!("0.4.3={}", sha256)]).unwrap();
+        assert_eq!(pins.get("0.4.3"), Some(&sha256.to_lowercase()));
+    }
+
+    #[test]
+    fn malformed_installer_pins_are_rejected() {
+        for pin in [
+            "0.4.3",
+            "0.4.3=abc",
+            "0.4.3=zz7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        ] {
+            assert!(parse_installer_pins(&[pin.to_string()]).is_err(), "{}", pin);
+        }
+    }
+}
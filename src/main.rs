@@ -17,16 +17,34 @@ use gadget_sdk::{
     tx,
 };
 pub use gaia_ai_agent_template as blueprint;
-use gaia_ai_agent_template::actix_server;
+use gaia_ai_agent_template::actix_server::{
+    self,
+    config::{ServerArgs, ServerConfig},
+    tools::ToolRegistry,
+};
+use gaia_ai_agent_template::lifecycle::NodeArgs;
 use structopt::StructOpt;
 
+#[derive(StructOpt)]
+struct Cli {
+    #[structopt(flatten)]
+    context: ContextConfig,
+    #[structopt(flatten)]
+    server: ServerArgs,
+    #[structopt(flatten)]
+    node: NodeArgs,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     gadget_sdk::logging::setup_log();
     // Load the environment and create the gadget runner
-    let config = ContextConfig::from_args();
+    let cli = Cli::from_args();
+    let server_config = ServerConfig::load(cli.server)?;
+    blueprint::lifecycle::configure(cli.node)?;
+    blueprint::snapshot::configure(&server_config);
 
-    let (env, mut runner) = create_gadget_runner(config.clone()).await;
+    let (env, mut runner) = create_gadget_runner(cli.context).await;
 
     info!("~~~ Executing the incredible squaring blueprint ~~~");
 
@@ -36,11 +54,24 @@ async fn main() -> Result<()> {
         runner.register().await?;
     }
 
-    let model = "llama".to_string();
+    // Pick up where the node was left before this gadget was restarted.
+    match blueprint::recover_node_state().await {
+        Ok(state) => info!("Gaia node is {}", state),
+        Err(e) => eprintln!("Failed to recover the Gaia node state: {}", e),
+    }
+
     let service_id = env.service_id.unwrap_or_default();
+    // Register in-process tools here to make them available to `run_tools` chats.
+    let tools = ToolRegistry::new();
     // Run the server and the gadget concurrently
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = actix_server::server::run_server(service_id, server_config, tools, async {
+        let _ = shutdown_rx.await;
+    });
+    tokio::pin!(server);
+
     tokio::select! {
-        server_result = actix_server::server::run_server(service_id, model) => {
+        server_result = &mut server => {
             if let Err(e) = server_result {
                 eprintln!("Server error: {}", e);
             }
@@ -49,6 +80,11 @@ async fn main() -> Result<()> {
             if let Err(e) = runner_result {
                 eprintln!("Runner error: {}", e);
             }
+            // Let the server drain in-flight requests before exiting.
+            let _ = shutdown_tx.send(());
+            if let Err(e) = server.await {
+                eprintln!("Server error: {}", e);
+            }
         }
     }
 
@@ -130,6 +166,11 @@ impl GadgetRunner for TangleGadgetRunner {
         };
 
         let update_config_job = blueprint::UpdateGaiaConfigJobEventHandler {
+            service_id: self.env.service_id.unwrap(),
+            signer: signer.clone(),
+        };
+
+        let build_snapshot_job = blueprint::BuildSnapshotJobEventHandler {
             service_id: self.env.service_id.unwrap(),
             signer,
         };
@@ -142,6 +183,7 @@ impl GadgetRunner for TangleGadgetRunner {
                 Box::new(stop_job),
                 Box::new(upgrade_job),
                 Box::new(update_config_job),
+                Box::new(build_snapshot_job),
             ],
         };
 
@@ -1,6 +1,288 @@
 use gadget_sdk::executor::process::manager::GadgetProcessManager;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// How long a single step may run before it is reported as timed out.
+///
+/// `gaianet init` downloads the configured models, so this is generous.
+pub const STEP_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// How long downloading an installer may take.
+const INSTALLER_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long a freshly started node has to start answering requests.
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Time between attempts while waiting for the node to answer.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a single health check request may take. A chat completion on a
+/// cold node can be slow.
+const HEALTH_CHECK_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest tail of each output stream kept in an error.
+const MAX_CAPTURED_OUTPUT: usize = 4096;
+
+/// Port of the node's API when its `config.json` does not set `llamaedge_port`.
+pub const DEFAULT_NODE_PORT: u16 = 8080;
+
+/// Printed after each command so its exit status can be read back.
+const EXIT_STATUS_MARKER: &str = "__gaia_exit_status=";
+
+/// What a failed command printed, trimmed to the last few kilobytes.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CommandOutput {
+    /// `None` if the command was cut off before reporting a status.
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    fn new(exit_code: Option<i32>, stdout: &str, stderr: &str) -> Self {
+        Self {
+            exit_code,
+            stdout: tail(stdout),
+            stderr: tail(stderr),
+        }
+    }
+}
+
+/// Shows the exit status and the last line the command printed.
+impl fmt::Display for CommandOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.exit_code {
+            Some(code) => write!(f, "exit status {}", code)?,
+            None => write!(f, "no exit status")?,
+        }
+        match last_line(&self.stderr).or_else(|| last_line(&self.stdout)) {
+            Some(line) => write!(f, ": {}", line),
+            None => Ok(()),
+        }
+    }
+}
+
+fn last_line(text: &str) -> Option<&str> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .last()
+}
+
+fn tail(text: &str) -> String {
+    if text.len() <= MAX_CAPTURED_OUTPUT {
+        return text.to_string();
+    }
+    let mut start = text.len() - MAX_CAPTURED_OUTPUT;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    text[start..].to_string()
+}
+
+/// Why managing the Gaia node failed.
+#[derive(Debug, Error)]
+pub enum RunnerError {
+    #[error("Installing the Gaia node failed ({output})")]
+    Install { output: CommandOutput },
+
+    #[error("gaianet init failed ({output})")]
+    Init { output: CommandOutput },
+
+    #[error("gaianet start failed ({output})")]
+    Start { output: CommandOutput },
+
+    #[error("Failed to download the installer for version {version}: {message}")]
+    Download { version: String, message: String },
+
+    #[error("Installer for version {version} has SHA-256 {actual}, expected {expected}")]
+    ChecksumMismatch {
+        version: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Step {step} failed ({output})")]
+    Command { step: String, output: CommandOutput },
+
+    #[error("Failed to extract the public URL from the gaianet start output")]
+    UrlExtraction { output: CommandOutput },
+
+    #[error("Invalid value for config key {key}: {reason}")]
+    ConfigValidation { key: String, reason: String },
+
+    #[error("Process manager error in step {step}: {message}")]
+    ProcessManager { step: String, message: String },
+
+    #[error("Step {step} did not finish within {timeout_secs} seconds")]
+    Timeout { step: String, timeout_secs: u64 },
+
+    #[error("Health check failed: {0}")]
+    HealthCheck(String),
+}
+
+impl RunnerError {
+    /// Stable identifier for the failure.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RunnerError::Install { .. } => "install_failed",
+            RunnerError::Init { .. } => "init_failed",
+            RunnerError::Start { .. } => "start_failed",
+            RunnerError::Download { .. } => "download_failed",
+            RunnerError::ChecksumMismatch { .. } => "checksum_mismatch",
+            RunnerError::Command { .. } => "command_failed",
+            RunnerError::UrlExtraction { .. } => "url_extraction_failed",
+            RunnerError::ConfigValidation { .. } => "invalid_config",
+            RunnerError::ProcessManager { .. } => "process_manager_error",
+            RunnerError::Timeout { .. } => "timeout",
+            RunnerError::HealthCheck(_) => "health_check_failed",
+        }
+    }
+
+    /// The captured output of the command that failed, if one did.
+    pub fn output(&self) -> Option<&CommandOutput> {
+        match self {
+            RunnerError::Install { output }
+            | RunnerError::Init { output }
+            | RunnerError::Start { output }
+            | RunnerError::Command { output, .. }
+            | RunnerError::UrlExtraction { output } => Some(output),
+            _ => None,
+        }
+    }
+
+    /// Classifies a failed step by what it was doing.
+    fn step_failed(step: &str, output: CommandOutput) -> Self {
+        match step {
+            "binary_install" | "upgrade_gaia_node" => RunnerError::Install { output },
+            "init_gaia" => RunnerError::Init { output },
+            "start_gaia" => RunnerError::Start { output },
+            _ => RunnerError::Command {
+                step: step.to_string(),
+                output,
+            },
+        }
+    }
+
+    fn process_manager(step: &str, e: impl fmt::Display) -> Self {
+        RunnerError::ProcessManager {
+            step: step.to_string(),
+            message: e.to_string(),
+        }
+    }
+
+    fn invalid_config(key: &str, reason: impl Into<String>) -> Self {
+        RunnerError::ConfigValidation {
+            key: key.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Runs a single command and returns its stdout.
+///
+/// The command's stderr is redirected to a temporary file and its exit status
+/// echoed after it, so a failure can be reported with both.
+///
+/// # Errors
+///
+/// Returns an error if the command exits with a non-zero status, does not
+/// finish within [`STEP_TIMEOUT`], or the process manager fails. A command
+/// that times out is killed, along with everything it started.
+async fn run_step(
+    manager: &mut GadgetProcessManager,
+    name: &str,
+    command: &str,
+) -> Result<String, RunnerError> {
+    let stderr_path =
+        std::env::temp_dir().join(format!("gaia-{}-{}.stderr", name, uuid::Uuid::new_v4()));
+    let wrapped = format!(
+        "( {} ) 2>'{}'; echo \"{}$?\"",
+        command,
+        stderr_path.display(),
+        EXIT_STATUS_MARKER
+    );
+
+    let service = manager
+        .run(name.to_string(), &wrapped)
+        .await
+        .map_err(|e| RunnerError::process_manager(name, e))?;
+    let result = tokio::time::timeout(
+        STEP_TIMEOUT,
+        manager.focus_service_to_completion(service.clone()),
+    )
+    .await;
+    if result.is_err() {
+        kill_service(manager, &service);
+    }
+    let stderr = std::fs::read_to_string(&stderr_path).unwrap_or_default();
+    let _ = std::fs::remove_file(&stderr_path);
+
+    let output = result
+        .map_err(|_| RunnerError::Timeout {
+            step: name.to_string(),
+            timeout_secs: STEP_TIMEOUT.as_secs(),
+        })?
+        .map_err(|e| RunnerError::process_manager(name, e))?;
+
+    let (stdout, exit_code) = match output.rfind(EXIT_STATUS_MARKER) {
+        Some(index) => (
+            &output[..index],
+            output[index + EXIT_STATUS_MARKER.len()..]
+                .trim()
+                .parse::<i32>()
+                .ok(),
+        ),
+        None => (output.as_str(), None),
+    };
+    if exit_code != Some(0) {
+        return Err(RunnerError::step_failed(
+            name,
+            CommandOutput::new(exit_code, stdout, &stderr),
+        ));
+    }
+    Ok(stdout.to_string())
+}
+
+/// Kills a service and every process below it, and forgets it.
+///
+/// The service is the wrapper shell from [`run_step`]; killing only it would
+/// leave the actual command running.
+fn kill_service(manager: &mut GadgetProcessManager, service: &str) {
+    let Some(process) = manager.children.remove(service) else {
+        return;
+    };
+    let mut pids = vec![process.pid];
+    pids.extend(descendants(process.pid));
+    let _ = std::process::Command::new("kill")
+        .arg("-KILL")
+        .args(pids.iter().map(u32::to_string))
+        .status();
+}
+
+/// Every process below `pid`, found by walking `pgrep -P`.
+fn descendants(pid: u32) -> Vec<u32> {
+    let children: Vec<u32> = std::process::Command::new("pgrep")
+        .arg("-P")
+        .arg(pid.to_string())
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter_map(|line| line.trim().parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    children
+        .iter()
+        .flat_map(|&child| std::iter::once(child).chain(descendants(child)))
+        .collect()
+}
 
 /// Function to run multiple commands and focus on the output of each command.
 ///
@@ -15,7 +297,8 @@ use std::error::Error;
 ///
 /// # Returns
 ///
-/// Returns a Result containing a HashMap with the output of each command, or an error.
+/// Returns a Result containing a HashMap with the output of each command, or the
+/// error of the first command that failed.
 ///
 /// # Example
 ///
@@ -30,23 +313,133 @@ use std::error::Error;
 async fn run_and_focus_multiple<'a>(
     manager: &mut GadgetProcessManager,
     commands: Vec<(&'a str, &'a str)>,
-) -> Result<HashMap<String, String>, Box<dyn Error>> {
+) -> Result<HashMap<String, String>, RunnerError> {
     let mut outputs = HashMap::new();
     for (name, command) in commands {
-        let service = manager.run(name.to_string(), command).await?;
-        let output = manager.focus_service_to_completion(service).await?;
+        let output = run_step(manager, name, command).await?;
         outputs.insert(name.to_string(), output);
     }
     Ok(outputs)
 }
 
-/// Runs a Gaia node and returns the outputs of each step along with the public URL.
+/// Downloads the installer of a Gaia node release into `dir` and checks it
+/// against a pinned checksum.
+///
+/// The installer is only written to disk once it has been verified, so a
+/// returned path is always safe to run.
+///
+/// # Arguments
+///
+/// * `version` - The release tag, e.g. `0.4.3`.
+/// * `sha256` - The expected hex SHA-256 of that release's `install.sh`.
+/// * `dir` - Where to keep the installer.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// - The installer cannot be downloaded ([`RunnerError::Download`])
+/// - Its checksum does not match ([`RunnerError::ChecksumMismatch`])
+pub async fn fetch_installer(
+    version: &str,
+    sha256: &str,
+    dir: &Path,
+) -> Result<PathBuf, RunnerError> {
+    let download_error = |message: String| RunnerError::Download {
+        version: version.to_string(),
+        message,
+    };
+    let url = format!(
+        "https://github.com/GaiaNet-AI/gaianet-node/releases/download/{}/install.sh",
+        version
+    );
+    let client = reqwest::Client::builder()
+        .timeout(INSTALLER_DOWNLOAD_TIMEOUT)
+        .build()
+        .map_err(|e| download_error(e.to_string()))?;
+    let installer = client
+        .get(&url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| download_error(e.to_string()))?
+        .bytes()
+        .await
+        .map_err(|e| download_error(e.to_string()))?;
+    verify_checksum(version, &installer, sha256)?;
+
+    let path = dir.join(format!("install-{}.sh", version));
+    std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::write(&path, &installer))
+        .map_err(|e| download_error(format!("failed to write {}: {}", path.display(), e)))?;
+    Ok(path)
+}
+
+/// Checks `installer` against the pinned hex SHA-256, in either case.
+fn verify_checksum(version: &str, installer: &[u8], sha256: &str) -> Result<(), RunnerError> {
+    let actual: String = Sha256::digest(installer)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if !actual.eq_ignore_ascii_case(sha256) {
+        return Err(RunnerError::ChecksumMismatch {
+            version: version.to_string(),
+            expected: sha256.to_lowercase(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+/// Installs the Gaia node binary under `$HOME/gaianet`.
 ///
 /// This function performs the following steps:
-/// 1. Installs the Gaia node binary
+/// 1. Runs a verified installer from [`fetch_installer`]
 /// 2. Sources the updated bashrc
-/// 3. Initializes the Gaia node
-/// 4. Starts the Gaia node
+///
+/// # Returns
+///
+/// Returns a tuple containing:
+/// - `()`: An empty tuple as the first element
+/// - `HashMap<String, String>`: A map of step names to their outputs
+///
+/// # Errors
+///
+/// This function will return [`RunnerError::Install`] if the installer fails.
+pub async fn install_gaia_node(
+    manager: &mut GadgetProcessManager,
+    installer: &Path,
+) -> Result<((), HashMap<String, String>), RunnerError> {
+    let install_command = format!("bash '{}'", installer.display());
+    let commands = vec![
+        ("binary_install", install_command.as_str()),
+        ("source_dir", "source ~/.bashrc"),
+    ];
+
+    let outputs = run_and_focus_multiple(manager, commands).await?;
+    Ok(((), outputs))
+}
+
+/// Initializes the Gaia node, downloading the configured models and snapshot.
+///
+/// # Returns
+///
+/// Returns a tuple containing:
+/// - `()`: An empty tuple as the first element
+/// - `HashMap<String, String>`: A map with the "init_gaia" output
+///
+/// # Errors
+///
+/// This function will return [`RunnerError::Init`] if `gaianet init` fails.
+pub async fn init_gaia_node(
+    manager: &mut GadgetProcessManager,
+) -> Result<((), HashMap<String, String>), RunnerError> {
+    let commands = vec![("init_gaia", "gaianet init")];
+
+    let outputs = run_and_focus_multiple(manager, commands).await?;
+    Ok(((), outputs))
+}
+
+/// Starts the Gaia node and returns its output along with the public URL.
 ///
 /// # Returns
 ///
@@ -59,41 +452,38 @@ async fn run_and_focus_multiple<'a>(
 /// # Errors
 ///
 /// This function will return an error if:
-/// - Any of the commands fail to execute
-/// - The public URL cannot be extracted from the output
+/// - `gaianet start` fails ([`RunnerError::Start`])
+/// - The public URL cannot be extracted from the output ([`RunnerError::UrlExtraction`])
 ///
 /// # Example
 ///
 /// ```
-/// let (_, outputs) = run_gaia_node().await?;
+/// let mut manager = GadgetProcessManager::new();
+/// let (_, outputs) = start_gaia_node(&mut manager).await?;
 /// println!("Gaia node public URL: {}", outputs.get("public_url").unwrap());
 /// ```
-pub async fn run_gaia_node(
+pub async fn start_gaia_node(
     manager: &mut GadgetProcessManager,
-) -> Result<((), HashMap<String, String>), Box<dyn Error>> {
-    let commands = vec![
-        ("binary_install", "curl -sSfL 'https://github.com/GaiaNet-AI/gaianet-node/releases/latest/download/install.sh' | bash"),
-        ("source_dir", "source ~/.bashrc"),
-        ("init_agai", "gaianet init"),
-        ("start_gaia", "gaianet start"),
-    ];
+) -> Result<((), HashMap<String, String>), RunnerError> {
+    let commands = vec![("start_gaia", "gaianet start")];
 
     let mut outputs = run_and_focus_multiple(manager, commands).await?;
 
     // Extract the public URL from the start_gaia output
-    let public_url = outputs
+    let start_output = outputs
         .get("start_gaia")
-        .and_then(|output: &String| {
-            output
-                .lines()
-                .find(|line| line.contains("https://") && line.contains(".gaianet.xyz"))
-                .map(|line| line.trim().to_string())
-        })
-        .ok_or_else(|| Box::<dyn Error>::from("Failed to extract public URL"))?;
+        .map(String::as_str)
+        .unwrap_or_default();
+    let public_url = start_output
+        .split_whitespace()
+        .find(|word| word.starts_with("https://") && word.contains(".gaianet.xyz"))
+        .map(|word| word.to_string())
+        .ok_or_else(|| RunnerError::UrlExtraction {
+            output: CommandOutput::new(Some(0), start_output, ""),
+        })?;
 
     println!("Gaia node public URL: {}", public_url);
 
-    // You can return the public_url if needed
     outputs.insert("public_url".to_string(), public_url);
 
     Ok(((), outputs))
@@ -129,31 +519,36 @@ pub async fn run_gaia_node(
 /// ```
 pub async fn stop_gaia_node(
     manager: &mut GadgetProcessManager,
-) -> Result<((), HashMap<String, String>), Box<dyn Error>> {
+) -> Result<((), HashMap<String, String>), RunnerError> {
     let commands = vec![("stop_gaia", "gaianet stop")];
 
     let outputs = run_and_focus_multiple(manager, commands).await?;
     Ok(((), outputs))
 }
 
+/// Upgrades the installed Gaia node binary in place with a verified
+/// installer from [`fetch_installer`].
+///
+/// The node must be stopped first and initialized again afterwards.
+///
+/// # Errors
+///
+/// This function will return [`RunnerError::Install`] if the installer fails.
 pub async fn upgrade_gaia_node(
     manager: &mut GadgetProcessManager,
-) -> Result<((), HashMap<String, String>), Box<dyn Error>> {
-    let commands = vec![
-        ("stop_gaia", "gaianet stop"),
-        ("upgrade_gaia_node", "curl -sSfL 'https://github.com/GaiaNet-AI/gaianet-node/releases/latest/download/install.sh' | bash -s -- --upgrade"),
-        ("init_agai", "gaianet init"),
-        ("start_gaia", "gaianet start"),
-    ];
+    installer: &Path,
+) -> Result<((), HashMap<String, String>), RunnerError> {
+    let upgrade_command = format!("bash '{}' --upgrade", installer.display());
+    let commands = vec![("upgrade_gaia_node", upgrade_command.as_str())];
 
     let outputs = run_and_focus_multiple(manager, commands).await?;
     Ok(((), outputs))
 }
 
-/// Updates the Gaia node configuration and restarts the node.
+/// Updates the Gaia node configuration.
 ///
-/// This function updates the specified configuration parameters of the Gaia node,
-/// reinitializes it, and then starts it again.
+/// This function updates the specified configuration parameters of the Gaia node.
+/// The node must be initialized again for them to take effect.
 ///
 /// # Arguments
 ///
@@ -166,8 +561,7 @@ pub async fn upgrade_gaia_node(
 /// - `()`: An empty tuple as the first element
 /// - `HashMap<String, String>`: A map of step names to their outputs
 ///
-/// The HashMap includes keys for each updated configuration parameter, as well as
-/// "init_gaia" and "start_gaia" with their respective command outputs.
+/// The HashMap includes an "update_config" key with the output of the config command.
 ///
 /// # Errors
 ///
@@ -186,11 +580,10 @@ pub async fn upgrade_gaia_node(
 /// let (_, outputs) = update_gaia_config(&mut manager, &config_updates).await?;
 /// println!("Update outputs: {:?}", outputs);
 /// ```
-
 pub async fn update_gaia_config(
     manager: &mut GadgetProcessManager,
     config_updates: &[(&str, &str)],
-) -> Result<((), HashMap<String, String>), Box<dyn Error>> {
+) -> Result<((), HashMap<String, String>), RunnerError> {
     let mut commands: Vec<(String, String)> = Vec::new();
 
     // Validate all config commands
@@ -206,9 +599,6 @@ pub async fn update_gaia_config(
 
     commands.push(("update_config".to_string(), config_command));
 
-    commands.push(("init_gaia".to_string(), "gaianet init".to_string()));
-    commands.push(("start_gaia".to_string(), "gaianet start".to_string()));
-
     // Convert commands into a Vec<(&str, &str)>
     let commands: Vec<(&str, &str)> = commands
         .iter()
@@ -236,7 +626,7 @@ pub async fn update_gaia_config(
 ///
 /// # Errors
 ///
-/// This function will return an error if:
+/// This function will return [`RunnerError::ConfigValidation`] naming the key if:
 /// - The key is not a known configuration parameter.
 /// - The value is invalid for the specified key.
 ///
@@ -247,13 +637,16 @@ pub async fn update_gaia_config(
 /// let value = "https://new-chat-url.com";
 /// validate_config_command(key, value)?;
 /// ```
-pub fn validate_config_command(key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+pub fn validate_config_command(key: &str, value: &str) -> Result<(), RunnerError> {
     match key {
         "chat-url" | "embedding-url" | "snapshot" => {
             if value.starts_with("http://") || value.starts_with("https://") {
                 // Validate URL structure
-                if let Err(_) = url::Url::parse(value) {
-                    return Err(format!("Invalid URL structure for {}: {}", key, value).into());
+                if let Err(e) = url::Url::parse(value) {
+                    return Err(RunnerError::invalid_config(
+                        key,
+                        format!("invalid URL {}: {}", value, e),
+                    ));
                 }
             } else {
                 // Check if it's a local file under $HOME/gaianet
@@ -261,14 +654,20 @@ pub fn validate_config_command(key: &str, value: &str) -> Result<(), Box<dyn Err
                 let gaia_path = std::path::Path::new(&home_dir).join("gaianet");
                 let file_path = std::path::Path::new(value);
                 if !file_path.exists() || !file_path.starts_with(&gaia_path) {
-                    return Err(format!("Invalid value for {}: {}. It should be a valid URL or a local file under $HOME/gaianet", key, value).into());
+                    return Err(RunnerError::invalid_config(
+                        key,
+                        format!(
+                            "{} should be a valid URL or a local file under $HOME/gaianet",
+                            value
+                        ),
+                    ));
                 }
             }
         }
         "chat-ctx-size" | "embedding-ctx-size" | "port" => {
-            value
-                .parse::<u32>()
-                .map_err(|_| format!("Invalid number for {}: {}", key, value))?;
+            value.parse::<u32>().map_err(|_| {
+                RunnerError::invalid_config(key, format!("invalid number {}", value))
+            })?;
         }
         "prompt-template" | "system-prompt" | "rag-prompt" | "reverse-prompt" => {
             // These are strings, so no validation needed
@@ -276,23 +675,29 @@ pub fn validate_config_command(key: &str, value: &str) -> Result<(), Box<dyn Err
         "base" => {
             // Validate if the path exists
             if !std::path::Path::new(value).exists() {
-                return Err(format!("Invalid path for base: {}", value).into());
+                return Err(RunnerError::invalid_config(
+                    key,
+                    format!("path {} does not exist", value),
+                ));
             }
         }
         "qdrant-limit" => {
-            let limit = value
-                .parse::<u32>()
-                .map_err(|_| format!("Invalid number for qdrant-limit: {}", value))?;
+            let limit = value.parse::<u32>().map_err(|_| {
+                RunnerError::invalid_config(key, format!("invalid number {}", value))
+            })?;
             if limit == 0 {
-                return Err("qdrant-limit must be greater than 0".into());
+                return Err(RunnerError::invalid_config(key, "must be greater than 0"));
             }
         }
         "qdrant-score-threshold" => {
-            let threshold = value
-                .parse::<f32>()
-                .map_err(|_| format!("Invalid number for qdrant-score-threshold: {}", value))?;
+            let threshold = value.parse::<f32>().map_err(|_| {
+                RunnerError::invalid_config(key, format!("invalid number {}", value))
+            })?;
             if threshold < 0.0 || threshold > 1.0 {
-                return Err("qdrant-score-threshold must be between 0.0 and 1.0".into());
+                return Err(RunnerError::invalid_config(
+                    key,
+                    "must be between 0.0 and 1.0",
+                ));
             }
         }
         "rag-policy" => {
@@ -300,10 +705,238 @@ pub fn validate_config_command(key: &str, value: &str) -> Result<(), Box<dyn Err
                 "system-message" | "last-user-message" => {
                     // These are valid options, no further validation needed
                 }
-                _ => return Err(format!("Invalid rag-policy value: {}. Must be either 'system-message' or 'last-user-message'", value).into()),
+                _ => {
+                    return Err(RunnerError::invalid_config(
+                        key,
+                        format!(
+                            "{} must be either 'system-message' or 'last-user-message'",
+                            value
+                        ),
+                    ))
+                }
             }
         }
-        _ => return Err(format!("Unknown config key: {}", key).into()),
+        _ => return Err(RunnerError::invalid_config(key, "unknown config key")),
     }
     Ok(())
 }
+
+/// Where the local node serves its API and which chat model it runs, as set
+/// in `$HOME/gaianet/config.json`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalNode {
+    pub port: u16,
+    /// `chat_name` from the config, if it sets one.
+    pub chat_model: Option<String>,
+}
+
+impl Default for LocalNode {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_NODE_PORT,
+            chat_model: None,
+        }
+    }
+}
+
+impl LocalNode {
+    /// Reads the node's `config.json`, falling back to the defaults if the
+    /// node has not been initialized yet.
+    pub fn load() -> Result<Self, RunnerError> {
+        let home_dir = std::env::var("HOME").unwrap_or_default();
+        let path = Path::new(&home_dir).join("gaianet").join("config.json");
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(RunnerError::invalid_config(
+                    "config.json",
+                    format!("failed to read {}: {}", path.display(), e),
+                ))
+            }
+        };
+        let config = serde_json::from_str(&contents)
+            .map_err(|e| RunnerError::invalid_config("config.json", e.to_string()))?;
+        Self::from_config(&config)
+    }
+
+    /// Picks the port and chat model out of a parsed `config.json`, where
+    /// `gaianet config` stores the port as a string.
+    fn from_config(config: &serde_json::Value) -> Result<Self, RunnerError> {
+        let port = match &config["llamaedge_port"] {
+            serde_json::Value::Null => DEFAULT_NODE_PORT,
+            serde_json::Value::String(port) => port.trim().parse().map_err(|_| {
+                RunnerError::invalid_config("llamaedge_port", format!("invalid port {}", port))
+            })?,
+            port => port
+                .as_u64()
+                .and_then(|port| u16::try_from(port).ok())
+                .ok_or_else(|| {
+                    RunnerError::invalid_config("llamaedge_port", format!("invalid port {}", port))
+                })?,
+        };
+        let chat_model = config["chat_name"]
+            .as_str()
+            .filter(|name| !name.is_empty())
+            .map(str::to_string);
+        Ok(Self { port, chat_model })
+    }
+
+    /// The node's OpenAI-compatible API, e.g. `http://127.0.0.1:8080/v1`.
+    pub fn base_url(&self) -> String {
+        format!("http://127.0.0.1:{}/v1", self.port)
+    }
+}
+
+/// Checks that a started node serves requests.
+///
+/// The models endpoint is polled until it lists the node's chat model (or
+/// any model, if the config names none) or [`HEALTH_CHECK_TIMEOUT`] passes,
+/// then a short chat completion is sent to that model.
+///
+/// # Arguments
+///
+/// * `node` - The node's address and chat model, usually from [`LocalNode::load`].
+///
+/// # Errors
+///
+/// This function will return [`RunnerError::HealthCheck`] if the node does
+/// not list the model in time or the chat completion fails.
+pub async fn check_node_health(node: &LocalNode) -> Result<(), RunnerError> {
+    let client = reqwest::Client::builder()
+        .timeout(HEALTH_CHECK_REQUEST_TIMEOUT)
+        .build()
+        .map_err(|e| RunnerError::HealthCheck(e.to_string()))?;
+
+    let base_url = &node.base_url();
+    let deadline = Instant::now() + HEALTH_CHECK_TIMEOUT;
+    let model = loop {
+        match chat_model(&client, base_url, node.chat_model.as_deref()).await {
+            Ok(model) => break model,
+            Err(_) if Instant::now() < deadline => tokio::time::sleep(HEALTH_CHECK_INTERVAL).await,
+            Err(e) => return Err(RunnerError::HealthCheck(format!("models endpoint: {}", e))),
+        }
+    };
+
+    let chat_error = |e: String| RunnerError::HealthCheck(format!("chat smoke test: {}", e));
+    let response: serde_json::Value = client
+        .post(format!("{}/chat/completions", base_url))
+        .json(&serde_json::json!({
+            "model": model,
+            "messages": [{"role": "user", "content": "Reply with the word OK."}],
+            "max_tokens": 16,
+        }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| chat_error(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| chat_error(e.to_string()))?;
+    if response["choices"][0]["message"]["content"].is_null() {
+        return Err(chat_error("the response has no message".to_string()));
+    }
+    Ok(())
+}
+
+/// The id of the chat model among those the node lists, or of the first
+/// model if `expected` is `None`.
+async fn chat_model(
+    client: &reqwest::Client,
+    base_url: &str,
+    expected: Option<&str>,
+) -> Result<String, String> {
+    let models: serde_json::Value = client
+        .get(format!("{}/models", base_url))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut ids = models["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|model| model["id"].as_str());
+    match expected {
+        Some(expected) => ids
+            .find(|id| *id == expected)
+            .map(str::to_string)
+            .ok_or_else(|| format!("chat model {} is not listed", expected)),
+        None => ids
+            .next()
+            .map(str::to_string)
+            .ok_or_else(|| "no models are listed".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// SHA-256 of `abc`, from FIPS 180-2.
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn matching_checksum_is_accepted() {
+        assert!(verify_checksum("0.4.3", b"abc", ABC_SHA256).is_ok());
+        assert!(verify_checksum("0.4.3", b"abc", &ABC_SHA256.to_uppercase()).is_ok());
+    }
+
+    #[test]
+    fn mismatched_checksum_is_rejected() {
+        match verify_checksum("0.4.3", b"abd", ABC_SHA256) {
+            Err(RunnerError::ChecksumMismatch {
+                version,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(version, "0.4.3");
+                assert_eq!(expected, ABC_SHA256);
+                assert_ne!(actual, ABC_SHA256);
+                assert_eq!(actual.len(), 64);
+            }
+            other => panic!("expected a checksum mismatch, got {:?}", other),
+        }
+        assert!(verify_checksum("0.4.3", b"abc", "").is_err());
+    }
+
+    #[test]
+    fn local_node_reads_port_and_chat_model() {
+        let node = LocalNode::from_config(&json!({
+            "llamaedge_port": "9090",
+            "chat_name": "Llama-3.2-3B-Instruct",
+            "embedding_name": "nomic-embed",
+        }))
+        .unwrap();
+        assert_eq!(node.port, 9090);
+        assert_eq!(node.chat_model.as_deref(), Some("Llama-3.2-3B-Instruct"));
+        assert_eq!(node.base_url(), "http://127.0.0.1:9090/v1");
+
+        let node = LocalNode::from_config(&json!({"llamaedge_port": 8081})).unwrap();
+        assert_eq!(node.port, 8081);
+    }
+
+    #[test]
+    fn local_node_defaults_when_unset() {
+        assert_eq!(
+            LocalNode::from_config(&json!({})).unwrap(),
+            LocalNode::default()
+        );
+        assert_eq!(LocalNode::default().base_url(), "http://127.0.0.1:8080/v1");
+    }
+
+    #[test]
+    fn local_node_rejects_invalid_ports() {
+        for port in [json!("eighty"), json!(70000), json!(true)] {
+            let config = json!({ "llamaedge_port": port });
+            assert!(matches!(
+                LocalNode::from_config(&config),
+                Err(RunnerError::ConfigValidation { .. })
+            ));
+        }
+    }
+}
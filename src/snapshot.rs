@@ -0,0 +1,289 @@
+use color_eyre::{eyre::eyre, Result};
+use gadget_sdk::info;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::actix_server::{
+    config::{ServerConfig, DEFAULT_BASE_URL},
+    gaia_client::GaiaNodeClient,
+    knowledge::{
+        DocumentFormat, IngestRequest, KnowledgeBase, KnowledgeConfig, DEFAULT_CHUNK_OVERLAP,
+        DEFAULT_CHUNK_SIZE, DEFAULT_COLLECTION, DEFAULT_QDRANT_URL,
+    },
+    net,
+};
+
+/// Upstream requests allowed in flight while embedding a corpus.
+const EMBEDDING_CONCURRENCY: usize = 4;
+/// How long downloading one corpus document may take.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+/// Largest corpus document accepted from a URL.
+const MAX_DOWNLOAD_BYTES: usize = 10 * 1024 * 1024;
+
+static SETTINGS: OnceLock<SnapshotSettings> = OnceLock::new();
+
+/// The node and Qdrant instance snapshots are built with.
+#[derive(Debug, Clone)]
+pub struct SnapshotSettings {
+    /// The operator's own node, which embeds the corpus.
+    pub base_url: String,
+    pub api_key: String,
+    /// Where the corpus is staged; its collection is replaced by a temporary one.
+    pub knowledge: KnowledgeConfig,
+}
+
+impl Default for SnapshotSettings {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            api_key: String::new(),
+            knowledge: KnowledgeConfig {
+                qdrant_url: DEFAULT_QDRANT_URL.to_string(),
+                qdrant_api_key: None,
+                collection: DEFAULT_COLLECTION.to_string(),
+                chunk_size: DEFAULT_CHUNK_SIZE,
+                chunk_overlap: DEFAULT_CHUNK_OVERLAP,
+            },
+        }
+    }
+}
+
+impl From<&ServerConfig> for SnapshotSettings {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            base_url: config
+                .upstreams
+                .first()
+                .map_or_else(|| DEFAULT_BASE_URL.to_string(), |node| node.url.clone()),
+            api_key: config.gaia_api_key.clone(),
+            knowledge: config.knowledge.clone(),
+        }
+    }
+}
+
+/// Uses the server's node and Qdrant settings for snapshot jobs. Must be
+/// called before the first job runs to take effect.
+pub fn configure(config: &ServerConfig) {
+    let _ = SETTINGS.set(SnapshotSettings::from(config));
+}
+
+/// The settings given to [`configure`], or the local defaults.
+pub fn settings() -> SnapshotSettings {
+    SETTINGS.get().cloned().unwrap_or_default()
+}
+
+/// Documents to build a knowledge-base snapshot from.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SnapshotCorpus {
+    /// Used in the snapshot file name.
+    pub name: Option<String>,
+    /// Plain-text or markdown documents to download.
+    pub urls: Vec<String>,
+    pub documents: Vec<CorpusDocument>,
+    pub chunk_size: Option<usize>,
+    pub chunk_overlap: Option<usize>,
+    /// Embedding model; defaults to the one in the node's `config.json`.
+    pub model: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CorpusDocument {
+    pub title: Option<String>,
+    pub content: String,
+    #[serde(default)]
+    pub format: DocumentFormat,
+}
+
+/// A snapshot written under `$HOME/gaianet/snapshots`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotInfo {
+    pub path: String,
+    /// Hex SHA-256 of the snapshot file.
+    pub sha256: String,
+    pub size_bytes: u64,
+    pub documents: usize,
+    pub chunks: usize,
+    pub model: String,
+}
+
+/// Builds a Qdrant snapshot of `corpus` with the local node's embedding model.
+///
+/// The corpus is ingested into a temporary collection of the Qdrant instance
+/// in `settings`, which is snapshotted to `$HOME/gaianet/snapshots` and
+/// dropped again. The resulting path can be used directly as the `snapshot`
+/// config value.
+pub async fn build_snapshot(
+    corpus: SnapshotCorpus,
+    settings: &SnapshotSettings,
+) -> Result<SnapshotInfo> {
+    let mut documents = corpus.documents;
+    for url in &corpus.urls {
+        documents.push(download_document(url).await?);
+    }
+    if documents.is_empty() {
+        return Err(eyre!("The corpus has no urls or documents"));
+    }
+
+    let model = match corpus.model {
+        Some(model) => model,
+        None => node_embedding_model()?,
+    };
+    let name = sanitize_name(corpus.name.as_deref().unwrap_or("knowledge"));
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let snapshot_dir = gaianet_dir()?.join("snapshots");
+    tokio::fs::create_dir_all(&snapshot_dir).await?;
+    let path = snapshot_dir.join(format!("{}-{}.snapshot", name, created_at));
+
+    let client = GaiaNodeClient::new(
+        settings.base_url.clone(),
+        settings.api_key.clone(),
+        model.clone(),
+        EMBEDDING_CONCURRENCY,
+    )?;
+    let knowledge = KnowledgeBase::new(KnowledgeConfig {
+        collection: format!("snapshot-{}-{}", name, created_at),
+        chunk_size: corpus.chunk_size.unwrap_or(settings.knowledge.chunk_size),
+        chunk_overlap: corpus
+            .chunk_overlap
+            .unwrap_or(settings.knowledge.chunk_overlap),
+        ..settings.knowledge.clone()
+    });
+
+    let result = async {
+        let mut chunks = 0;
+        for document in &documents {
+            let info = knowledge
+                .ingest(
+                    &client,
+                    IngestRequest {
+                        document_id: None,
+                        title: document.title.clone(),
+                        content: document.content.clone(),
+                        format: document.format,
+                        chunk_size: None,
+                        chunk_overlap: None,
+                        model: Some(model.clone()),
+                        metadata: None,
+                    },
+                )
+                .await?;
+            chunks += info.chunk_count;
+        }
+        let (size_bytes, sha256) = knowledge.save_snapshot(&path).await?;
+        Ok::<_, color_eyre::Report>((chunks, size_bytes, sha256))
+    }
+    .await;
+
+    // The temporary collection is dropped whether or not the build succeeded.
+    if let Err(e) = knowledge.delete_collection().await {
+        info!("Failed to drop temporary snapshot collection: {}", e);
+    }
+    let (chunks, size_bytes, sha256) = result?;
+
+    info!(
+        "Built snapshot {} ({} documents, {} chunks)",
+        path.display(),
+        documents.len(),
+        chunks
+    );
+
+    Ok(SnapshotInfo {
+        path: path.to_string_lossy().into_owned(),
+        sha256,
+        size_bytes,
+        documents: documents.len(),
+        chunks,
+        model,
+    })
+}
+
+/// `$HOME/gaianet`, where the node keeps its configuration and data.
+fn gaianet_dir() -> Result<PathBuf> {
+    let home_dir = std::env::var("HOME").map_err(|_| eyre!("HOME is not set"))?;
+    Ok(PathBuf::from(home_dir).join("gaianet"))
+}
+
+/// Reads the embedding model name from the node's `config.json`.
+fn node_embedding_model() -> Result<String> {
+    let path = gaianet_dir()?.join("config.json");
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| eyre!("Failed to read {}: {}", path.display(), e))?;
+    let config: serde_json::Value = serde_json::from_str(&contents)?;
+    config["embedding_name"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| eyre!("No embedding_name in {}", path.display()))
+}
+
+/// Downloads a corpus document from a public host.
+///
+/// Hosts resolving to private or local addresses are refused and redirects
+/// are not followed, so a corpus cannot read from the operator's network.
+async fn download_document(url: &str) -> Result<CorpusDocument> {
+    let parsed = url::Url::parse(url).map_err(|e| eyre!("Invalid url {}: {}", url, e))?;
+    let client = net::public_client(
+        &parsed,
+        reqwest::Client::builder().timeout(DOWNLOAD_TIMEOUT),
+    )
+    .await?;
+    let mut response = client.get(parsed).send().await?.error_for_status()?;
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if content_type.starts_with("application/pdf") {
+        return Err(eyre!(
+            "{} is a PDF; extract its text and pass it as a pdf_text document instead",
+            url
+        ));
+    }
+
+    let format = if content_type.starts_with("text/markdown")
+        || url.ends_with(".md")
+        || url.ends_with(".markdown")
+    {
+        DocumentFormat::Markdown
+    } else {
+        DocumentFormat::Text
+    };
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_DOWNLOAD_BYTES {
+            return Err(eyre!("{} is larger than {} bytes", url, MAX_DOWNLOAD_BYTES));
+        }
+    }
+    Ok(CorpusDocument {
+        title: Some(url.to_string()),
+        content: String::from_utf8_lossy(&body).into_owned(),
+        format,
+    })
+}
+
+/// Keeps a name safe for use in file and collection names.
+fn sanitize_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    if name.is_empty() {
+        "knowledge".to_string()
+    } else {
+        name
+    }
+}